@@ -0,0 +1,94 @@
+#[macro_use]
+extern crate criterion;
+extern crate tic_tac;
+
+use std::io;
+
+use criterion::Criterion;
+use tic_tac::bytecode::parse::parse;
+use tic_tac::bytecode::Program;
+
+const ARITH: &str = r#"
+defn f0 3 : 1 2 7 15
+  x0 := k0
+  x1 := k1
+  x0 := x0 + x1
+  x0 := x0 * x0
+  x1 := k2
+  x0 := x0 % x1
+  x1 := k3
+  x0 := x1 / x0
+  return x0
+"#;
+
+const FIB: &str = r#"
+defn f0 2 : f1 20
+  x0 := k1
+  x1 := k0
+  x0 := x1(x0)
+  return x0
+
+defn f1 4 : f1 2
+  x1 := k1
+  x2 := x0 < x1
+  cond x2 1 2
+  return x0
+  x3 := k0
+  x1 := x0 +# -1
+  x1 := x3(x1)
+  x2 := x0 +# -2
+  x2 := x3(x2)
+  x0 := x1 + x2
+  return x0
+"#;
+
+/// Builds a tuple of the numbers 0 to 99, then sums it by indexing each element.
+fn tuple_program() -> String {
+    let consts = (0..101).map(|i| i.to_string()).collect::<Vec<_>>();
+    let mut text = format!("defn f0 106 : {}\n", consts.join(" "));
+    for i in 0..100 {
+        text += &format!("  x{} := k{}\n", i, i);
+    }
+    text += r#"
+  x100 := (x0; 100)
+  x101 := k0
+  x102 := k0
+  x103 := k100
+  x104 := x101 < x103
+  cond x104 1 5
+  x105 := x100[x101]
+  x102 := x102 + x105
+  x101 := x101 +# 1
+  jump -5
+  return x102
+"#;
+    text
+}
+
+fn eval(program: &Program) {
+    program.eval(&mut io::empty(), &mut io::sink()).unwrap();
+}
+
+fn bench_arith(c: &mut Criterion) {
+    let program = parse(ARITH).unwrap();
+    c.bench_function("arith x10000", |b| {
+        b.iter(|| {
+            for _ in 0..10_000 {
+                eval(&program);
+            }
+        })
+    });
+}
+
+fn bench_calls(c: &mut Criterion) {
+    let program = parse(FIB).unwrap();
+    c.bench_function("fib 20", |b| b.iter(|| eval(&program)));
+}
+
+fn bench_tuples(c: &mut Criterion) {
+    let program = parse(&tuple_program()).unwrap();
+    c.bench_function("tuple 100", |b| b.iter(|| eval(&program)));
+}
+
+criterion_group!(benches, bench_arith, bench_calls, bench_tuples);
+criterion_main!(benches);
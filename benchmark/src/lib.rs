@@ -0,0 +1,5 @@
+//! Benchmarks for the tic-tac interpreter, kept in their own crate so the
+//! main crate doesn't depend on `criterion`.
+//!
+//! Run them with `cargo bench` from this directory. Criterion saves each run
+//! as the baseline for the next one, so a second run reports any changes.
@@ -8,7 +8,8 @@ fn main() {
             .nth(1)
             .expect("first argument to be filename");
         let mut text = String::new();
-        let mut file = std::fs::File::open(&fname).expect(&format!("file '{}' exists", fname));
+        let mut file = std::fs::File::open(&fname)
+            .unwrap_or_else(|_| panic!("file '{}' exists", fname));
         file.read_to_string(&mut text).unwrap();
         text
     };
@@ -0,0 +1,516 @@
+//! A compact binary encoding for values, instructions, and programs.
+//!
+//! Every value starts with a one byte tag, followed by its payload. All
+//! integers are stored little-endian.
+//!
+//! | Tag    | Value       | Payload                                        |
+//! |--------|-------------|------------------------------------------------|
+//! | `0x00` | `B(false)`  |                                                |
+//! | `0x01` | `B(true)`   |                                                |
+//! | `0x02` | `I`         | 8 bytes                                        |
+//! | `0x03` | `F`         | 8 bytes                                        |
+//! | `0x04` | `T`         | 4 byte length, then each element               |
+//! | `0x05` | `C`         | 2 byte function id                             |
+//! | `0x06` | `Closure`   | 2 byte function id, 4 byte length, each upval  |
+//! | `0x07` | `Ref`       | the referenced value                           |
+//! | `0x08` | `C`         | 2 byte function id, 1 byte arity               |
+//! | `0x09` | `S`         | 4 byte length, then the UTF-8 bytes            |
+//! | `0x0a` | `Null`      |                                                |
+//!
+//! Decoding rejects values nested more than 256 levels deep.
+//!
+//! Instructions are a one byte opcode followed by their operands in order,
+//! each taking as many bytes as its type. `Return(None)` and `Return(Some(a))`
+//! have separate opcodes.
+//!
+//! A program is its entry point, the number of functions as 4 bytes, and then
+//! for each function its local count, its constants as a sequence of values,
+//! and its code as a 4 byte length followed by each instruction.
+
+use smallvec::SmallVec;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use super::{AddrSize, Defn, FnId, Instr, Program, Val};
+
+/// Represents a failure to decode a value from bytes.
+#[derive(Debug, PartialEq)]
+pub struct DecodeError {}
+
+impl Val {
+    /// Encodes this value into its binary form.
+    ///
+    /// Encoding a reference encodes the value it currently points to.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.encode_into(&mut bytes);
+        bytes
+    }
+
+    /// Decodes a value from its binary form.
+    ///
+    /// The whole of `bytes` must be a single encoded value.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Val, DecodeError> {
+        let (val, len) = decode(bytes, 0)?;
+        if len == bytes.len() {
+            Ok(val)
+        } else {
+            Err(DecodeError {})
+        }
+    }
+
+    fn encode_into(&self, bytes: &mut Vec<u8>) {
+        use self::Val::*;
+        match *self {
+            B(false) => bytes.push(0x00),
+            B(true) => bytes.push(0x01),
+            I(i) => {
+                bytes.push(0x02);
+                bytes.extend_from_slice(&i.to_le_bytes());
+            }
+            F(f) => {
+                bytes.push(0x03);
+                bytes.extend_from_slice(&f.to_bits().to_le_bytes());
+            }
+            T(ref t) => {
+                bytes.push(0x04);
+                encode_seq(t, bytes);
+            }
+            C(id, None) => {
+                bytes.push(0x05);
+                bytes.extend_from_slice(&id.to_le_bytes());
+            }
+            C(id, Some(arity)) => {
+                bytes.push(0x08);
+                bytes.extend_from_slice(&id.to_le_bytes());
+                bytes.push(arity);
+            }
+            Closure {
+                fn_id,
+                ref upvals,
+            } => {
+                bytes.push(0x06);
+                bytes.extend_from_slice(&fn_id.to_le_bytes());
+                encode_seq(upvals, bytes);
+            }
+            Ref(ref r) => {
+                bytes.push(0x07);
+                match r.lock() {
+                    Ok(val) => val.encode_into(bytes),
+                    Err(poisoned) => poisoned.into_inner().encode_into(bytes),
+                }
+            }
+            S(ref s) => {
+                bytes.push(0x09);
+                bytes.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(s.as_bytes());
+            }
+            Null => bytes.push(0x0a),
+        }
+    }
+}
+
+fn encode_seq(vals: &[Val], bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(&(vals.len() as u32).to_le_bytes());
+    for val in vals {
+        val.encode_into(bytes);
+    }
+}
+
+/// Takes the first `n` bytes, failing if there aren't enough.
+fn take(bytes: &[u8], n: usize) -> Result<&[u8], DecodeError> {
+    bytes.get(..n).ok_or(DecodeError {})
+}
+
+fn decode_u16(bytes: &[u8]) -> Result<u16, DecodeError> {
+    let mut buf = [0; 2];
+    buf.copy_from_slice(take(bytes, 2)?);
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn decode_u32(bytes: &[u8]) -> Result<u32, DecodeError> {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(take(bytes, 4)?);
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn decode_u64(bytes: &[u8]) -> Result<u64, DecodeError> {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(take(bytes, 8)?);
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// How deeply tuples, closures, and references may nest in encoded values.
+/// Decoding recurses once per level, so without a limit a few kilobytes of
+/// input could overflow the stack.
+const MAX_DECODE_DEPTH: usize = 256;
+
+/// Decodes a length-prefixed sequence of values, returning them along with
+/// the number of bytes consumed. `depth` is how deeply the sequence is nested.
+fn decode_seq(bytes: &[u8], depth: usize) -> Result<(Vec<Val>, usize), DecodeError> {
+    let len = decode_u32(bytes)?;
+    let mut offset = 4;
+    // Don't trust the length for preallocation, since every value takes at
+    // least one byte.
+    let mut vals = Vec::with_capacity(::std::cmp::min(len as usize, bytes.len()));
+    for _ in 0..len {
+        let (val, size) = decode(&bytes[offset..], depth)?;
+        vals.push(val);
+        offset += size;
+    }
+    Ok((vals, offset))
+}
+
+/// Decodes a single value from the start of `bytes`, returning it along
+/// with the number of bytes consumed. `depth` is how many values it's nested in.
+fn decode(bytes: &[u8], depth: usize) -> Result<(Val, usize), DecodeError> {
+    use self::Val::*;
+    if depth > MAX_DECODE_DEPTH {
+        return Err(DecodeError {});
+    }
+    let (&tag, rest) = bytes.split_first().ok_or(DecodeError {})?;
+    let (val, size) = match tag {
+        0x00 => (B(false), 0),
+        0x01 => (B(true), 0),
+        0x02 => (I(decode_u64(rest)? as i64), 8),
+        0x03 => (F(f64::from_bits(decode_u64(rest)?)), 8),
+        0x04 => {
+            let (vals, size) = decode_seq(rest, depth + 1)?;
+            (T(vals), size)
+        }
+        0x05 => (C(decode_u16(rest)? as FnId, None), 2),
+        0x06 => {
+            let fn_id = decode_u16(rest)? as FnId;
+            let (upvals, size) = decode_seq(&rest[2..], depth + 1)?;
+            (
+                Closure {
+                    fn_id,
+                    upvals: upvals.into_boxed_slice(),
+                },
+                2 + size,
+            )
+        }
+        0x07 => {
+            let (val, size) = decode(rest, depth + 1)?;
+            (Ref(Arc::new(Mutex::new(val))), size)
+        }
+        0x08 => {
+            let fn_id = decode_u16(rest)? as FnId;
+            let arity = *rest.get(2).ok_or(DecodeError {})?;
+            (C(fn_id, Some(arity)), 3)
+        }
+        0x09 => {
+            let len = decode_u32(rest)? as usize;
+            let text = rest.get(4..4 + len).ok_or(DecodeError {})?;
+            let s = String::from_utf8(text.to_vec()).map_err(|_| DecodeError {})?;
+            (S(s), 4 + len)
+        }
+        0x0a => (Null, 0),
+        _ => return Err(DecodeError {}),
+    };
+    Ok((val, 1 + size))
+}
+
+/// A fixed size instruction operand.
+trait Operand: Sized {
+    fn encode(self, bytes: &mut SmallVec<[u8; 8]>);
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! impl_operand {
+    ($($ty:ty),*) => {
+        $(impl Operand for $ty {
+            fn encode(self, bytes: &mut SmallVec<[u8; 8]>) {
+                bytes.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn decode(bytes: &[u8]) -> Option<Self> {
+                let mut buf = [0; mem::size_of::<$ty>()];
+                buf.copy_from_slice(bytes.get(..mem::size_of::<$ty>())?);
+                Some(<$ty>::from_le_bytes(buf))
+            }
+        })*
+    }
+}
+
+impl_operand!(u8, i8, u16, i16, u32, i32);
+
+struct Encoder {
+    bytes: SmallVec<[u8; 8]>,
+}
+
+impl Encoder {
+    fn op(opcode: u8) -> Encoder {
+        let mut bytes = SmallVec::new();
+        bytes.push(opcode);
+        Encoder { bytes }
+    }
+
+    fn put<T: Operand>(mut self, operand: T) -> Encoder {
+        operand.encode(&mut self.bytes);
+        self
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn get<T: Operand>(&mut self) -> Option<T> {
+        let operand = T::decode(&self.bytes[self.pos..])?;
+        self.pos += mem::size_of::<T>();
+        Some(operand)
+    }
+}
+
+impl Instr {
+    /// Encodes this instruction as its opcode followed by its operands.
+    pub fn encode(&self) -> SmallVec<[u8; 8]> {
+        use self::Instr::*;
+        let e = match *self {
+            Const(a, b) => Encoder::op(0x00).put(a).put(b),
+            LoadConst(a, b) => Encoder::op(0x01).put(a).put(b),
+            Copy(a, b) => Encoder::op(0x02).put(a).put(b),
+            Add(a, b, c) => Encoder::op(0x03).put(a).put(b).put(c),
+            AddImm(a, b, c) => Encoder::op(0x04).put(a).put(b).put(c),
+            Sub(a, b, c) => Encoder::op(0x05).put(a).put(b).put(c),
+            Mul(a, b, c) => Encoder::op(0x06).put(a).put(b).put(c),
+            MulImm(a, b, c) => Encoder::op(0x07).put(a).put(b).put(c),
+            Div(a, b, c) => Encoder::op(0x08).put(a).put(b).put(c),
+            Rem(a, b, c) => Encoder::op(0x09).put(a).put(b).put(c),
+            And(a, b, c) => Encoder::op(0x0a).put(a).put(b).put(c),
+            Orr(a, b, c) => Encoder::op(0x0b).put(a).put(b).put(c),
+            Xor(a, b, c) => Encoder::op(0x0c).put(a).put(b).put(c),
+            Shl(a, b, c) => Encoder::op(0x37).put(a).put(b).put(c),
+            Shr(a, b, c) => Encoder::op(0x38).put(a).put(b).put(c),
+            CastItoF(a, b) => Encoder::op(0x39).put(a).put(b),
+            CastFtoI(a, b) => Encoder::op(0x3a).put(a).put(b),
+            TupLen(a, b) => Encoder::op(0x3b).put(a).put(b),
+            Concat(a, b, c) => Encoder::op(0x3c).put(a).put(b).put(c),
+            Slice(a, b, c, e) => Encoder::op(0x3d).put(a).put(b).put(c).put(e),
+            Swap(a, b) => Encoder::op(0x3e).put(a).put(b),
+            Select(a, b, c, e) => Encoder::op(0x3f).put(a).put(b).put(c).put(e),
+            Assert(a) => Encoder::op(0x40).put(a),
+            BoolToInt(a, b) => Encoder::op(0x41).put(a).put(b),
+            IntToBool(a, b) => Encoder::op(0x42).put(a).put(b),
+            JumpLong(a) => Encoder::op(0x43).put(a),
+            CondJumpLong(a, b, c) => Encoder::op(0x44).put(a).put(b).put(c),
+            Abs(a, b) => Encoder::op(0x45).put(a).put(b),
+            Sqrt(a, b) => Encoder::op(0x46).put(a).put(b),
+            Floor(a, b) => Encoder::op(0x47).put(a).put(b),
+            Ceil(a, b) => Encoder::op(0x48).put(a).put(b),
+            Round(a, b) => Encoder::op(0x49).put(a).put(b),
+            Min(a, b, c) => Encoder::op(0x4a).put(a).put(b).put(c),
+            Max(a, b, c) => Encoder::op(0x4b).put(a).put(b).put(c),
+            WAdd(a, b, c) => Encoder::op(0x4c).put(a).put(b).put(c),
+            WSub(a, b, c) => Encoder::op(0x4d).put(a).put(b).put(c),
+            WMul(a, b, c) => Encoder::op(0x4e).put(a).put(b).put(c),
+            TupSet(a, b, c) => Encoder::op(0x4f).put(a).put(b).put(c),
+            TupPush(a, b) => Encoder::op(0x50).put(a).put(b),
+            TupPop(a, b) => Encoder::op(0x51).put(a).put(b),
+            Eq(a, b, c) => Encoder::op(0x0d).put(a).put(b).put(c),
+            Neq(a, b, c) => Encoder::op(0x0e).put(a).put(b).put(c),
+            Lt(a, b, c) => Encoder::op(0x0f).put(a).put(b).put(c),
+            Gt(a, b, c) => Encoder::op(0x10).put(a).put(b).put(c),
+            Leq(a, b, c) => Encoder::op(0x11).put(a).put(b).put(c),
+            Geq(a, b, c) => Encoder::op(0x12).put(a).put(b).put(c),
+            Neg(a, b) => Encoder::op(0x13).put(a).put(b),
+            Not(a, b) => Encoder::op(0x14).put(a).put(b),
+            Jump(a) => Encoder::op(0x15).put(a),
+            CondJump(a, b, c) => Encoder::op(0x16).put(a).put(b).put(c),
+            MkTup(a, b, c) => Encoder::op(0x17).put(a).put(b).put(c),
+            UnTup(a, b, c) => Encoder::op(0x18).put(a).put(b).put(c),
+            IdxTup(a, b, c) => Encoder::op(0x19).put(a).put(b).put(c),
+            Call(a, b, c) => Encoder::op(0x1a).put(a).put(b).put(c),
+            MkClosure(a, b, c) => Encoder::op(0x1b).put(a).put(b).put(c),
+            GetUpval(a, b) => Encoder::op(0x1c).put(a).put(b),
+            SetUpval(a, b) => Encoder::op(0x1d).put(a).put(b),
+            Alloc(a) => Encoder::op(0x1e).put(a),
+            Load(a, b) => Encoder::op(0x1f).put(a).put(b),
+            Store(a, b) => Encoder::op(0x20).put(a).put(b),
+            CallNative(a, b, c) => Encoder::op(0x21).put(a).put(b).put(c),
+            Return(None) => Encoder::op(0x22),
+            Return(Some(a)) => Encoder::op(0x23).put(a),
+            Read(a) => Encoder::op(0x24).put(a),
+            Write(a) => Encoder::op(0x25).put(a),
+            Switch(a, b, c) => Encoder::op(0x26).put(a).put(b).put(c),
+            ReadN(a, b) => Encoder::op(0x27).put(a).put(b),
+            WriteN(a) => Encoder::op(0x28).put(a),
+            DebugPrint(a) => Encoder::op(0x29).put(a),
+            Nop => Encoder::op(0x2b),
+            CmpJump(a, b, lt, eq, gt) => Encoder::op(0x2c).put(a).put(b).put(lt).put(eq).put(gt),
+            ReadStr(a) => Encoder::op(0x2d).put(a),
+            WriteStr(a) => Encoder::op(0x2e).put(a),
+            StrLen(a, b) => Encoder::op(0x2f).put(a).put(b),
+            StrCat(a, b, c) => Encoder::op(0x30).put(a).put(b).put(c),
+            StrIdx(a, b, c) => Encoder::op(0x31).put(a).put(b).put(c),
+            Pow(a, b, c) => Encoder::op(0x32).put(a).put(b).put(c),
+            Inspect(a, b) => Encoder::op(0x33).put(a).put(b),
+            #[cfg(feature = "transcendentals")]
+            Sin(a, b) => Encoder::op(0x34).put(a).put(b),
+            #[cfg(feature = "transcendentals")]
+            Cos(a, b) => Encoder::op(0x35).put(a).put(b),
+            #[cfg(feature = "transcendentals")]
+            Tan(a, b) => Encoder::op(0x36).put(a).put(b),
+            FEq(a, b, c, e) => Encoder::op(0x2a).put(a).put(b).put(c).put(e),
+        };
+        e.bytes
+    }
+
+    /// Decodes an instruction from the start of `bytes`, returning it along
+    /// with the number of bytes consumed.
+    ///
+    /// Returns `None` if the opcode is unknown or there aren't enough bytes.
+    pub fn decode(bytes: &[u8]) -> Option<(Instr, usize)> {
+        use self::Instr::*;
+        let mut d = Decoder { bytes, pos: 1 };
+        let instr = match *bytes.first()? {
+            0x00 => Const(d.get()?, d.get()?),
+            0x01 => LoadConst(d.get()?, d.get()?),
+            0x02 => Copy(d.get()?, d.get()?),
+            0x03 => Add(d.get()?, d.get()?, d.get()?),
+            0x04 => AddImm(d.get()?, d.get()?, d.get()?),
+            0x05 => Sub(d.get()?, d.get()?, d.get()?),
+            0x06 => Mul(d.get()?, d.get()?, d.get()?),
+            0x07 => MulImm(d.get()?, d.get()?, d.get()?),
+            0x08 => Div(d.get()?, d.get()?, d.get()?),
+            0x09 => Rem(d.get()?, d.get()?, d.get()?),
+            0x0a => And(d.get()?, d.get()?, d.get()?),
+            0x0b => Orr(d.get()?, d.get()?, d.get()?),
+            0x0c => Xor(d.get()?, d.get()?, d.get()?),
+            0x0d => Eq(d.get()?, d.get()?, d.get()?),
+            0x0e => Neq(d.get()?, d.get()?, d.get()?),
+            0x0f => Lt(d.get()?, d.get()?, d.get()?),
+            0x10 => Gt(d.get()?, d.get()?, d.get()?),
+            0x11 => Leq(d.get()?, d.get()?, d.get()?),
+            0x12 => Geq(d.get()?, d.get()?, d.get()?),
+            0x13 => Neg(d.get()?, d.get()?),
+            0x14 => Not(d.get()?, d.get()?),
+            0x15 => Jump(d.get()?),
+            0x16 => CondJump(d.get()?, d.get()?, d.get()?),
+            0x17 => MkTup(d.get()?, d.get()?, d.get()?),
+            0x18 => UnTup(d.get()?, d.get()?, d.get()?),
+            0x19 => IdxTup(d.get()?, d.get()?, d.get()?),
+            0x1a => Call(d.get()?, d.get()?, d.get()?),
+            0x1b => MkClosure(d.get()?, d.get()?, d.get()?),
+            0x1c => GetUpval(d.get()?, d.get()?),
+            0x1d => SetUpval(d.get()?, d.get()?),
+            0x1e => Alloc(d.get()?),
+            0x1f => Load(d.get()?, d.get()?),
+            0x20 => Store(d.get()?, d.get()?),
+            0x21 => CallNative(d.get()?, d.get()?, d.get()?),
+            0x22 => Return(None),
+            0x23 => Return(Some(d.get()?)),
+            0x24 => Read(d.get()?),
+            0x25 => Write(d.get()?),
+            0x26 => Switch(d.get()?, d.get()?, d.get()?),
+            0x27 => ReadN(d.get()?, d.get()?),
+            0x28 => WriteN(d.get()?),
+            0x29 => DebugPrint(d.get()?),
+            0x2a => FEq(d.get()?, d.get()?, d.get()?, d.get()?),
+            0x2b => Nop,
+            0x2c => CmpJump(d.get()?, d.get()?, d.get()?, d.get()?, d.get()?),
+            0x2d => ReadStr(d.get()?),
+            0x2e => WriteStr(d.get()?),
+            0x2f => StrLen(d.get()?, d.get()?),
+            0x30 => StrCat(d.get()?, d.get()?, d.get()?),
+            0x31 => StrIdx(d.get()?, d.get()?, d.get()?),
+            0x32 => Pow(d.get()?, d.get()?, d.get()?),
+            0x33 => Inspect(d.get()?, d.get()?),
+            #[cfg(feature = "transcendentals")]
+            0x34 => Sin(d.get()?, d.get()?),
+            #[cfg(feature = "transcendentals")]
+            0x35 => Cos(d.get()?, d.get()?),
+            #[cfg(feature = "transcendentals")]
+            0x36 => Tan(d.get()?, d.get()?),
+            0x37 => Shl(d.get()?, d.get()?, d.get()?),
+            0x38 => Shr(d.get()?, d.get()?, d.get()?),
+            0x39 => CastItoF(d.get()?, d.get()?),
+            0x3a => CastFtoI(d.get()?, d.get()?),
+            0x3b => TupLen(d.get()?, d.get()?),
+            0x3c => Concat(d.get()?, d.get()?, d.get()?),
+            0x3d => Slice(d.get()?, d.get()?, d.get()?, d.get()?),
+            0x3e => Swap(d.get()?, d.get()?),
+            0x3f => Select(d.get()?, d.get()?, d.get()?, d.get()?),
+            0x40 => Assert(d.get()?),
+            0x41 => BoolToInt(d.get()?, d.get()?),
+            0x42 => IntToBool(d.get()?, d.get()?),
+            0x43 => JumpLong(d.get()?),
+            0x44 => CondJumpLong(d.get()?, d.get()?, d.get()?),
+            0x45 => Abs(d.get()?, d.get()?),
+            0x46 => Sqrt(d.get()?, d.get()?),
+            0x47 => Floor(d.get()?, d.get()?),
+            0x48 => Ceil(d.get()?, d.get()?),
+            0x49 => Round(d.get()?, d.get()?),
+            0x4a => Min(d.get()?, d.get()?, d.get()?),
+            0x4b => Max(d.get()?, d.get()?, d.get()?),
+            0x4c => WAdd(d.get()?, d.get()?, d.get()?),
+            0x4d => WSub(d.get()?, d.get()?, d.get()?),
+            0x4e => WMul(d.get()?, d.get()?, d.get()?),
+            0x4f => TupSet(d.get()?, d.get()?, d.get()?),
+            0x50 => TupPush(d.get()?, d.get()?),
+            0x51 => TupPop(d.get()?, d.get()?),
+            _ => return None,
+        };
+        Some((instr, d.pos))
+    }
+}
+
+impl Program {
+    /// Encodes the whole program into its binary form.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.entry_point.to_le_bytes());
+        bytes.extend_from_slice(&(self.defns.len() as u32).to_le_bytes());
+        for defn in &self.defns {
+            bytes.extend_from_slice(&defn.local_count.to_le_bytes());
+            encode_seq(&defn.consts, &mut bytes);
+            bytes.extend_from_slice(&(defn.code.len() as u32).to_le_bytes());
+            for instr in &defn.code {
+                bytes.extend_from_slice(&instr.encode());
+            }
+        }
+        bytes
+    }
+
+    /// Decodes a program from its binary form.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, DecodeError> {
+        let entry_point = decode_u16(bytes)? as FnId;
+        let defn_count = decode_u32(&bytes[2..])?;
+        let mut pos = 6;
+        let mut defns = Vec::new();
+        for _ in 0..defn_count {
+            let local_count: AddrSize = decode_operand(&bytes[pos..])?;
+            pos += mem::size_of::<AddrSize>();
+            let (consts, size) = decode_seq(&bytes[pos..], 0)?;
+            pos += size;
+            let code_len = decode_u32(&bytes[pos..])?;
+            pos += 4;
+            let mut code = Vec::new();
+            for _ in 0..code_len {
+                let (instr, size) = Instr::decode(&bytes[pos..]).ok_or(DecodeError {})?;
+                code.push(instr);
+                pos += size;
+            }
+            defns.push(Defn {
+                consts,
+                code,
+                local_count,
+            });
+        }
+        if pos != bytes.len() {
+            return Err(DecodeError {});
+        }
+        Ok(Program {
+            defns,
+            entry_point,
+            stack_depth: Default::default(),
+        })
+    }
+}
+
+fn decode_operand<T: Operand>(bytes: &[u8]) -> Result<T, DecodeError> {
+    T::decode(bytes).ok_or(DecodeError {})
+}
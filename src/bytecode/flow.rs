@@ -0,0 +1,204 @@
+//! Control flow analysis: basic blocks, the graph between them, and loops.
+
+use super::liveness::BitSet;
+use super::{Defn, Instr};
+
+/// A run of instructions `code[start..end]` that is only entered at `start`
+/// and only left after `end - 1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A natural loop, made of the blocks that can reach a jump back to `header`
+/// without passing through it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    pub header: usize,
+    /// Indices of the loop's blocks in increasing order, including the header.
+    pub blocks: Vec<usize>,
+}
+
+/// The basic blocks of a function and the jumps between them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlFlowGraph {
+    pub blocks: Vec<BasicBlock>,
+    pub succs: Vec<Vec<usize>>,
+    pub preds: Vec<Vec<usize>>,
+}
+
+impl Defn {
+    /// Splits the code into basic blocks, in order.
+    pub fn basic_blocks(&self) -> Vec<BasicBlock> {
+        let len = self.code().len();
+        let mut leaders = vec![false; len + 1];
+        leaders[0] = true;
+        leaders[len] = true;
+        for (iptr, instr) in self.code().iter().enumerate() {
+            if ends_block(instr) {
+                leaders[iptr + 1] = true;
+            }
+        }
+        for target in self.jump_targets() {
+            if target < len {
+                leaders[target] = true;
+            }
+        }
+
+        let starts = (0..len + 1).filter(|&i| leaders[i]).collect::<Vec<_>>();
+        starts
+            .windows(2)
+            .map(|w| BasicBlock {
+                start: w[0],
+                end: w[1],
+            })
+            .collect()
+    }
+}
+
+impl ControlFlowGraph {
+    /// Builds the graph for `defn`. A `Switch` may jump anywhere, so it has
+    /// an edge to every block.
+    pub fn new(defn: &Defn) -> ControlFlowGraph {
+        use self::Instr::*;
+
+        let blocks = defn.basic_blocks();
+        let mut succs = vec![Vec::new(); blocks.len()];
+        let mut preds = vec![Vec::new(); blocks.len()];
+        for (b, block) in blocks.iter().enumerate() {
+            let last = block.end - 1;
+            let mut targets = match defn.code()[last] {
+                Return(_) => vec![],
+                Switch(..) => (0..blocks.len()).collect(),
+                ref instr if !instr.jump_offsets().is_empty() => instr
+                    .jump_offsets()
+                    .into_iter()
+                    .map(|off| last as isize + off)
+                    .filter(|&t| 0 <= t && (t as usize) < defn.code().len())
+                    .map(|t| block_at(&blocks, t as usize))
+                    .collect(),
+                _ if b + 1 < blocks.len() => vec![b + 1],
+                _ => vec![],
+            };
+            targets.sort();
+            targets.dedup();
+            for &t in &targets {
+                preds[t].push(b);
+            }
+            succs[b] = targets;
+        }
+        ControlFlowGraph {
+            blocks,
+            succs,
+            preds,
+        }
+    }
+
+    /// The index of the block containing the instruction at `iptr`.
+    pub fn block_of(&self, iptr: usize) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|block| block.start <= iptr && iptr < block.end)
+    }
+
+    /// For each block, the set of blocks that every path from the entry to it
+    /// passes through. Blocks the entry can't reach have an empty set.
+    pub fn dominators(&self) -> Vec<BitSet> {
+        let n = self.blocks.len();
+        let reachable = self.reachable();
+        let mut all = BitSet::new(n);
+        for b in 0..n {
+            all.insert(b);
+        }
+        let mut doms = (0..n)
+            .map(|b| if reachable[b] { all.clone() } else { BitSet::new(n) })
+            .collect::<Vec<_>>();
+        if n == 0 {
+            return doms;
+        }
+        doms[0] = BitSet::new(n);
+        doms[0].insert(0);
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (1..n).filter(|&b| reachable[b]) {
+                let mut dom = doms[b].clone();
+                for &p in self.preds[b].iter().filter(|&&p| reachable[p]) {
+                    dom.intersect_with(&doms[p]);
+                }
+                dom.insert(b);
+                if dom != doms[b] {
+                    doms[b] = dom;
+                    changed = true;
+                }
+            }
+        }
+        doms
+    }
+
+    /// Finds the natural loops of the function. Loops sharing a header are
+    /// merged into one. Unreachable code is never part of a loop.
+    pub fn loops(&self) -> Vec<Loop> {
+        let doms = self.dominators();
+        let mut loops: Vec<Loop> = Vec::new();
+        for (b, succs) in self.succs.iter().enumerate() {
+            for &header in succs {
+                // A back edge jumps to a block that dominates it. Unreachable
+                // blocks have no dominators, so they never count.
+                if !doms[b].contains(header) {
+                    continue;
+                }
+                let mut body = vec![header];
+                let mut todo = vec![b];
+                while let Some(block) = todo.pop() {
+                    if !body.contains(&block) && !doms[block].is_empty() {
+                        body.push(block);
+                        todo.extend(&self.preds[block]);
+                    }
+                }
+                match loops.iter_mut().find(|l| l.header == header) {
+                    Some(l) => l.blocks.extend(body),
+                    None => loops.push(Loop {
+                        header,
+                        blocks: body,
+                    }),
+                }
+            }
+        }
+        for l in &mut loops {
+            l.blocks.sort();
+            l.blocks.dedup();
+        }
+        loops
+    }
+
+    /// Which blocks some path from the entry reaches.
+    fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut todo = if self.blocks.is_empty() { vec![] } else { vec![0] };
+        while let Some(b) = todo.pop() {
+            if !reachable[b] {
+                reachable[b] = true;
+                todo.extend(&self.succs[b]);
+            }
+        }
+        reachable
+    }
+}
+
+/// Whether execution might not continue to the next instruction.
+fn ends_block(instr: &Instr) -> bool {
+    match *instr {
+        Instr::Return(_) | Instr::Switch(..) => true,
+        ref instr => !instr.jump_offsets().is_empty(),
+    }
+}
+
+fn block_at(blocks: &[BasicBlock], iptr: usize) -> usize {
+    blocks
+        .iter()
+        .position(|block| block.start == iptr)
+        .expect("jump targets start blocks")
+}
@@ -0,0 +1,175 @@
+//! Backward liveness analysis over the registers of a function.
+//!
+//! A register is live before an instruction if some path from that
+//! instruction reads the register before writing to it.
+
+use smallvec::SmallVec;
+
+use super::{Addr, Defn, Instr};
+
+/// A fixed size set of register indices.
+///
+/// Stored as `u64` words, so functions with up to 64 locals don't allocate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitSet {
+    words: SmallVec<[u64; 1]>,
+    capacity: usize,
+}
+
+impl BitSet {
+    /// Creates an empty set that can hold the indices `0..capacity`.
+    pub fn new(capacity: usize) -> BitSet {
+        BitSet {
+            words: SmallVec::from_elem(0, capacity.div_ceil(64)),
+            capacity,
+        }
+    }
+
+    /// The number of indices the set can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns true if no index is in the set.
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|&w| w == 0)
+    }
+
+    pub fn contains(&self, i: usize) -> bool {
+        i < self.capacity && self.words[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    /// Adds `i` to the set. Indices past the end of the set are ignored.
+    pub fn insert(&mut self, i: usize) {
+        if i < self.capacity {
+            self.words[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    pub fn remove(&mut self, i: usize) {
+        if i < self.capacity {
+            self.words[i / 64] &= !(1 << (i % 64));
+        }
+    }
+
+    /// Adds every index in `other` to the set, returning true if it changed.
+    pub fn union_with(&mut self, other: &BitSet) -> bool {
+        let mut changed = false;
+        for (w, &o) in self.words.iter_mut().zip(other.words.iter()) {
+            changed |= *w | o != *w;
+            *w |= o;
+        }
+        changed
+    }
+
+    /// Removes every index not in `other` from the set.
+    pub fn intersect_with(&mut self, other: &BitSet) {
+        for (w, &o) in self.words.iter_mut().zip(other.words.iter()) {
+            *w &= o;
+        }
+    }
+
+    /// Iterates over the indices in the set in increasing order.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
+        (0..self.capacity).filter(move |&i| self.contains(i))
+    }
+}
+
+/// Computes the registers live immediately before each instruction of `defn`.
+///
+/// The analysis iterates to a fixed point, so loops are handled. A `Switch`
+/// can jump anywhere, so it's treated as a possible predecessor of every
+/// instruction.
+pub fn compute(defn: &Defn) -> Vec<BitSet> {
+    let code = defn.code();
+    let size = defn.local_count() as usize;
+    let mut live = vec![BitSet::new(size); code.len()];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for iptr in (0..code.len()).rev() {
+            let mut out = BitSet::new(size);
+            for succ in successors(code, iptr) {
+                out.union_with(&live[succ]);
+            }
+            let (uses, defs) = uses_and_defs(&code[iptr]);
+            for a in defs {
+                out.remove(a);
+            }
+            for a in uses {
+                out.insert(a);
+            }
+            changed |= live[iptr].union_with(&out);
+        }
+    }
+    live
+}
+
+/// The instructions that can run right after the one at `iptr`.
+fn successors(code: &[Instr], iptr: usize) -> Vec<usize> {
+    use self::Instr::*;
+
+    let offsets = match code[iptr] {
+        Return(_) => vec![],
+        Jump(_) | CondJump(..) | CmpJump(..) | JumpLong(_) | CondJumpLong(..) => {
+            code[iptr].jump_offsets()
+        }
+        Switch(..) => return (0..code.len()).collect(),
+        _ => vec![1],
+    };
+    offsets
+        .into_iter()
+        .map(|off| iptr as isize + off)
+        .filter(|&target| 0 <= target && (target as usize) < code.len())
+        .map(|target| target as usize)
+        .collect()
+}
+
+/// The registers an instruction reads and the registers it writes.
+pub(super) fn uses_and_defs(instr: &Instr) -> (Vec<usize>, Vec<usize>) {
+    use self::Instr::*;
+
+    let range = |start: Addr, len| (start as usize..start as usize + len as usize).collect();
+    match *instr {
+        Const(a, _) | LoadConst(a, _) | GetUpval(a, _) | Read(a) | ReadStr(a) => {
+            (vec![], vec![a as usize])
+        }
+        Copy(a, b) | AddImm(a, b, _) | MulImm(a, b, _) | Neg(a, b) | Not(a, b) | Load(a, b)
+        | Abs(a, b) | Sqrt(a, b) | Floor(a, b) | Ceil(a, b) | Round(a, b) | CastItoF(a, b)
+        | CastFtoI(a, b) | BoolToInt(a, b) | IntToBool(a, b)
+        | CallNative(a, _, b) | ReadN(a, b) | StrLen(a, b) | TupLen(a, b) | Inspect(a, b) => {
+            (vec![b as usize], vec![a as usize])
+        }
+        #[cfg(feature = "transcendentals")]
+        Sin(a, b) | Cos(a, b) | Tan(a, b) => (vec![b as usize], vec![a as usize]),
+        Add(a, b, c) | Sub(a, b, c) | Mul(a, b, c) | Div(a, b, c) | Rem(a, b, c) | Pow(a, b, c)
+        | WAdd(a, b, c) | WSub(a, b, c) | WMul(a, b, c) | Min(a, b, c) | Max(a, b, c)
+        | And(a, b, c) | Orr(a, b, c) | Xor(a, b, c) | Shl(a, b, c) | Shr(a, b, c)
+        | Eq(a, b, c) | Neq(a, b, c)
+        | Lt(a, b, c) | Gt(a, b, c) | Leq(a, b, c) | Geq(a, b, c) | IdxTup(a, b, c)
+        | Concat(a, b, c) | Call(a, b, c) | MkClosure(a, b, c) | StrCat(a, b, c)
+        | StrIdx(a, b, c) => {
+            (vec![b as usize, c as usize], vec![a as usize])
+        }
+        FEq(a, b, c, d) | Select(a, b, c, d) | Slice(a, b, c, d) => {
+            (vec![b as usize, c as usize, d as usize], vec![a as usize])
+        }
+        TupSet(a, b, c) => (vec![a as usize, b as usize, c as usize], vec![a as usize]),
+        TupPush(a, b) => (vec![a as usize, b as usize], vec![a as usize]),
+        TupPop(a, b) => (vec![b as usize], vec![a as usize, b as usize]),
+        MkTup(a, b, c) => (range(b, c), vec![a as usize]),
+        UnTup(a, b, c) => (vec![c as usize], range(a, b)),
+        // Alloc reads the value it moves onto the heap
+        Alloc(a) => (vec![a as usize], vec![a as usize]),
+        Store(a, b) => (vec![a as usize, b as usize], vec![]),
+        Swap(a, b) => (vec![a as usize, b as usize], vec![a as usize, b as usize]),
+        SetUpval(_, a) | Write(a) | WriteN(a) | WriteStr(a) | DebugPrint(a) | Assert(a)
+        | CondJump(a, _, _) | CondJumpLong(a, _, _) => {
+            (vec![a as usize], vec![])
+        }
+        Switch(a, b, _) | CmpJump(a, b, _, _, _) => (vec![a as usize, b as usize], vec![]),
+        Return(a) => (a.into_iter().map(|a| a as usize).collect(), vec![]),
+        Jump(_) | JumpLong(_) | Nop => (vec![], vec![]),
+    }
+}
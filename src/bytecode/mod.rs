@@ -1,32 +1,74 @@
 #[cfg(test)]
 mod tests;
+mod encode;
+pub mod flow;
+pub mod liveness;
+pub mod opt;
 pub mod parse;
+mod source_map;
 
+pub use self::encode::DecodeError;
+pub use self::source_map::{SourceEntry, SourceMap};
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt;
-use std::io::{Read, Write};
-use std::ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Neg, Not, Rem, Sub};
-use std::cmp::{Ordering, PartialOrd};
+use std::mem;
+use std::iter;
+use std::slice;
+use std::sync::{Arc, Mutex, OnceLock};
+use rayon::prelude::*;
+use std::io::{self, Cursor, Read, Write};
+use std::ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Neg, Not, Range, Rem, Shl, Shr, Sub};
+use std::cmp::{self, Ordering, PartialOrd};
 
+#[cfg(not(feature = "wide_regs"))]
 type Addr = u8;
+#[cfg(not(feature = "wide_regs"))]
 type AddrSize = u8;
+#[cfg(feature = "wide_regs")]
+type Addr = u16;
+#[cfg(feature = "wide_regs")]
+type AddrSize = u16;
 type FnId = u16;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Instr {
     /// Loads a constant a = k[b]
     Const(Addr, Addr),
+    /// Loads a constant a = k[b], for constant pools too large for `Const` to address
+    LoadConst(Addr, u16),
     /// Copies a = b
     Copy(Addr, Addr),
+    /// Exchanges the values of a and b
+    Swap(Addr, Addr),
     /// a = b + c
     Add(Addr, Addr, Addr),
+    /// a = b + c, where c is a small immediate rather than a register
+    AddImm(Addr, Addr, i8),
     /// a = b - c
     Sub(Addr, Addr, Addr),
     /// a = b * c
     Mul(Addr, Addr, Addr),
+    /// a = b * c, where c is a small immediate rather than a register
+    MulImm(Addr, Addr, u8),
+    /// a = b + c for integers, wrapping around on overflow
+    WAdd(Addr, Addr, Addr),
+    /// a = b - c for integers, wrapping around on overflow
+    WSub(Addr, Addr, Addr),
+    /// a = b * c for integers, wrapping around on overflow
+    WMul(Addr, Addr, Addr),
     /// a = b / c
     Div(Addr, Addr, Addr),
     /// a = b % c
     Rem(Addr, Addr, Addr),
+    /// a = b ** c
+    /// Integer exponents must not be negative unless the base is a float.
+    Pow(Addr, Addr, Addr),
+    /// The smaller of two integers or of two floats, a = min(b, c). Fails on NaN.
+    Min(Addr, Addr, Addr),
+    /// The larger of two integers or of two floats, a = max(b, c). Fails on NaN.
+    Max(Addr, Addr, Addr),
     /// a = b & c
     ///
     /// This acts as a boolean and as well as a bitwise and.
@@ -37,7 +79,14 @@ pub enum Instr {
     Orr(Addr, Addr, Addr),
     /// a = b ^ c
     Xor(Addr, Addr, Addr),
+    /// a = b << c, for integers. Fails unless c is from 0 to 63.
+    Shl(Addr, Addr, Addr),
+    /// a = b >> c, a logical shift that fills with zeros. Fails unless c is
+    /// from 0 to 63.
+    Shr(Addr, Addr, Addr),
     /// a = b == c
+    /// Unlike IEEE floats, NaN is equal to itself. Use `FEq` to compare floats
+    /// numerically.
     Eq(Addr, Addr, Addr),
     /// a = b != c
     Neq(Addr, Addr, Addr),
@@ -49,33 +98,130 @@ pub enum Instr {
     Leq(Addr, Addr, Addr),
     /// a = b >= c
     Geq(Addr, Addr, Addr),
-    /// a = -b
+    /// a = |b - c| <= d, for floats b, c, and d. False if any of them is NaN.
+    FEq(Addr, Addr, Addr, Addr),
+    /// a = c if b is true, or d if b is false
+    Select(Addr, Addr, Addr, Addr),
+    /// a = -b, failing for the smallest integer since its negation overflows
     Neg(Addr, Addr),
     /// a = !b
     Not(Addr, Addr),
+    /// a = sqrt(b), for a float b. Fails if b is negative.
+    Sqrt(Addr, Addr),
+    /// Rounds a float down, a = floor(b)
+    Floor(Addr, Addr),
+    /// Rounds a float up, a = ceil(b)
+    Ceil(Addr, Addr),
+    /// Rounds a float to the nearest integer, with halves rounded away from zero
+    Round(Addr, Addr),
+    /// a = |b|, failing for the smallest integer since it has no positive counterpart
+    Abs(Addr, Addr),
+    /// Converts an integer to the nearest float, a = b as f64
+    CastItoF(Addr, Addr),
+    /// Converts a float to an integer by rounding towards zero. Fails for NaN
+    /// and for floats too large to fit.
+    CastFtoI(Addr, Addr),
+    /// Converts a boolean to 1 for true or 0 for false
+    BoolToInt(Addr, Addr),
+    /// Converts an integer to a boolean that is true unless it's 0
+    IntToBool(Addr, Addr),
     /// Jumps program execution by n instructions
     Jump(i16),
     /// Jumps program execution by n instructions if a is true, else it jumps by m instructions
     /// Note that a must be a boolean, otherwise the program is invalid.
     CondJump(Addr, i8, i8),
+    /// Like `Jump`, but with room for offsets too large for it
+    JumpLong(i32),
+    /// Like `CondJump`, but with room for offsets too large for it
+    CondJumpLong(Addr, i32, i32),
+    /// Compares a and b, then jumps by the first offset if a < b, the second if a == b,
+    /// and the third if a > b. Values that can't be ordered, like NaN, are an error.
+    CmpJump(Addr, Addr, i16, i16, i16),
+    /// Jumps program execution by b + a instructions if 0 <= a < c, else falls through.
+    /// Note that a and b must be integers, otherwise the program is invalid.
+    Switch(Addr, Addr, u8),
     /// Constructs a tuple, a = (b; c)
     /// Takes a contiguous range of c slots starting at b, a = (_; 0) builds the empty tuple.
-    MkTup(Addr, Addr, u8),
+    MkTup(Addr, Addr, AddrSize),
     /// Destructs a tuple (a; b) = c
     /// Unpacks c into a contiguous range of b slots starting at a.
-    UnTup(Addr, u8, Addr),
+    UnTup(Addr, AddrSize, Addr),
     /// Indexes a tuple a = b[c]
     IdxTup(Addr, Addr, Addr),
+    /// Replaces a tuple element in place, a[b] = c
+    TupSet(Addr, Addr, Addr),
+    /// Appends b to the end of the tuple in a
+    TupPush(Addr, Addr),
+    /// Removes the last element of the tuple in b and stores it in a
+    TupPop(Addr, Addr),
+    /// Number of elements in a tuple, a = len(b)
+    TupLen(Addr, Addr),
+    /// Concatenates two tuples, a = b ++ c
+    /// Written `x0 := concat x1 x2`, since `++` is already `StrCat`.
+    Concat(Addr, Addr, Addr),
+    /// Takes the elements of a tuple from index c up to but excluding d, a = b[c..d]
+    Slice(Addr, Addr, Addr, Addr),
     /// Calls a function, a = b(c).
     /// This expects c to be a tuple of arguments to b, and b to be a function type.
     Call(Addr, Addr, Addr),
+    /// Creates a closure, a = b{c}.
+    /// This expects b to be a function and c to be a tuple of values to capture.
+    MkClosure(Addr, Addr, Addr),
+    /// Loads a captured value from the current closure, a = upvals[b]
+    GetUpval(Addr, u8),
+    /// Stores into a captured value of the current closure, upvals[a] = b.
+    /// On return, the register holding the called closure sees the update.
+    SetUpval(u8, Addr),
+    /// Moves a onto the heap, replacing it with a reference to the new cell.
+    Alloc(Addr),
+    /// Loads from a heap cell, a = *b
+    Load(Addr, Addr),
+    /// Stores into a heap cell, *a = b
+    Store(Addr, Addr),
+    /// Calls a function provided by the host, a = natives[b](c).
+    CallNative(Addr, u32, Addr),
     /// Return the value stored in a.
     /// If a is None, then this returns an empty tuple.
     Return(Option<Addr>),
-    /// Read a byte from stdin and store it in a
+    /// Read a byte from stdin and store it in a. Fails if the input has ended.
     Read(Addr),
     /// Write a byte stored in a to stdout
     Write(Addr),
+    /// Read b bytes from stdin and store them in a as a tuple of integers
+    ReadN(Addr, Addr),
+    /// Write a tuple of bytes stored in a to stdout
+    /// Also written `write_all x0` in assembly.
+    WriteN(Addr),
+    /// Byte length of a string, a = len(b)
+    StrLen(Addr, Addr),
+    /// Concatenates two strings, a = b ++ c
+    StrCat(Addr, Addr, Addr),
+    /// Reads a byte of a string, a = b[c]
+    /// `IdxTup` does the same when given a string.
+    StrIdx(Addr, Addr, Addr),
+    /// Read a null-terminated UTF-8 string from stdin and store it in a
+    ReadStr(Addr),
+    /// Write the string stored in a to stdout, followed by a null byte
+    WriteStr(Addr),
+    /// Fails with `AssertionFailed` if a is false, and does nothing if it's true.
+    Assert(Addr),
+    /// Does nothing.
+    Nop,
+    /// Print a to stderr for debugging. Does nothing unless the
+    /// `debug_instrs` feature is enabled.
+    DebugPrint(Addr),
+    /// Stores the type tag of b in a, as given by `Val::type_tag`.
+    /// Also written `x0 := typeof x1` in assembly.
+    Inspect(Addr, Addr),
+    /// a = sin(b), for a float b. Needs the `transcendentals` feature.
+    #[cfg(feature = "transcendentals")]
+    Sin(Addr, Addr),
+    /// a = cos(b), for a float b. Needs the `transcendentals` feature.
+    #[cfg(feature = "transcendentals")]
+    Cos(Addr, Addr),
+    /// a = tan(b), for a float b. Needs the `transcendentals` feature.
+    #[cfg(feature = "transcendentals")]
+    Tan(Addr, Addr),
 }
 
 impl fmt::Display for Instr {
@@ -83,44 +229,310 @@ impl fmt::Display for Instr {
         use self::Instr::*;
         match *self {
             Const(a, b) => write!(fmt, "x{} := k{}", a, b),
+            LoadConst(a, b) => write!(fmt, "x{} := K{}", a, b),
             Copy(a, b) => write!(fmt, "x{} := x{}", a, b),
+            Swap(a, b) => write!(fmt, "swap x{} x{}", a, b),
             Add(a, b, c) => write!(fmt, "x{} := x{} + x{}", a, b, c),
+            AddImm(a, b, c) => write!(fmt, "x{} := x{} +# {}", a, b, c),
             Sub(a, b, c) => write!(fmt, "x{} := x{} - x{}", a, b, c),
             Mul(a, b, c) => write!(fmt, "x{} := x{} * x{}", a, b, c),
+            MulImm(a, b, c) => write!(fmt, "x{} := x{} *# {}", a, b, c),
+            WAdd(a, b, c) => write!(fmt, "x{} := x{} +% x{}", a, b, c),
+            WSub(a, b, c) => write!(fmt, "x{} := x{} -% x{}", a, b, c),
+            WMul(a, b, c) => write!(fmt, "x{} := x{} *% x{}", a, b, c),
             Div(a, b, c) => write!(fmt, "x{} := x{} / x{}", a, b, c),
             Rem(a, b, c) => write!(fmt, "x{} := x{} % x{}", a, b, c),
+            Pow(a, b, c) => write!(fmt, "x{} := x{} ** x{}", a, b, c),
+            Min(a, b, c) => write!(fmt, "x{} := min x{} x{}", a, b, c),
+            Max(a, b, c) => write!(fmt, "x{} := max x{} x{}", a, b, c),
             And(a, b, c) => write!(fmt, "x{} := x{} & x{}", a, b, c),
             Orr(a, b, c) => write!(fmt, "x{} := x{} | x{}", a, b, c),
             Xor(a, b, c) => write!(fmt, "x{} := x{} ^ x{}", a, b, c),
+            Shl(a, b, c) => write!(fmt, "x{} := x{} << x{}", a, b, c),
+            Shr(a, b, c) => write!(fmt, "x{} := x{} >> x{}", a, b, c),
             Eq(a, b, c) => write!(fmt, "x{} := x{} == x{}", a, b, c),
             Neq(a, b, c) => write!(fmt, "x{} := x{} != x{}", a, b, c),
             Lt(a, b, c) => write!(fmt, "x{} := x{} < x{}", a, b, c),
             Gt(a, b, c) => write!(fmt, "x{} := x{} > x{}", a, b, c),
             Leq(a, b, c) => write!(fmt, "x{} := x{} <= x{}", a, b, c),
             Geq(a, b, c) => write!(fmt, "x{} := x{} >= x{}", a, b, c),
+            FEq(a, b, c, d) => write!(fmt, "x{} := x{} ~= x{} x{}", a, b, c, d),
+            Select(a, b, c, d) => write!(fmt, "x{} := select x{} x{} x{}", a, b, c, d),
             Neg(a, b) => write!(fmt, "x{} := -x{}", a, b),
             Not(a, b) => write!(fmt, "x{} := !x{}", a, b),
+            Abs(a, b) => write!(fmt, "x{} := abs x{}", a, b),
+            Sqrt(a, b) => write!(fmt, "x{} := sqrt x{}", a, b),
+            Floor(a, b) => write!(fmt, "x{} := floor x{}", a, b),
+            Ceil(a, b) => write!(fmt, "x{} := ceil x{}", a, b),
+            Round(a, b) => write!(fmt, "x{} := round x{}", a, b),
+            CastItoF(a, b) => write!(fmt, "x{} := i2f x{}", a, b),
+            CastFtoI(a, b) => write!(fmt, "x{} := f2i x{}", a, b),
+            BoolToInt(a, b) => write!(fmt, "x{} := b2i x{}", a, b),
+            IntToBool(a, b) => write!(fmt, "x{} := i2b x{}", a, b),
             Jump(off) => write!(fmt, "jump {}", off),
             CondJump(a, b, c) => write!(fmt, "cond x{} {} {}", a, b, c),
+            JumpLong(off) => write!(fmt, "jumpl {}", off),
+            CondJumpLong(a, b, c) => write!(fmt, "condl x{} {} {}", a, b, c),
+            CmpJump(a, b, lt, eq, gt) => write!(fmt, "cmp3 x{} x{} {} {} {}", a, b, lt, eq, gt),
+            Switch(a, b, c) => write!(fmt, "switch x{} x{} {}", a, b, c),
             MkTup(a, b, c) => write!(fmt, "x{} := (x{}; {})", a, b, c),
             UnTup(a, b, c) => write!(fmt, "(x{}; {}) := x{}", a, b, c),
             IdxTup(a, b, c) => write!(fmt, "x{} := x{}[x{}]", a, b, c),
+            TupSet(a, b, c) => write!(fmt, "x{}[x{}] := x{}", a, b, c),
+            TupPush(a, b) => write!(fmt, "push x{} x{}", a, b),
+            TupPop(a, b) => write!(fmt, "x{} := pop x{}", a, b),
+            TupLen(a, b) => write!(fmt, "x{} := len x{}", a, b),
+            Concat(a, b, c) => write!(fmt, "x{} := concat x{} x{}", a, b, c),
+            Slice(a, b, c, d) => write!(fmt, "x{} := x{}[x{}..x{}]", a, b, c, d),
             Call(a, b, c) => write!(fmt, "x{} := x{}(x{})", a, b, c),
+            MkClosure(a, b, c) => write!(fmt, "x{} := closure x{} x{}", a, b, c),
+            GetUpval(a, b) => write!(fmt, "x{} := upval {}", a, b),
+            SetUpval(a, b) => write!(fmt, "upval {} := x{}", a, b),
+            Alloc(a) => write!(fmt, "alloc x{}", a),
+            Load(a, b) => write!(fmt, "x{} := *x{}", a, b),
+            Store(a, b) => write!(fmt, "*x{} := x{}", a, b),
+            CallNative(a, b, c) => write!(fmt, "x{} := native {}(x{})", a, b, c),
             Return(None) => write!(fmt, "return"),
             Return(Some(a)) => write!(fmt, "return x{}", a),
             Read(a) => write!(fmt, "x{} := read", a),
             Write(a) => write!(fmt, "write x{}", a),
+            ReadN(a, b) => write!(fmt, "x{} := readn x{}", a, b),
+            WriteN(a) => write!(fmt, "writen x{}", a),
+            StrLen(a, b) => write!(fmt, "x{} := strlen x{}", a, b),
+            StrCat(a, b, c) => write!(fmt, "x{} := x{} ++ x{}", a, b, c),
+            StrIdx(a, b, c) => write!(fmt, "x{} := stridx x{} x{}", a, b, c),
+            ReadStr(a) => write!(fmt, "x{} := readstr", a),
+            WriteStr(a) => write!(fmt, "writestr x{}", a),
+            Assert(a) => write!(fmt, "assert x{}", a),
+            Nop => write!(fmt, "nop"),
+            DebugPrint(a) => write!(fmt, "debug x{}", a),
+            Inspect(a, b) => write!(fmt, "x{} := inspect x{}", a, b),
+            #[cfg(feature = "transcendentals")]
+            Sin(a, b) => write!(fmt, "x{} := sin x{}", a, b),
+            #[cfg(feature = "transcendentals")]
+            Cos(a, b) => write!(fmt, "x{} := cos x{}", a, b),
+            #[cfg(feature = "transcendentals")]
+            Tan(a, b) => write!(fmt, "x{} := tan x{}", a, b),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone)]
 pub enum Val {
     B(bool),
     I(i64),
     F(f64),
     T(Vec<Val>),
-    C(FnId),
+    /// A function, optionally with the number of arguments it takes. Calls
+    /// to a function with a known arity check the argument count.
+    C(FnId, Option<u8>),
+    /// A function along with the values it captured when it was created.
+    Closure { fn_id: FnId, upvals: Box<[Val]> },
+    /// A mutable heap cell. Copies of a reference all point to the same cell.
+    Ref(Arc<Mutex<Val>>),
+    /// A UTF-8 string.
+    S(String),
+    /// An explicitly absent value. Unlike the empty tuple, which a bare
+    /// `return` gives back, nothing produces this unless asked to.
+    Null,
+}
+
+impl Instr {
+    /// The offsets this instruction can jump by, not counting falling through
+    /// to the next instruction.
+    ///
+    /// A `Switch` jumps by offsets stored in registers, so it has none here.
+    pub fn jump_offsets(&self) -> Vec<isize> {
+        use self::Instr::*;
+        match *self {
+            Jump(a) => vec![a as isize],
+            CondJump(_, b, c) => vec![b as isize, c as isize],
+            JumpLong(a) => vec![a as isize],
+            CondJumpLong(_, b, c) => vec![b as isize, c as isize],
+            CmpJump(_, _, lt, eq, gt) => vec![lt as isize, eq as isize, gt as isize],
+            _ => vec![],
+        }
+    }
+
+    /// Returns this instruction with each of its jump offsets replaced by `f(offset)`.
+    pub fn map_jump_offsets<F: FnMut(isize) -> isize>(&self, mut f: F) -> Instr {
+        use self::Instr::*;
+        match *self {
+            Jump(a) => Jump(f(a as isize) as i16),
+            CondJump(a, b, c) => CondJump(a, f(b as isize) as i8, f(c as isize) as i8),
+            JumpLong(a) => JumpLong(f(a as isize) as i32),
+            CondJumpLong(a, b, c) => CondJumpLong(a, f(b as isize) as i32, f(c as isize) as i32),
+            CmpJump(a, b, lt, eq, gt) => CmpJump(
+                a,
+                b,
+                f(lt as isize) as i16,
+                f(eq as isize) as i16,
+                f(gt as isize) as i16,
+            ),
+            ref instr => instr.clone(),
+        }
+    }
+
+    /// Whether this instruction only computes a value from its operands, without
+    /// side effects, jumping, or reading mutable state like the heap or upvalues.
+    ///
+    /// Pure instructions can still fail, such as when dividing by zero.
+    pub fn is_pure(&self) -> bool {
+        use self::Instr::*;
+        #[cfg(feature = "transcendentals")]
+        {
+            if let Sin(..) | Cos(..) | Tan(..) = *self {
+                return true;
+            }
+        }
+        matches!(
+            *self,
+            Const(..) | LoadConst(..) | Copy(..) | Add(..) | AddImm(..) | Sub(..) | Mul(..)
+                | MulImm(..) | WAdd(..) | WSub(..) | WMul(..) | Div(..) | Rem(..) | Pow(..)
+                | Min(..) | Max(..) | And(..) | Orr(..) | Xor(..)
+                | Shl(..) | Shr(..) | Eq(..) | Neq(..) | Lt(..) | Gt(..) | Leq(..) | Geq(..)
+                | FEq(..) | Select(..) | Neg(..) | Not(..) | Abs(..) | Sqrt(..) | Floor(..)
+                | Ceil(..) | Round(..) | CastItoF(..) | CastFtoI(..)
+                | BoolToInt(..) | IntToBool(..) | MkTup(..) | IdxTup(..) | TupSet(..)
+                | TupPush(..) | TupLen(..)
+                | Concat(..) | Slice(..) | MkClosure(..) | StrLen(..) | StrCat(..) | StrIdx(..)
+                | Inspect(..)
+        )
+    }
+
+    /// The number of register operands this instruction has. A contiguous
+    /// range of registers, like the elements of a `MkTup`, counts as one.
+    pub fn arity(&self) -> usize {
+        let mut count = 0;
+        self.map_registers(|a| {
+            count += 1;
+            a
+        });
+        count
+    }
+
+    /// Returns this instruction with each register it names replaced by `f(register)`.
+    ///
+    /// Only the first register of a contiguous range, like the elements of a
+    /// `MkTup`, is passed to `f`.
+    pub fn map_registers<F: FnMut(Addr) -> Addr>(&self, mut f: F) -> Instr {
+        use self::Instr::*;
+        match *self {
+            Const(a, k) => Const(f(a), k),
+            LoadConst(a, k) => LoadConst(f(a), k),
+            Copy(a, b) => Copy(f(a), f(b)),
+            Swap(a, b) => Swap(f(a), f(b)),
+            Add(a, b, c) => Add(f(a), f(b), f(c)),
+            AddImm(a, b, i) => AddImm(f(a), f(b), i),
+            Sub(a, b, c) => Sub(f(a), f(b), f(c)),
+            Mul(a, b, c) => Mul(f(a), f(b), f(c)),
+            MulImm(a, b, i) => MulImm(f(a), f(b), i),
+            WAdd(a, b, c) => WAdd(f(a), f(b), f(c)),
+            WSub(a, b, c) => WSub(f(a), f(b), f(c)),
+            WMul(a, b, c) => WMul(f(a), f(b), f(c)),
+            Div(a, b, c) => Div(f(a), f(b), f(c)),
+            Rem(a, b, c) => Rem(f(a), f(b), f(c)),
+            Pow(a, b, c) => Pow(f(a), f(b), f(c)),
+            Min(a, b, c) => Min(f(a), f(b), f(c)),
+            Max(a, b, c) => Max(f(a), f(b), f(c)),
+            And(a, b, c) => And(f(a), f(b), f(c)),
+            Orr(a, b, c) => Orr(f(a), f(b), f(c)),
+            Xor(a, b, c) => Xor(f(a), f(b), f(c)),
+            Shl(a, b, c) => Shl(f(a), f(b), f(c)),
+            Shr(a, b, c) => Shr(f(a), f(b), f(c)),
+            Eq(a, b, c) => Eq(f(a), f(b), f(c)),
+            Neq(a, b, c) => Neq(f(a), f(b), f(c)),
+            Lt(a, b, c) => Lt(f(a), f(b), f(c)),
+            Gt(a, b, c) => Gt(f(a), f(b), f(c)),
+            Leq(a, b, c) => Leq(f(a), f(b), f(c)),
+            Geq(a, b, c) => Geq(f(a), f(b), f(c)),
+            FEq(a, b, c, d) => FEq(f(a), f(b), f(c), f(d)),
+            Select(a, b, c, d) => Select(f(a), f(b), f(c), f(d)),
+            Neg(a, b) => Neg(f(a), f(b)),
+            Not(a, b) => Not(f(a), f(b)),
+            Abs(a, b) => Abs(f(a), f(b)),
+            Sqrt(a, b) => Sqrt(f(a), f(b)),
+            Floor(a, b) => Floor(f(a), f(b)),
+            Ceil(a, b) => Ceil(f(a), f(b)),
+            Round(a, b) => Round(f(a), f(b)),
+            CastItoF(a, b) => CastItoF(f(a), f(b)),
+            CastFtoI(a, b) => CastFtoI(f(a), f(b)),
+            BoolToInt(a, b) => BoolToInt(f(a), f(b)),
+            IntToBool(a, b) => IntToBool(f(a), f(b)),
+            Jump(a) => Jump(a),
+            CondJump(a, b, c) => CondJump(f(a), b, c),
+            JumpLong(a) => JumpLong(a),
+            CondJumpLong(a, b, c) => CondJumpLong(f(a), b, c),
+            CmpJump(a, b, lt, eq, gt) => CmpJump(f(a), f(b), lt, eq, gt),
+            Switch(a, b, c) => Switch(f(a), f(b), c),
+            MkTup(a, b, c) => MkTup(f(a), f(b), c),
+            UnTup(a, b, c) => UnTup(f(a), b, f(c)),
+            IdxTup(a, b, c) => IdxTup(f(a), f(b), f(c)),
+            TupSet(a, b, c) => TupSet(f(a), f(b), f(c)),
+            TupPush(a, b) => TupPush(f(a), f(b)),
+            TupPop(a, b) => TupPop(f(a), f(b)),
+            TupLen(a, b) => TupLen(f(a), f(b)),
+            Concat(a, b, c) => Concat(f(a), f(b), f(c)),
+            Slice(a, b, c, d) => Slice(f(a), f(b), f(c), f(d)),
+            Call(a, b, c) => Call(f(a), f(b), f(c)),
+            MkClosure(a, b, c) => MkClosure(f(a), f(b), f(c)),
+            GetUpval(a, i) => GetUpval(f(a), i),
+            SetUpval(i, a) => SetUpval(i, f(a)),
+            Alloc(a) => Alloc(f(a)),
+            Load(a, b) => Load(f(a), f(b)),
+            Store(a, b) => Store(f(a), f(b)),
+            CallNative(a, id, c) => CallNative(f(a), id, f(c)),
+            Return(a) => Return(a.map(f)),
+            Read(a) => Read(f(a)),
+            Write(a) => Write(f(a)),
+            ReadN(a, b) => ReadN(f(a), f(b)),
+            WriteN(a) => WriteN(f(a)),
+            StrLen(a, b) => StrLen(f(a), f(b)),
+            StrCat(a, b, c) => StrCat(f(a), f(b), f(c)),
+            StrIdx(a, b, c) => StrIdx(f(a), f(b), f(c)),
+            ReadStr(a) => ReadStr(f(a)),
+            WriteStr(a) => WriteStr(f(a)),
+            Assert(a) => Assert(f(a)),
+            Nop => Nop,
+            DebugPrint(a) => DebugPrint(f(a)),
+            Inspect(a, b) => Inspect(f(a), f(b)),
+            #[cfg(feature = "transcendentals")]
+            Sin(a, b) => Sin(f(a), f(b)),
+            #[cfg(feature = "transcendentals")]
+            Cos(a, b) => Cos(f(a), f(b)),
+            #[cfg(feature = "transcendentals")]
+            Tan(a, b) => Tan(f(a), f(b)),
+        }
+    }
+}
+
+impl PartialEq for Val {
+    fn eq(&self, other: &Val) -> bool {
+        use self::Val::*;
+        match (self, other) {
+            (&B(b), &B(c)) => b == c,
+            (&I(b), &I(c)) => b == c,
+            // NaN equals itself, to agree with `Ord`
+            (&F(b), &F(c)) => b == c || (b.is_nan() && c.is_nan()),
+            (T(b), T(c)) => b == c,
+            (&C(b, b_arity), &C(c, c_arity)) => b == c && b_arity == c_arity,
+            (
+                &Closure {
+                    fn_id: b,
+                    upvals: ref b_upvals,
+                },
+                &Closure {
+                    fn_id: c,
+                    upvals: ref c_upvals,
+                },
+            ) => b == c && b_upvals == c_upvals,
+            // References are only equal if they point to the same cell
+            (Ref(b), Ref(c)) => Arc::ptr_eq(b, c),
+            (S(b), S(c)) => b == c,
+            (&Null, &Null) => true,
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for Val {
@@ -129,6 +541,9 @@ impl fmt::Display for Val {
         match *self {
             B(b) => write!(fmt, "{}", b),
             I(i) => write!(fmt, "{}", i),
+            F(f) if f.is_nan() => write!(fmt, "nan"),
+            F(f) if f == f64::INFINITY => write!(fmt, "inf"),
+            F(f) if f == f64::NEG_INFINITY => write!(fmt, "-inf"),
             F(f) => {
                 let text = format!("{}", f);
                 if text.contains('.') {
@@ -145,23 +560,331 @@ impl fmt::Display for Val {
                     .collect::<Vec<_>>()
                     .join(", ")
             ),
-            C(c) => write!(fmt, "f{}", c),
+            C(c, None) => write!(fmt, "f{}", c),
+            C(c, Some(arity)) => write!(fmt, "f{}/{}", c, arity),
+            Closure { fn_id, ref upvals } => write!(
+                fmt,
+                "f{}{{{}}}",
+                fn_id,
+                upvals
+                    .iter()
+                    .map(|x| format!("{}", x))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Ref(ref r) => match r.lock() {
+                Ok(val) => write!(fmt, "ref {}", *val),
+                Err(poisoned) => write!(fmt, "ref {}", *poisoned.into_inner()),
+            },
+            S(ref s) => {
+                // Sticks to the escapes `Buffer::quoted_string` reads back
+                fmt.write_str("\"")?;
+                for c in s.chars() {
+                    match c {
+                        '\\' => fmt.write_str("\\\\")?,
+                        '"' => fmt.write_str("\\\"")?,
+                        '\n' => fmt.write_str("\\n")?,
+                        '\t' => fmt.write_str("\\t")?,
+                        '\r' => fmt.write_str("\\r")?,
+                        '\0' => fmt.write_str("\\0")?,
+                        c if c.is_ascii_control() => write!(fmt, "\\x{:02x}", c as u32)?,
+                        c => write!(fmt, "{}", c)?,
+                    }
+                }
+                fmt.write_str("\"")
+            }
+            Null => write!(fmt, "null"),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+impl Val {
+    /// A number identifying which variant a value is, in declaration order:
+    /// 0 for `B`, 1 for `I`, 2 for `F`, 3 for `T`, 4 for `C`, 5 for `Closure`,
+    /// 6 for `Ref`, 7 for `S`, and 8 for `Null`.
+    pub fn type_tag(&self) -> i64 {
+        use self::Val::*;
+        match *self {
+            B(_) => 0,
+            I(_) => 1,
+            F(_) => 2,
+            T(_) => 3,
+            C(..) => 4,
+            Closure { .. } => 5,
+            Ref(_) => 6,
+            S(_) => 7,
+            Null => 8,
+        }
+    }
+
+    /// Shows the value with its type spelled out, for debugging output.
+    ///
+    /// Tuples and closures also show how many values they hold, as in
+    /// `tuple[2](int(1), bool(false))`.
+    pub fn fmt_debug_repr(&self) -> String {
+        use self::Val::*;
+        let join = |vals: &[Val]| {
+            vals.iter()
+                .map(Val::fmt_debug_repr)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match *self {
+            B(b) => format!("bool({})", b),
+            I(i) => format!("int({})", i),
+            F(_) => format!("float({})", self),
+            T(ref t) => format!("tuple[{}]({})", t.len(), join(t)),
+            C(id, None) => format!("fn({})", id),
+            C(id, Some(arity)) => format!("fn({}/{})", id, arity),
+            Closure { fn_id, ref upvals } if upvals.is_empty() => {
+                format!("closure[0](fn({}))", fn_id)
+            }
+            Closure { fn_id, ref upvals } => {
+                format!("closure[{}](fn({}); {})", upvals.len(), fn_id, join(upvals))
+            }
+            Ref(ref r) => match r.lock() {
+                Ok(val) => format!("ref({})", val.fmt_debug_repr()),
+                Err(poisoned) => format!("ref({})", poisoned.into_inner().fmt_debug_repr()),
+            },
+            S(ref s) => format!("str[{}]({:?})", s.len(), s),
+            Null => "null".to_string(),
+        }
+    }
+
+    /// Shows the value the way the text format writes constants, so
+    /// `parse_val` reads it back. This is the same as `Display`.
+    pub fn fmt_display_repr(&self) -> String {
+        self.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Defn {
     consts: Vec<Val>,
     code: Vec<Instr>,
     local_count: AddrSize,
 }
 
+impl Defn {
+    /// The number of local slots allocated when this function is called.
+    pub fn local_count(&self) -> AddrSize {
+        self.local_count
+    }
+
+    /// The constant pool, indexed by `Const` instructions.
+    pub fn consts(&self) -> &[Val] {
+        &self.consts
+    }
+
+    /// The instructions making up the body of this function.
+    pub fn code(&self) -> &[Instr] {
+        &self.code
+    }
+
+    /// Mutable access to the body of this function, for use by optimization passes.
+    pub fn code_mut(&mut self) -> &mut Vec<Instr> {
+        &mut self.code
+    }
+
+    /// Computes the fewest locals this function can have, one past the highest
+    /// register any instruction uses.
+    ///
+    /// Returns it as `Ok` if `local_count` is at least that, or as `Err` if the
+    /// function can access a register out of bounds when it runs.
+    pub fn verify_local_count(&self) -> Result<usize, usize> {
+        let needed = self.code
+            .iter()
+            .flat_map(|instr| {
+                let (uses, defs) = liveness::uses_and_defs(instr);
+                uses.into_iter().chain(defs)
+            })
+            .map(|a| a + 1)
+            .max()
+            .unwrap_or(0);
+        if needed <= self.local_count as usize {
+            Ok(needed)
+        } else {
+            Err(needed)
+        }
+    }
+
+    /// Returns the sorted indices of every instruction that some jump can land on.
+    ///
+    /// The targets of a `Switch` depend on registers, so they aren't included.
+    pub fn jump_targets(&self) -> Vec<usize> {
+        let mut targets = Vec::new();
+        for (iptr, instr) in self.code.iter().enumerate() {
+            for off in instr.jump_offsets() {
+                let target = iptr as isize + off;
+                if target >= 0 {
+                    targets.push(target as usize);
+                }
+            }
+        }
+        targets.sort();
+        targets.dedup();
+        targets
+    }
+
+    /// Removes every `Nop`, adjusting jump offsets to match.
+    ///
+    /// Functions containing a `Switch` are left alone, since its targets
+    /// aren't known until it runs.
+    pub fn compact_nops(&mut self) {
+        use self::Instr::*;
+
+        if self.code.iter().any(|instr| matches!(instr, Switch(..))) {
+            return;
+        }
+        // new_pos[i] is where the instruction at i ends up, or where the next
+        // kept instruction ends up if i is a `Nop`.
+        let mut new_pos = Vec::with_capacity(self.code.len() + 1);
+        let mut kept = 0;
+        for instr in &self.code {
+            new_pos.push(kept as isize);
+            if *instr != Nop {
+                kept += 1;
+            }
+        }
+        new_pos.push(kept as isize);
+
+        let code = self.code
+            .iter()
+            .enumerate()
+            .filter(|&(_, instr)| *instr != Nop)
+            .map(|(iptr, instr)| {
+                instr.map_jump_offsets(|off| {
+                    let target = iptr as isize + off;
+                    if 0 <= target && target < new_pos.len() as isize {
+                        new_pos[target as usize] - new_pos[iptr]
+                    } else {
+                        off
+                    }
+                })
+            })
+            .collect();
+        self.code = code;
+    }
+
+    /// Returns a copy of this function with its registers renamed according to
+    /// `mapping`. Registers missing from `mapping` keep their address.
+    ///
+    /// Returns `None` if the registers of a `MkTup` or `UnTup` range wouldn't
+    /// be consecutive anymore.
+    pub fn rename_registers(&self, mapping: &HashMap<Addr, Addr>) -> Option<Defn> {
+        use self::Instr::*;
+
+        let rename = |a: Addr| *mapping.get(&a).unwrap_or(&a);
+        let contiguous = |start: Addr, count: AddrSize| {
+            (0..count as usize).all(|i| {
+                let a = start as usize + i;
+                a > Addr::MAX as usize || rename(a as Addr) as usize == rename(start) as usize + i
+            })
+        };
+        let ranges_kept = self.code.iter().all(|instr| match *instr {
+            MkTup(_, b, c) => contiguous(b, c),
+            UnTup(a, b, _) => contiguous(a, b),
+            _ => true,
+        });
+        if !ranges_kept {
+            return None;
+        }
+        let local_count = (0..self.local_count as usize)
+            .map(|a| rename(a as Addr) as usize + 1)
+            .max()
+            .unwrap_or(0);
+        Some(Defn {
+            consts: self.consts.clone(),
+            code: self.code
+                .iter()
+                .map(|instr| instr.map_registers(rename))
+                .collect(),
+            local_count: local_count as AddrSize,
+        })
+    }
+
+    /// Splits the function before the instruction at `iptr`.
+    ///
+    /// The first half runs `code[..iptr]` and then returns an empty tuple, and
+    /// the second half runs `code[iptr..]`. Both keep all the constants and
+    /// locals. Jumps across the split aren't adjusted, so fixing them up is
+    /// left to the caller.
+    ///
+    /// Returns `None` if either half would have no instructions of its own.
+    pub fn split_at(&self, iptr: usize) -> Option<(Defn, Defn)> {
+        if iptr == 0 || iptr >= self.code.len() {
+            return None;
+        }
+        let mut first = self.code[..iptr].to_vec();
+        first.push(Instr::Return(None));
+        let half = |code| Defn {
+            consts: self.consts.clone(),
+            code,
+            local_count: self.local_count,
+        };
+        Some((half(first), half(self.code[iptr..].to_vec())))
+    }
+
+    /// Returns a label for each instruction that is the target of a jump.
+    ///
+    /// Labels are named `L0:`, `L1:`, ... in the order they appear in the code.
+    pub fn annotate_jumps(&self) -> Vec<Option<String>> {
+        let mut labels = vec![None; self.code.len()];
+        let len = self.code.len();
+        let targets = self.jump_targets();
+        for (i, &target) in targets.iter().filter(|&&t| t < len).enumerate() {
+            labels[target] = Some(format!("L{}:", i));
+        }
+        labels
+    }
+}
+
 /// A piece of compiled code that's ready to be evaluated.
 #[derive(Debug, PartialEq)]
 pub struct Program {
     defns: Vec<Defn>,
     entry_point: FnId,
+    stack_depth: CachedDepth,
+}
+
+/// `Program::estimate_stack_depth`, worked out the first time the program runs.
+///
+/// It only depends on the code, so it's ignored when comparing programs.
+#[derive(Debug, Default)]
+struct CachedDepth(OnceLock<usize>);
+
+impl PartialEq for CachedDepth {
+    fn eq(&self, _: &CachedDepth) -> bool {
+        true
+    }
+}
+
+impl<'a> IntoIterator for &'a Program {
+    type Item = (FnId, &'a Defn);
+    type IntoIter = Defns<'a>;
+
+    fn into_iter(self) -> Defns<'a> {
+        Defns {
+            iter: self.defns.iter().enumerate(),
+        }
+    }
+}
+
+/// An iterator over the function definitions of a `Program` along with their ids.
+pub struct Defns<'a> {
+    iter: iter::Enumerate<slice::Iter<'a, Defn>>,
+}
+
+impl<'a> Iterator for Defns<'a> {
+    type Item = (FnId, &'a Defn);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|(i, defn)| (i as FnId, defn))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
 }
 
 impl fmt::Display for Program {
@@ -172,7 +895,7 @@ impl fmt::Display for Program {
             }
             let consts = defn.consts
                 .iter()
-                .map(|k| format!(" {}", k))
+                .map(|k| format!(" {}", k.fmt_display_repr()))
                 .collect::<String>();
             write!(fmt, "defn f{} {} :{}", i, defn.local_count, consts)?;
             for line in &defn.code {
@@ -183,249 +906,1361 @@ impl fmt::Display for Program {
     }
 }
 
+/// A problem with a program that can be detected without running it.
+#[derive(Debug, PartialEq)]
+pub enum ValidationIssue {
+    /// The function has more constants than `Const` can address, so the
+    /// remainder must be loaded with `LoadConst`.
+    TooManyConsts { fn_id: FnId, count: usize },
+    /// The function's last instruction doesn't return or jump, so execution
+    /// can run off the end of it.
+    FallsOffEnd { fn_id: FnId },
+    /// The jump at `iptr` leaves the bounds of the function.
+    JumpOutOfBounds { fn_id: FnId, iptr: usize },
+    /// The instruction at `iptr` uses a register past the function's local count.
+    RegisterOutOfBounds { fn_id: FnId, iptr: usize },
+}
+
+/// Why programs couldn't be linked by `Program::link`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    /// The linked program would have more functions than a `FnId` can address.
+    TooManyFunctions { count: usize },
+    /// A constant in function `fn_id` of `module` refers to function `target`,
+    /// which wouldn't exist after linking. Module 0 is the main program and the
+    /// libraries are numbered from 1. Both ids are from before linking.
+    UnresolvedFunction {
+        module: usize,
+        fn_id: FnId,
+        target: FnId,
+    },
+}
+
+/// Returned when converting a `Val` into a Rust type that it doesn't hold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValTypeError {
+    /// The type the conversion needed, named as in `Val::fmt_debug_repr`,
+    /// such as `int` or `tuple`.
+    pub expected: &'static str,
+    /// The value that was converted, given back unchanged.
+    pub found: Val,
+}
+
 /// Represents failures during execution.
 ///
 /// Use it to get access to the cause, backtraces, etc.
 #[derive(Debug, PartialEq)]
-pub struct EvalError {}
+pub struct EvalError {
+    pub kind: EvalErrorKind,
+}
+
+impl From<EvalErrorKind> for EvalError {
+    fn from(kind: EvalErrorKind) -> EvalError {
+        EvalError { kind }
+    }
+}
+
+/// Returned by `Program::eval_steps` when a program runs for too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepLimitExceeded {
+    /// How many instructions ran before the program was stopped.
+    pub steps_taken: u64,
+}
+
+/// Why evaluation stopped early.
+enum Halt {
+    Error(EvalError),
+    StepLimit(StepLimitExceeded),
+}
+
+impl From<EvalError> for Halt {
+    fn from(err: EvalError) -> Halt {
+        Halt::Error(err)
+    }
+}
+
+impl From<EvalErrorKind> for Halt {
+    fn from(kind: EvalErrorKind) -> Halt {
+        Halt::Error(kind.into())
+    }
+}
+
+/// The reason that evaluation failed.
+#[derive(Debug, PartialEq)]
+pub enum EvalErrorKind {
+    /// An instruction was given a value of the wrong type.
+    TypeMismatch,
+    /// Integer arithmetic overflowed or divided by zero.
+    Arithmetic,
+    /// An index was outside the bounds of what it indexes into.
+    OutOfBounds { index: i64, len: usize },
+    /// A byte to be written wasn't in the range 0 to 255.
+    ByteOutOfRange(i64),
+    /// No native function is registered under the given id.
+    UnknownNative(u32),
+    /// A call, or the entry point, names a function the program doesn't have.
+    UnknownFunction(FnId),
+    /// The call stack grew past the limit set in `EvalOptions`.
+    CallDepthExceeded,
+    /// A heap cell was poisoned by a panic while it was being accessed.
+    PoisonedRef,
+    /// Two values couldn't be ordered, because one is NaN or they have
+    /// different types.
+    Unordered,
+    /// A function was called with a different number of arguments than its
+    /// arity.
+    WrongArity { expected: u8, got: usize },
+    /// Execution ran past the end of a function without returning.
+    InstructionPointerOutOfBounds { fn_id: FnId, iptr: usize },
+    /// The jump at `iptr` targets an instruction before the start of the function.
+    JumpOutOfBounds { fn_id: FnId, iptr: usize },
+    /// A string read from the input wasn't valid UTF-8.
+    Utf8Error,
+    /// The input ended before an instruction could read everything it needed.
+    UnexpectedEof,
+    /// Reading input or writing output failed.
+    Io,
+    /// An `Assert` instruction found its register false.
+    AssertionFailed,
+}
+
+/// Options that control how a program is evaluated.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EvalOptions {
+    /// The deepest the call stack may grow before evaluation fails.
+    ///
+    /// `None` means calls may nest without limit.
+    pub max_call_depth: Option<usize>,
+}
+
+/// A function provided by the host that programs can call with `CallNative`.
+pub trait NativeFn: Send + Sync {
+    fn call(&self, args: &Val) -> Result<Val, EvalError>;
+}
+
+impl<F> NativeFn for F
+where
+    F: Fn(&Val) -> Result<Val, EvalError> + Send + Sync,
+{
+    fn call(&self, args: &Val) -> Result<Val, EvalError> {
+        self(args)
+    }
+}
+
+/// Everything a program needs from its environment while being evaluated.
+pub struct EvalContext<'a, R: 'a, W: 'a> {
+    pub input: &'a mut R,
+    pub output: &'a mut W,
+    pub options: EvalOptions,
+    pub natives: HashMap<u32, Box<dyn NativeFn>>,
+}
+
+impl<'a, R: Read, W: Write> EvalContext<'a, R, W> {
+    /// Creates a context with the given I/O buffers and default options.
+    pub fn new(input: &'a mut R, output: &'a mut W) -> Self {
+        EvalContext {
+            input,
+            output,
+            options: EvalOptions::default(),
+            natives: HashMap::new(),
+        }
+    }
+
+    /// Makes `f` callable from programs as native function `id`, replacing
+    /// any function previously registered under that id.
+    pub fn register_native<F: NativeFn + 'static>(&mut self, id: u32, f: F) {
+        self.natives.insert(id, Box::new(f));
+    }
+}
 
 impl Program {
+    /// The function that evaluation starts from.
+    pub fn entry_point(&self) -> FnId {
+        self.entry_point
+    }
+
+    /// All function definitions, indexed by their `FnId`.
+    pub fn defns(&self) -> &[Defn] {
+        &self.defns
+    }
+
+    /// Mutable access to all function definitions.
+    pub fn defns_mut(&mut self) -> &mut [Defn] {
+        // The code may change, so the stack depth has to be estimated again
+        self.stack_depth = CachedDepth::default();
+        &mut self.defns
+    }
+
+    /// Checks the program for problems that can be found statically.
+    ///
+    /// An empty result means no problems were found.
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+        for (fn_id, defn) in self {
+            if defn.consts.len() > Addr::MAX as usize + 1 {
+                issues.push(ValidationIssue::TooManyConsts {
+                    fn_id,
+                    count: defn.consts.len(),
+                });
+            }
+            match defn.code.last() {
+                Some(&Instr::Return(_))
+                | Some(&Instr::Jump(_))
+                | Some(&Instr::CondJump(..))
+                | Some(&Instr::JumpLong(_))
+                | Some(&Instr::CondJumpLong(..))
+                | Some(&Instr::CmpJump(..)) => {}
+                _ => issues.push(ValidationIssue::FallsOffEnd { fn_id }),
+            }
+            for (iptr, instr) in defn.code.iter().enumerate() {
+                let offsets = instr.jump_offsets();
+                let len = defn.code.len() as isize;
+                if offsets.iter().any(|off| !(0..len).contains(&(iptr as isize + off))) {
+                    issues.push(ValidationIssue::JumpOutOfBounds { fn_id, iptr });
+                }
+            }
+            if defn.verify_local_count().is_ok() {
+                continue;
+            }
+            for (iptr, instr) in defn.code.iter().enumerate() {
+                let (uses, defs) = liveness::uses_and_defs(instr);
+                if uses.iter().chain(&defs).any(|&a| a >= defn.local_count as usize) {
+                    issues.push(ValidationIssue::RegisterOutOfBounds { fn_id, iptr });
+                }
+            }
+        }
+        issues
+    }
+
+    /// Computes a 64-bit FNV-1a hash of the program, for detecting corruption
+    /// or changes in a stored program.
+    ///
+    /// This is not a cryptographic hash and won't detect deliberate tampering.
+    pub fn checksum(&self) -> u64 {
+        let mut hash = FNV_OFFSET_BASIS;
+        for byte in self.to_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+        hash
+    }
+
+    /// Builds a map from each function to the functions it can call.
+    ///
+    /// Calls go through registers, so any function referenced from a
+    /// function's constant pool is considered to be callable by it.
+    pub fn call_graph(&self) -> HashMap<FnId, Vec<FnId>> {
+        self.into_iter()
+            .map(|(id, defn)| {
+                let mut callees = Vec::new();
+                for k in &defn.consts {
+                    collect_fn_ids(k, &mut callees);
+                }
+                callees.sort();
+                callees.dedup();
+                (id, callees)
+            })
+            .collect()
+    }
+
+    /// Returns true if `fn_id` can end up calling itself, either directly or
+    /// through other functions.
+    pub fn is_recursive(&self, fn_id: FnId) -> bool {
+        let graph = self.call_graph();
+        let mut seen = HashSet::new();
+        let mut todo = vec![fn_id];
+        while let Some(id) = todo.pop() {
+            for &callee in graph.get(&id).into_iter().flatten() {
+                if callee == fn_id {
+                    return true;
+                }
+                if seen.insert(callee) {
+                    todo.push(callee);
+                }
+            }
+        }
+        false
+    }
+
+    /// Estimates how many call frames evaluating the program can push, by
+    /// following the deepest chain in the call graph from the entry point.
+    ///
+    /// Returns `usize::MAX` if a recursive function is reachable, since the
+    /// depth then depends on the input.
+    pub fn estimate_stack_depth(&self) -> usize {
+        let graph = self.call_graph();
+        let mut depths = HashMap::new();
+        stack_depth(&graph, self.entry_point, &mut depths).unwrap_or(usize::MAX)
+    }
+
+    /// Combines this program with the library modules in `libs` into one program.
+    ///
+    /// Each library's functions are appended in order, so a library's function
+    /// `fN` becomes `f(N + base)`, where `base` counts the functions before it.
+    /// This program's functions keep their ids, so it calls into a library by
+    /// the ids the library's functions will have after linking. Only this
+    /// program's entry point is kept.
+    pub fn link(&self, libs: &[Program]) -> Result<Program, LinkError> {
+        let count = iter::once(self).chain(libs).map(|p| p.defns.len()).sum::<usize>();
+        if count > FnId::MAX as usize + 1 {
+            return Err(LinkError::TooManyFunctions { count });
+        }
+        unresolved_fn_id(self, 0, count)?;
+
+        let mut defns = self.defns.clone();
+        for (i, lib) in libs.iter().enumerate() {
+            unresolved_fn_id(lib, i + 1, lib.defns.len())?;
+            let base = defns.len() as FnId;
+            for defn in &lib.defns {
+                let mut defn = defn.clone();
+                for k in &mut defn.consts {
+                    offset_fn_ids(k, base);
+                }
+                defns.push(defn);
+            }
+        }
+        Ok(Program {
+            defns,
+            entry_point: self.entry_point,
+            stack_depth: Default::default(),
+        })
+    }
+
+    /// Returns a copy of the program where function `fn_id` assumes its
+    /// argument is always `known_arg`, with constants folded through its body.
+    ///
+    /// Returns `None` if there is no function `fn_id`.
+    pub fn specialize(&self, fn_id: FnId, known_arg: Val) -> Option<Program> {
+        let defn = self.defns.get(fn_id as usize)?;
+        let mut defns = self.defns.clone();
+        defns[fn_id as usize] = opt::ConstantFolding::apply(defn, Some(known_arg));
+        Some(Program {
+            defns,
+            entry_point: self.entry_point,
+            stack_depth: Default::default(),
+        })
+    }
+
+    /// Returns a copy of the program with the `Call` at `call_iptr` in function
+    /// `caller` replaced by the body of the function it calls.
+    ///
+    /// The called register must be loaded with a function constant before the
+    /// call on every path to it, so the callee is known without running the code.
+    pub fn inline_function(
+        &self,
+        caller: FnId,
+        call_iptr: usize,
+    ) -> Result<Program, opt::InlineError> {
+        use self::opt::InlineError::*;
+
+        let defn = self.defns.get(caller as usize).ok_or(NoSuchFunction(caller))?;
+        let callee_id = opt::call_target(defn, call_iptr)?;
+        let callee = self.defns
+            .get(callee_id as usize)
+            .ok_or(NoSuchFunction(callee_id))?;
+        if self.is_recursive(callee_id) {
+            return Err(Recursive(callee_id));
+        }
+        let mut defns = self.defns.clone();
+        defns[caller as usize] = opt::inline_call(defn, call_iptr, callee)?;
+        Ok(Program {
+            defns,
+            entry_point: self.entry_point,
+            stack_depth: Default::default(),
+        })
+    }
+
     /// Evaluate a program with given I/O buffers.
     pub fn eval<R: Read, W: Write>(&self, input: &mut R, output: &mut W) -> Result<Val, EvalError> {
+        self.eval_with_context(&mut EvalContext::new(input, output))
+    }
+
+    /// Evaluate the program once for each input in parallel, discarding any output.
+    ///
+    /// Results are returned in the same order as `inputs`.
+    pub fn eval_parallel(&self, inputs: Vec<Vec<u8>>) -> Vec<Result<Val, EvalError>> {
+        inputs
+            .into_par_iter()
+            .map(|input| self.eval(&mut Cursor::new(input), &mut io::sink()))
+            .collect()
+    }
+
+    /// Evaluate a program within the given context.
+    pub fn eval_with_context<R: Read, W: Write>(
+        &self,
+        ctx: &mut EvalContext<R, W>,
+    ) -> Result<Val, EvalError> {
+        match self.run(ctx, Val::I(0), None) {
+            Ok(val) => Ok(val),
+            Err(Halt::Error(err)) => Err(err),
+            Err(Halt::StepLimit(_)) => unreachable!("no step limit was set"),
+        }
+    }
+
+    /// Evaluate a program with given I/O buffers, stopping after `max`
+    /// instructions have run.
+    ///
+    /// The outer result is `Err` if the program was stopped, and the inner
+    /// result is what evaluation returned otherwise.
+    pub fn eval_steps<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        max: u64,
+    ) -> Result<Result<Val, EvalError>, StepLimitExceeded> {
+        match self.run(&mut EvalContext::new(input, output), Val::I(0), Some(max)) {
+            Ok(val) => Ok(Ok(val)),
+            Err(Halt::Error(err)) => Ok(Err(err)),
+            Err(Halt::StepLimit(limit)) => Err(limit),
+        }
+    }
+
+    /// Evaluate a program with given I/O buffers, passing `args` to the entry
+    /// point in `x0` the same way `Call` passes arguments.
+    pub fn eval_with_args<R: Read, W: Write>(
+        &self,
+        args: Val,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<Val, EvalError> {
+        match self.run(&mut EvalContext::new(input, output), args, None) {
+            Ok(val) => Ok(val),
+            Err(Halt::Error(err)) => Err(err),
+            Err(Halt::StepLimit(_)) => unreachable!("no step limit was set"),
+        }
+    }
+
+    fn run<R: Read, W: Write>(
+        &self,
+        ctx: &mut EvalContext<R, W>,
+        args: Val,
+        max_steps: Option<u64>,
+    ) -> Result<Val, Halt> {
+        use self::EvalErrorKind::*;
         use self::Val::*;
         use self::Instr::*;
 
-        let mut stack = Vec::new();
-        let mut code = &self.defns[self.entry_point as usize];
+        let depth = *self.stack_depth.0.get_or_init(|| self.estimate_stack_depth());
+        let mut stack = Vec::with_capacity(cmp::min(depth, MAX_PREALLOCATED_FRAMES));
+        let mut fn_id = self.entry_point;
+        let mut code = self.defns.get(fn_id as usize).ok_or(UnknownFunction(fn_id))?;
         let mut locals = vec![I(0); code.local_count as usize];
+        if let Some(x0) = locals.first_mut() {
+            *x0 = args;
+        }
+        let mut upvals: Box<[Val]> = Box::new([]);
         let mut iptr = 0;
+        let mut steps = 0;
         loop {
-            match code.code.get(iptr).unwrap_or_else(|| &Return(None)) {
+            if let Some(max) = max_steps {
+                if steps == max {
+                    return Err(Halt::StepLimit(StepLimitExceeded { steps_taken: steps }));
+                }
+                steps += 1;
+            }
+            match code.code
+                .get(iptr)
+                .ok_or(InstructionPointerOutOfBounds { fn_id, iptr })?
+            {
                 &Const(a, k) => locals[a as usize] = code.consts[k as usize].clone(),
+                &LoadConst(a, k) => locals[a as usize] = code.consts[k as usize].clone(),
                 &Copy(a, b) => locals[a as usize] = locals[b as usize].clone(),
+                &Swap(a, b) => locals.swap(a as usize, b as usize),
                 &Add(a, b, c) => locals[a as usize] = (&locals[b as usize] + &locals[c as usize])?,
+                &AddImm(a, b, c) => locals[a as usize] = (&locals[b as usize] + &I(c as i64))?,
                 &Sub(a, b, c) => locals[a as usize] = (&locals[b as usize] - &locals[c as usize])?,
                 &Mul(a, b, c) => locals[a as usize] = (&locals[b as usize] * &locals[c as usize])?,
+                &MulImm(a, b, c) => locals[a as usize] = (&locals[b as usize] * &I(c as i64))?,
+                &WAdd(a, b, c) => {
+                    let (b, c) = (&locals[b as usize], &locals[c as usize]);
+                    locals[a as usize] = wrapping(b, c, i64::wrapping_add)?;
+                }
+                &WSub(a, b, c) => {
+                    let (b, c) = (&locals[b as usize], &locals[c as usize]);
+                    locals[a as usize] = wrapping(b, c, i64::wrapping_sub)?;
+                }
+                &WMul(a, b, c) => {
+                    let (b, c) = (&locals[b as usize], &locals[c as usize]);
+                    locals[a as usize] = wrapping(b, c, i64::wrapping_mul)?;
+                }
                 &Div(a, b, c) => locals[a as usize] = (&locals[b as usize] / &locals[c as usize])?,
                 &Rem(a, b, c) => locals[a as usize] = (&locals[b as usize] % &locals[c as usize])?,
+                &Pow(a, b, c) => locals[a as usize] = pow(&locals[b as usize], &locals[c as usize])?,
+                &Min(a, b, c) => {
+                    locals[a as usize] = min_max(&locals[b as usize], &locals[c as usize], false)?;
+                }
+                &Max(a, b, c) => {
+                    locals[a as usize] = min_max(&locals[b as usize], &locals[c as usize], true)?;
+                }
                 &And(a, b, c) => locals[a as usize] = (&locals[b as usize] & &locals[c as usize])?,
                 &Orr(a, b, c) => locals[a as usize] = (&locals[b as usize] | &locals[c as usize])?,
                 &Xor(a, b, c) => locals[a as usize] = (&locals[b as usize] ^ &locals[c as usize])?,
-                &Eq(a, b, c) => locals[a as usize] = B(&locals[b as usize] == &locals[c as usize]),
-                &Neq(a, b, c) => locals[a as usize] = B(&locals[b as usize] != &locals[c as usize]),
-                &Lt(a, b, c) => locals[a as usize] = B(&locals[b as usize] < &locals[c as usize]),
-                &Gt(a, b, c) => locals[a as usize] = B(&locals[b as usize] > &locals[c as usize]),
-                &Leq(a, b, c) => locals[a as usize] = B(&locals[b as usize] <= &locals[c as usize]),
-                &Geq(a, b, c) => locals[a as usize] = B(&locals[b as usize] >= &locals[c as usize]),
+                &Shl(a, b, c) => locals[a as usize] = (&locals[b as usize] << &locals[c as usize])?,
+                &Shr(a, b, c) => locals[a as usize] = (&locals[b as usize] >> &locals[c as usize])?,
+                &Eq(a, b, c) => {
+                    locals[a as usize] = B(equal(&locals[b as usize], &locals[c as usize])?)
+                }
+                &Neq(a, b, c) => {
+                    locals[a as usize] = B(!equal(&locals[b as usize], &locals[c as usize])?)
+                }
+                &Lt(a, b, c) => {
+                    let ord = compare(&locals[b as usize], &locals[c as usize])?;
+                    locals[a as usize] = B(ord.is_some_and(Ordering::is_lt));
+                }
+                &Gt(a, b, c) => {
+                    let ord = compare(&locals[b as usize], &locals[c as usize])?;
+                    locals[a as usize] = B(ord.is_some_and(Ordering::is_gt));
+                }
+                &Leq(a, b, c) => {
+                    let ord = compare(&locals[b as usize], &locals[c as usize])?;
+                    locals[a as usize] = B(ord.is_some_and(Ordering::is_le));
+                }
+                &Geq(a, b, c) => {
+                    let ord = compare(&locals[b as usize], &locals[c as usize])?;
+                    locals[a as usize] = B(ord.is_some_and(Ordering::is_ge));
+                }
+                &Select(a, b, c, d) => {
+                    locals[a as usize] = match locals[b as usize] {
+                        B(true) => locals[c as usize].clone(),
+                        B(false) => locals[d as usize].clone(),
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                }
+                &FEq(a, b, c, d) => {
+                    locals[a as usize] = match (
+                        &locals[b as usize],
+                        &locals[c as usize],
+                        &locals[d as usize],
+                    ) {
+                        (&F(b), &F(c), &F(eps)) => B((b - c).abs() <= eps),
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                }
                 &Neg(a, b) => locals[a as usize] = (-&locals[b as usize])?,
                 &Not(a, b) => locals[a as usize] = (!&locals[b as usize])?,
+                &Abs(a, b) => locals[a as usize] = abs(&locals[b as usize])?,
+                &Sqrt(a, b) => locals[a as usize] = sqrt(&locals[b as usize])?,
+                &Floor(a, b) => locals[a as usize] = float_fn(&locals[b as usize], f64::floor)?,
+                &Ceil(a, b) => locals[a as usize] = float_fn(&locals[b as usize], f64::ceil)?,
+                &Round(a, b) => locals[a as usize] = float_fn(&locals[b as usize], f64::round)?,
+                &CastItoF(a, b) => locals[a as usize] = int_to_float(&locals[b as usize])?,
+                &CastFtoI(a, b) => locals[a as usize] = float_to_int(&locals[b as usize])?,
+                &BoolToInt(a, b) => locals[a as usize] = bool_to_int(&locals[b as usize])?,
+                &IntToBool(a, b) => locals[a as usize] = int_to_bool(&locals[b as usize])?,
                 &MkTup(a, b, c) => {
-                    locals[a as usize] = T(locals[b as usize..(b + c) as usize].into())
+                    let range = reg_range(locals.len(), b, c)?;
+                    locals[a as usize] = T(locals[range].into())
                 }
                 &UnTup(a, b, c) => {
                     let c = match locals[c as usize] {
                         T(ref c) if c.len() == b as usize => c.clone(),
-                        _ => return Err(EvalError {}),
+                        _ => return Err(TypeMismatch.into()),
                     };
-                    locals[a as usize..(a + b) as usize].clone_from_slice(&c[..])
+                    let range = reg_range(locals.len(), a, b)?;
+                    locals[range].clone_from_slice(&c[..])
                 }
                 &IdxTup(a, t, i) => {
                     locals[a as usize] = match (&locals[t as usize], &locals[i as usize]) {
-                        (&T(ref t), &I(i)) => t[i as usize].clone(),
-                        _ => return Err(EvalError {}),
+                        (T(t), &I(i)) => tup_elem(t, i)?,
+                        (S(s), &I(i)) => str_byte(s, i)?,
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                }
+                &TupSet(a, b, c) => {
+                    let val = locals[c as usize].clone();
+                    let i = match locals[b as usize] {
+                        I(i) => i,
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                    match locals[a as usize] {
+                        T(ref mut t) => {
+                            let i = tup_index(t.len(), i)?;
+                            t[i] = val;
+                        }
+                        _ => return Err(TypeMismatch.into()),
+                    }
+                }
+                &TupPush(a, b) => {
+                    let val = locals[b as usize].clone();
+                    match locals[a as usize] {
+                        T(ref mut t) => t.push(val),
+                        _ => return Err(TypeMismatch.into()),
+                    }
+                }
+                &TupPop(a, b) => {
+                    locals[a as usize] = match locals[b as usize] {
+                        T(ref mut t) => t.pop().ok_or(OutOfBounds { index: -1, len: 0 })?,
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                }
+                &TupLen(a, b) => {
+                    locals[a as usize] = match locals[b as usize] {
+                        T(ref t) => I(t.len() as i64),
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                }
+                &Concat(a, b, c) => {
+                    locals[a as usize] = match (&locals[b as usize], &locals[c as usize]) {
+                        (T(b), T(c)) => T(b.iter().chain(c).cloned().collect()),
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                }
+                &Slice(a, b, c, d) => {
+                    let (b, c, d) = (&locals[b as usize], &locals[c as usize], &locals[d as usize]);
+                    locals[a as usize] = match (b, c, d) {
+                        (T(t), &I(lo), &I(hi)) => T(tup_slice(t, lo, hi)?.to_vec()),
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                }
+                &StrLen(a, b) => {
+                    locals[a as usize] = match locals[b as usize] {
+                        S(ref s) => I(s.len() as i64),
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                }
+                &StrCat(a, b, c) => {
+                    locals[a as usize] = match (&locals[b as usize], &locals[c as usize]) {
+                        (S(b), S(c)) => S(format!("{}{}", b, c)),
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                }
+                &StrIdx(a, b, c) => {
+                    locals[a as usize] = match (&locals[b as usize], &locals[c as usize]) {
+                        (S(s), &I(i)) => str_byte(s, i)?,
+                        _ => return Err(TypeMismatch.into()),
                     };
                 }
                 &Call(a, f, c) => {
-                    let new_code = &self.defns[f as usize];
+                    let (new_fn_id, new_upvals, closure) = match locals[f as usize] {
+                        C(id, arity) => {
+                            if let Some(expected) = arity {
+                                // A non-tuple argument counts as a single argument.
+                                let got = match locals[c as usize] {
+                                    T(ref t) => t.len(),
+                                    _ => 1,
+                                };
+                                if got != expected as usize {
+                                    return Err(WrongArity { expected, got }.into());
+                                }
+                            }
+                            (id, Box::new([]) as Box<[Val]>, None)
+                        }
+                        Closure {
+                            fn_id: id,
+                            ref upvals,
+                        } => (id, upvals.clone(), Some(f)),
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                    let new_code = self.defns
+                        .get(new_fn_id as usize)
+                        .ok_or(UnknownFunction(new_fn_id))?;
+                    if ctx.options.max_call_depth.is_some_and(|max| stack.len() >= max) {
+                        return Err(CallDepthExceeded.into());
+                    }
                     let mut new_locals = vec![I(0); new_code.local_count as usize];
                     new_locals[0] = locals[c as usize].clone();
-                    stack.push((a, code, locals, iptr));
+                    let old_upvals = mem::replace(&mut upvals, new_upvals);
+                    stack.push((a, closure, fn_id, code, locals, iptr, old_upvals));
+                    fn_id = new_fn_id;
                     code = new_code;
                     locals = new_locals;
                     iptr = 0;
                     continue;
                 }
+                &CallNative(a, id, c) => {
+                    let f = ctx.natives.get(&id).ok_or(UnknownNative(id))?;
+                    locals[a as usize] = f.call(&locals[c as usize])?;
+                }
+                &MkClosure(a, f, c) => {
+                    locals[a as usize] = match (&locals[f as usize], &locals[c as usize]) {
+                        (&C(fn_id, _), T(t)) => Closure {
+                            fn_id,
+                            upvals: t.clone().into_boxed_slice(),
+                        },
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                }
+                &GetUpval(a, i) => {
+                    let bounds = OutOfBounds {
+                        index: i as i64,
+                        len: upvals.len(),
+                    };
+                    locals[a as usize] = upvals.get(i as usize).ok_or(bounds)?.clone();
+                }
+                &SetUpval(i, b) => {
+                    let bounds = OutOfBounds {
+                        index: i as i64,
+                        len: upvals.len(),
+                    };
+                    *upvals.get_mut(i as usize).ok_or(bounds)? = locals[b as usize].clone();
+                }
+                &Alloc(a) => {
+                    let val = mem::replace(&mut locals[a as usize], I(0));
+                    locals[a as usize] = Ref(Arc::new(Mutex::new(val)));
+                }
+                &Load(a, b) => {
+                    locals[a as usize] = match locals[b as usize] {
+                        Ref(ref r) => r.lock().map_err(|_| PoisonedRef)?.clone(),
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                }
+                &Store(a, b) => match locals[a as usize] {
+                    Ref(ref r) => *r.lock().map_err(|_| PoisonedRef)? = locals[b as usize].clone(),
+                    _ => return Err(TypeMismatch.into()),
+                },
                 &Return(a) => {
                     let res = match a {
-                        Some(a) => locals.remove(a as usize),
+                        Some(a) if (a as usize) < locals.len() => locals.remove(a as usize),
+                        Some(a) => {
+                            return Err(OutOfBounds {
+                                index: a as i64,
+                                len: locals.len(),
+                            }.into())
+                        }
                         None => T(Vec::new()),
                     };
 
-                    if let Some((addr, new_code, mut new_locals, new_iptr)) = stack.pop() {
-                        new_locals[addr as usize] = res;
-                        locals = new_locals;
-                        code = new_code;
-                        iptr = new_iptr;
-                    } else {
-                        return Ok(res);
+                    // The entry point's frame is never pushed, so an empty stack
+                    // means the program itself is returning.
+                    let (addr, closure, new_fn_id, new_code, mut new_locals, new_iptr, new_upvals) =
+                        match stack.pop() {
+                            Some(frame) => frame,
+                            None => {
+                                debug_assert_eq!(fn_id, self.entry_point);
+                                return Ok(res);
+                            }
+                        };
+                    // Upvalues set during the call are kept by the closure that was called
+                    if let Some(f) = closure {
+                        let upvals = mem::replace(&mut upvals, Box::new([]));
+                        new_locals[f as usize] = Closure { fn_id, upvals };
                     }
+                    new_locals[addr as usize] = res;
+                    locals = new_locals;
+                    fn_id = new_fn_id;
+                    code = new_code;
+                    iptr = new_iptr;
+                    upvals = new_upvals;
                 }
                 &Read(a) => {
                     let mut buf = [0];
-                    input.read(&mut buf[..]).map_err(|_| EvalError {})?;
+                    ctx.input.read_exact(&mut buf[..]).map_err(io_error)?;
                     locals[a as usize] = I(buf[0] as i64);
                 }
                 &Write(a) => {
                     match locals[a as usize] {
                         I(x) => {
-                            output.write(&[x as u8]).map_err(|_| EvalError {})?;
+                            ctx.output.write(&[x as u8]).map_err(|_| Io)?;
                         }
-                        _ => return Err(EvalError {}),
+                        _ => return Err(TypeMismatch.into()),
                     };
                 }
+                &ReadN(a, b) => {
+                    let mut buf = match locals[b as usize] {
+                        I(n) if n >= 0 => vec![0; n as usize],
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                    ctx.input.read_exact(&mut buf).map_err(io_error)?;
+                    locals[a as usize] = T(buf.into_iter().map(|x| I(x as i64)).collect());
+                }
+                &ReadStr(a) => {
+                    let mut bytes = Vec::new();
+                    let mut buf = [0];
+                    loop {
+                        ctx.input.read_exact(&mut buf).map_err(io_error)?;
+                        if buf[0] == 0 {
+                            break;
+                        }
+                        bytes.push(buf[0]);
+                    }
+                    locals[a as usize] = S(String::from_utf8(bytes).map_err(|_| Utf8Error)?);
+                }
+                &WriteStr(a) => {
+                    match locals[a as usize] {
+                        S(ref s) => {
+                            ctx.output.write_all(s.as_bytes()).map_err(io_error)?;
+                            ctx.output.write_all(&[0]).map_err(io_error)?;
+                        }
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                }
+                &WriteN(a) => {
+                    let bytes = match locals[a as usize] {
+                        T(ref t) => t.iter().map(to_byte).collect::<Result<Vec<_>, _>>()?,
+                        _ => return Err(TypeMismatch.into()),
+                    };
+                    ctx.output.write_all(&bytes).map_err(io_error)?;
+                }
+                &Assert(a) => match locals[a as usize] {
+                    B(true) => {}
+                    B(false) => return Err(AssertionFailed.into()),
+                    _ => return Err(TypeMismatch.into()),
+                },
+                &Nop => {}
+                &DebugPrint(a) => if cfg!(feature = "debug_instrs") {
+                    eprintln!("DEBUG[{}]: {}", a, locals[a as usize].fmt_debug_repr());
+                },
+                &Inspect(a, b) => locals[a as usize] = I(locals[b as usize].type_tag()),
+                #[cfg(feature = "transcendentals")]
+                &Sin(a, b) => locals[a as usize] = float_fn(&locals[b as usize], f64::sin)?,
+                #[cfg(feature = "transcendentals")]
+                &Cos(a, b) => locals[a as usize] = float_fn(&locals[b as usize], f64::cos)?,
+                #[cfg(feature = "transcendentals")]
+                &Tan(a, b) => locals[a as usize] = float_fn(&locals[b as usize], f64::tan)?,
                 &Jump(a) => {
-                    iptr = sum(iptr, a as isize);
+                    iptr = jump_target(fn_id, iptr, a as isize)?;
                     continue;
                 }
                 &CondJump(a, b, c) => {
                     match locals[a as usize] {
-                        B(true) => iptr = sum(iptr, b as isize),
-                        B(false) => iptr = sum(iptr, c as isize),
-                        _ => return Err(EvalError {}),
+                        B(true) => iptr = jump_target(fn_id, iptr, b as isize)?,
+                        B(false) => iptr = jump_target(fn_id, iptr, c as isize)?,
+                        _ => return Err(TypeMismatch.into()),
                     }
                     continue;
                 }
+                &JumpLong(a) => {
+                    iptr = jump_target(fn_id, iptr, a as isize)?;
+                    continue;
+                }
+                &CondJumpLong(a, b, c) => {
+                    match locals[a as usize] {
+                        B(true) => iptr = jump_target(fn_id, iptr, b as isize)?,
+                        B(false) => iptr = jump_target(fn_id, iptr, c as isize)?,
+                        _ => return Err(TypeMismatch.into()),
+                    }
+                    continue;
+                }
+                &CmpJump(a, b, lt, eq, gt) => {
+                    let off = match compare(&locals[a as usize], &locals[b as usize])? {
+                        Some(Ordering::Less) => lt,
+                        Some(Ordering::Equal) => eq,
+                        Some(Ordering::Greater) => gt,
+                        None => return Err(Unordered.into()),
+                    };
+                    iptr = jump_target(fn_id, iptr, off as isize)?;
+                    continue;
+                }
+                &Switch(a, b, c) => match (&locals[a as usize], &locals[b as usize]) {
+                    (&I(n), &I(base)) => if 0 <= n && n < c as i64 {
+                        let off = base.checked_add(n).ok_or(JumpOutOfBounds { fn_id, iptr })?;
+                        iptr = jump_target(fn_id, iptr, off as isize)?;
+                        continue;
+                    },
+                    _ => return Err(TypeMismatch.into()),
+                },
             }
             iptr += 1;
         }
     }
 }
 
-fn sum(a: usize, b: isize) -> usize {
-    if b > 0 {
-        a + b as usize
-    } else {
-        a - (b.abs() as usize)
+fn io_error(err: io::Error) -> EvalError {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => EvalErrorKind::UnexpectedEof.into(),
+        _ => EvalErrorKind::Io.into(),
+    }
+}
+
+/// The byte at index `i` of `s`, as an integer.
+fn str_byte(s: &str, i: i64) -> Result<Val, EvalError> {
+    let bounds = EvalErrorKind::OutOfBounds {
+        index: i,
+        len: s.len(),
+    };
+    if i < 0 {
+        return Err(bounds.into());
+    }
+    let byte = s.as_bytes().get(i as usize).ok_or(bounds)?;
+    Ok(Val::I(*byte as i64))
+}
+
+fn tup_slice(t: &[Val], lo: i64, hi: i64) -> Result<&[Val], EvalError> {
+    let bounds = |index| EvalErrorKind::OutOfBounds { index, len: t.len() };
+    if hi < 0 || hi as usize > t.len() {
+        return Err(bounds(hi).into());
+    }
+    if lo < 0 || lo > hi {
+        return Err(bounds(lo).into());
+    }
+    Ok(&t[lo as usize..hi as usize])
+}
+
+/// The `count` registers starting at `start`, checked against the number of locals.
+fn reg_range(len: usize, start: Addr, count: AddrSize) -> Result<Range<usize>, EvalError> {
+    let (start, end) = (start as usize, start as usize + count as usize);
+    if end > len {
+        // Points at the first register past the locals
+        let index = cmp::max(start, len) as i64;
+        return Err(EvalErrorKind::OutOfBounds { index, len }.into());
+    }
+    Ok(start..end)
+}
+
+fn tup_elem(t: &[Val], i: i64) -> Result<Val, EvalError> {
+    Ok(t[tup_index(t.len(), i)?].clone())
+}
+
+/// Checks that `i` indexes a tuple with `len` elements.
+fn tup_index(len: usize, i: i64) -> Result<usize, EvalError> {
+    if i < 0 || i as usize >= len {
+        return Err(EvalErrorKind::OutOfBounds { index: i, len }.into());
+    }
+    Ok(i as usize)
+}
+
+fn to_byte(val: &Val) -> Result<u8, EvalError> {
+    match *val {
+        Val::I(x) if (0..=255).contains(&x) => Ok(x as u8),
+        Val::I(x) => Err(EvalErrorKind::ByteOutOfRange(x).into()),
+        _ => Err(EvalErrorKind::TypeMismatch.into()),
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Upper bound on the call frames `eval` reserves space for up front.
+const MAX_PREALLOCATED_FRAMES: usize = 1024;
+
+/// Depth of the deepest call chain starting at `fn_id`, or `None` if it
+/// contains a cycle. `depths` maps finished functions to their depth and
+/// functions still being visited to `None`.
+fn stack_depth(
+    graph: &HashMap<FnId, Vec<FnId>>,
+    fn_id: FnId,
+    depths: &mut HashMap<FnId, Option<usize>>,
+) -> Option<usize> {
+    if let Some(&depth) = depths.get(&fn_id) {
+        return depth;
+    }
+    depths.insert(fn_id, None);
+    let mut depth = 0;
+    for &callee in graph.get(&fn_id).into_iter().flatten() {
+        depth = cmp::max(depth, stack_depth(graph, callee, depths)? + 1);
+    }
+    depths.insert(fn_id, Some(depth));
+    Some(depth)
+}
+
+fn collect_fn_ids(val: &Val, ids: &mut Vec<FnId>) {
+    match *val {
+        Val::C(id, _) => ids.push(id),
+        Val::Closure {
+            fn_id,
+            ref upvals,
+        } => {
+            ids.push(fn_id);
+            for v in upvals.iter() {
+                collect_fn_ids(v, ids);
+            }
+        }
+        Val::T(ref t) => for v in t {
+            collect_fn_ids(v, ids);
+        },
+        _ => {}
+    }
+}
+
+/// Checks that the function constants of `program` only refer to ids below `limit`.
+fn unresolved_fn_id(program: &Program, module: usize, limit: usize) -> Result<(), LinkError> {
+    for (fn_id, defn) in program {
+        let mut ids = Vec::new();
+        for k in &defn.consts {
+            collect_fn_ids(k, &mut ids);
+        }
+        if let Some(&target) = ids.iter().find(|&&id| id as usize >= limit) {
+            return Err(LinkError::UnresolvedFunction {
+                module,
+                fn_id,
+                target,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Adds `base` to every function id in `val`.
+fn offset_fn_ids(val: &mut Val, base: FnId) {
+    match *val {
+        Val::C(ref mut id, _) => *id += base,
+        Val::Closure {
+            ref mut fn_id,
+            ref mut upvals,
+        } => {
+            *fn_id += base;
+            for v in upvals.iter_mut() {
+                offset_fn_ids(v, base);
+            }
+        }
+        Val::T(ref mut t) => for v in t {
+            offset_fn_ids(v, base);
+        },
+        _ => {}
     }
 }
 
-impl<'a> Add for &'a Val {
+/// Where the jump at `iptr` lands, or an error if it's before the start of the function.
+fn jump_target(fn_id: FnId, iptr: usize, off: isize) -> Result<usize, EvalErrorKind> {
+    iptr.checked_add_signed(off)
+        .ok_or(EvalErrorKind::JumpOutOfBounds { fn_id, iptr })
+}
+
+impl Add for &Val {
     type Output = Result<Val, EvalError>;
     fn add(self, rhs: &Val) -> Self::Output {
+        use self::EvalErrorKind::*;
         use self::Val::*;
         match (self, rhs) {
-            (&I(b), &I(c)) => b.checked_add(c).ok_or(EvalError {}).map(I),
+            (&I(b), &I(c)) => b.checked_add(c).ok_or(Arithmetic.into()).map(I),
             (&F(b), &F(c)) => Ok(F(b + c)),
-            _ => Err(EvalError {}),
+            _ => Err(TypeMismatch.into()),
         }
     }
 }
 
-impl<'a> Sub for &'a Val {
+impl Sub for &Val {
     type Output = Result<Val, EvalError>;
     fn sub(self, rhs: &Val) -> Self::Output {
+        use self::EvalErrorKind::*;
         use self::Val::*;
         match (self, rhs) {
-            (&I(b), &I(c)) => b.checked_sub(c).ok_or(EvalError {}).map(I),
+            (&I(b), &I(c)) => b.checked_sub(c).ok_or(Arithmetic.into()).map(I),
             (&F(b), &F(c)) => Ok(F(b - c)),
-            _ => Err(EvalError {}),
+            _ => Err(TypeMismatch.into()),
         }
     }
 }
 
-impl<'a> Mul for &'a Val {
+impl Mul for &Val {
     type Output = Result<Val, EvalError>;
     fn mul(self, rhs: &Val) -> Self::Output {
+        use self::EvalErrorKind::*;
         use self::Val::*;
         match (self, rhs) {
-            (&I(b), &I(c)) => b.checked_mul(c).ok_or(EvalError {}).map(I),
+            (&I(b), &I(c)) => b.checked_mul(c).ok_or(Arithmetic.into()).map(I),
             (&F(b), &F(c)) => Ok(F(b * c)),
-            _ => Err(EvalError {}),
+            _ => Err(TypeMismatch.into()),
         }
     }
 }
 
-impl<'a> Div for &'a Val {
+impl Div for &Val {
     type Output = Result<Val, EvalError>;
     fn div(self, rhs: &Val) -> Self::Output {
+        use self::EvalErrorKind::*;
         use self::Val::*;
         match (self, rhs) {
-            (&I(b), &I(c)) => b.checked_div(c).ok_or(EvalError {}).map(I),
+            (&I(b), &I(c)) => b.checked_div(c).ok_or(Arithmetic.into()).map(I),
             (&F(b), &F(c)) => Ok(F(b / c)),
-            _ => Err(EvalError {}),
+            _ => Err(TypeMismatch.into()),
+        }
+    }
+}
+
+fn abs(val: &Val) -> Result<Val, EvalError> {
+    match *val {
+        Val::I(x) => x.checked_abs().ok_or(EvalErrorKind::Arithmetic.into()).map(Val::I),
+        Val::F(x) => Ok(Val::F(x.abs())),
+        _ => Err(EvalErrorKind::TypeMismatch.into()),
+    }
+}
+
+fn sqrt(val: &Val) -> Result<Val, EvalError> {
+    match *val {
+        Val::F(x) if x < 0.0 => Err(EvalErrorKind::Arithmetic.into()),
+        ref val => float_fn(val, f64::sqrt),
+    }
+}
+
+fn int_to_float(val: &Val) -> Result<Val, EvalError> {
+    match *val {
+        Val::I(x) => Ok(Val::F(x as f64)),
+        _ => Err(EvalErrorKind::TypeMismatch.into()),
+    }
+}
+
+fn float_to_int(val: &Val) -> Result<Val, EvalError> {
+    // 2^63 is the first float too large for an i64, while -2^63 still fits
+    const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+    match *val {
+        Val::F(x) if (-LIMIT..LIMIT).contains(&x) => Ok(Val::I(x as i64)),
+        Val::F(_) => Err(EvalErrorKind::Arithmetic.into()),
+        _ => Err(EvalErrorKind::TypeMismatch.into()),
+    }
+}
+
+fn bool_to_int(val: &Val) -> Result<Val, EvalError> {
+    match *val {
+        Val::B(x) => Ok(Val::I(x as i64)),
+        _ => Err(EvalErrorKind::TypeMismatch.into()),
+    }
+}
+
+fn int_to_bool(val: &Val) -> Result<Val, EvalError> {
+    match *val {
+        Val::I(x) => Ok(Val::B(x != 0)),
+        _ => Err(EvalErrorKind::TypeMismatch.into()),
+    }
+}
+
+/// Applies the wrapping integer operation `f` to two integers.
+fn wrapping(b: &Val, c: &Val, f: fn(i64, i64) -> i64) -> Result<Val, EvalError> {
+    match (b, c) {
+        (&Val::I(b), &Val::I(c)) => Ok(Val::I(f(b, c))),
+        _ => Err(EvalErrorKind::TypeMismatch.into()),
+    }
+}
+
+/// Raises `base` to the power of `exp`.
+fn pow(base: &Val, exp: &Val) -> Result<Val, EvalError> {
+    use self::EvalErrorKind::*;
+    use self::Val::*;
+    match (base, exp) {
+        (&I(b), &I(e)) if e >= 0 => {
+            // Exponentiation by squaring
+            let (mut result, mut b, mut e) = (1i64, b, e);
+            while e > 0 {
+                if e & 1 == 1 {
+                    result = result.checked_mul(b).ok_or(Arithmetic)?;
+                }
+                e >>= 1;
+                if e > 0 {
+                    b = b.checked_mul(b).ok_or(Arithmetic)?;
+                }
+            }
+            Ok(I(result))
         }
+        (&I(_), &I(_)) => Err(Arithmetic.into()),
+        (&F(b), &F(e)) => Ok(F(b.powf(e))),
+        (&F(b), &I(e)) => Ok(F(b.powf(e as f64))),
+        _ => Err(TypeMismatch.into()),
+    }
+}
+
+/// The smaller of two integers or two floats, or the larger if `max` is set.
+fn min_max(b: &Val, c: &Val, max: bool) -> Result<Val, EvalError> {
+    use self::EvalErrorKind::*;
+    use self::Val::*;
+    match (b, c) {
+        (&I(b), &I(c)) => Ok(I(if max { b.max(c) } else { b.min(c) })),
+        // NaN has no place in the ordering, and `f64::min` would quietly drop it
+        (&F(b), &F(c)) if b.is_nan() || c.is_nan() => Err(Arithmetic.into()),
+        (&F(b), &F(c)) => Ok(F(if max { b.max(c) } else { b.min(c) })),
+        _ => Err(TypeMismatch.into()),
     }
 }
 
-impl<'a> Rem for &'a Val {
+/// Applies `f` to a float.
+fn float_fn(val: &Val, f: fn(f64) -> f64) -> Result<Val, EvalError> {
+    match *val {
+        Val::F(x) => Ok(Val::F(f(x))),
+        _ => Err(EvalErrorKind::TypeMismatch.into()),
+    }
+}
+
+impl Rem for &Val {
     type Output = Result<Val, EvalError>;
     fn rem(self, rhs: &Val) -> Self::Output {
+        use self::EvalErrorKind::*;
         use self::Val::*;
         match (self, rhs) {
-            (&I(b), &I(c)) => b.checked_rem(c).ok_or(EvalError {}).map(I),
-            (&F(b), &F(c)) => Ok(F(b / c)),
-            _ => Err(EvalError {}),
+            (&I(b), &I(c)) => b.checked_rem(c).ok_or(Arithmetic.into()).map(I),
+            // As in IEEE 754, a float remainder by zero is NaN rather than an error
+            (&F(b), &F(c)) => Ok(F(b % c)),
+            _ => Err(TypeMismatch.into()),
         }
     }
 }
 
-impl<'a> BitAnd for &'a Val {
+impl BitAnd for &Val {
     type Output = Result<Val, EvalError>;
     fn bitand(self, rhs: &Val) -> Self::Output {
+        use self::EvalErrorKind::*;
         use self::Val::*;
         match (self, rhs) {
             (&I(b), &I(c)) => Ok(I(b & c)),
             (&B(b), &B(c)) => Ok(B(b && c)),
-            _ => Err(EvalError {}),
+            _ => Err(TypeMismatch.into()),
         }
     }
 }
 
-impl<'a> BitOr for &'a Val {
+impl BitOr for &Val {
     type Output = Result<Val, EvalError>;
     fn bitor(self, rhs: &Val) -> Self::Output {
+        use self::EvalErrorKind::*;
         use self::Val::*;
         match (self, rhs) {
             (&I(b), &I(c)) => Ok(I(b | c)),
             (&B(b), &B(c)) => Ok(B(b || c)),
-            _ => Err(EvalError {}),
+            _ => Err(TypeMismatch.into()),
         }
     }
 }
 
-impl<'a> BitXor for &'a Val {
+impl BitXor for &Val {
     type Output = Result<Val, EvalError>;
     fn bitxor(self, rhs: &Val) -> Self::Output {
+        use self::EvalErrorKind::*;
         use self::Val::*;
         match (self, rhs) {
             (&I(b), &I(c)) => Ok(I(b ^ c)),
-            _ => Err(EvalError {}),
+            (&B(b), &B(c)) => Ok(B(b ^ c)),
+            _ => Err(TypeMismatch.into()),
         }
     }
 }
 
-impl<'a> Neg for &'a Val {
+/// The amount to shift an integer by, which must be less than its width.
+fn shift_amount(val: &Val) -> Result<u32, EvalError> {
+    match *val {
+        Val::I(c) if (0..64).contains(&c) => Ok(c as u32),
+        Val::I(_) => Err(EvalErrorKind::Arithmetic.into()),
+        _ => Err(EvalErrorKind::TypeMismatch.into()),
+    }
+}
+
+impl Shl for &Val {
+    type Output = Result<Val, EvalError>;
+    fn shl(self, rhs: &Val) -> Self::Output {
+        match *self {
+            Val::I(b) => Ok(Val::I(b << shift_amount(rhs)?)),
+            _ => Err(EvalErrorKind::TypeMismatch.into()),
+        }
+    }
+}
+
+impl Shr for &Val {
+    type Output = Result<Val, EvalError>;
+    fn shr(self, rhs: &Val) -> Self::Output {
+        match *self {
+            Val::I(b) => Ok(Val::I(((b as u64) >> shift_amount(rhs)?) as i64)),
+            _ => Err(EvalErrorKind::TypeMismatch.into()),
+        }
+    }
+}
+
+impl Neg for &Val {
     type Output = Result<Val, EvalError>;
     fn neg(self) -> Self::Output {
+        use self::EvalErrorKind::*;
         use self::Val::*;
-        match self {
-            &I(a) => Ok(I(-a)),
-            &F(a) => Ok(F(-a)),
-            _ => Err(EvalError {}),
+        match *self {
+            I(a) => a.checked_neg().ok_or(Arithmetic.into()).map(I),
+            F(a) => Ok(F(-a)),
+            _ => Err(TypeMismatch.into()),
         }
     }
 }
 
-impl<'a> Not for &'a Val {
+impl Not for &Val {
     type Output = Result<Val, EvalError>;
     fn not(self) -> Self::Output {
+        use self::EvalErrorKind::*;
         use self::Val::*;
-        match self {
-            &I(a) => Ok(I(!a)),
-            &B(a) => Ok(B(!a)),
-            _ => Err(EvalError {}),
+        match *self {
+            I(a) => Ok(I(!a)),
+            B(a) => Ok(B(!a)),
+            _ => Err(TypeMismatch.into()),
         }
     }
 }
 
-impl PartialOrd for Val {
-    fn partial_cmp(&self, other: &Val) -> Option<Ordering> {
+impl Eq for Val {}
+
+/// A total order over values, for sorting.
+///
+/// Values of different types are ordered by their `type_tag`. NaN is greater
+/// than every other float, references are ordered by address, and everything
+/// else is ordered by its contents.
+impl Ord for Val {
+    fn cmp(&self, other: &Val) -> Ordering {
         use self::Val::*;
         match (self, other) {
-            (&I(b), &I(c)) => b.partial_cmp(&c),
-            (&F(b), &F(c)) => b.partial_cmp(&c),
-            (&B(b), &B(c)) => b.partial_cmp(&c),
-            _ => None,
+            (&B(b), &B(c)) => b.cmp(&c),
+            (&I(b), &I(c)) => b.cmp(&c),
+            (&F(b), &F(c)) => match (b.is_nan(), c.is_nan()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => b.partial_cmp(&c).expect("neither float is NaN"),
+            },
+            (T(b), T(c)) => b.cmp(c),
+            (&C(b, b_arity), &C(c, c_arity)) => (b, b_arity).cmp(&(c, c_arity)),
+            (
+                &Closure {
+                    fn_id: b,
+                    upvals: ref b_upvals,
+                },
+                &Closure {
+                    fn_id: c,
+                    upvals: ref c_upvals,
+                },
+            ) => (b, b_upvals).cmp(&(c, c_upvals)),
+            (Ref(b), Ref(c)) => Arc::as_ptr(b).cmp(&Arc::as_ptr(c)),
+            (S(b), S(c)) => b.cmp(c),
+            _ => self.type_tag().cmp(&other.type_tag()),
+        }
+    }
+}
+
+impl PartialOrd for Val {
+    fn partial_cmp(&self, other: &Val) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Converts between `Val` and the Rust type each listed variant holds.
+macro_rules! impl_val_conversions {
+    ($($ty:ty => $variant:ident, $name:expr;)*) => {$(
+        impl From<$ty> for Val {
+            fn from(x: $ty) -> Val {
+                Val::$variant(x)
+            }
         }
+
+        impl TryFrom<Val> for $ty {
+            type Error = ValTypeError;
+            fn try_from(val: Val) -> Result<$ty, ValTypeError> {
+                match val {
+                    Val::$variant(x) => Ok(x),
+                    found => Err(ValTypeError { expected: $name, found }),
+                }
+            }
+        }
+    )*};
+}
+
+impl_val_conversions! {
+    bool => B, "bool";
+    i64 => I, "int";
+    f64 => F, "float";
+    Vec<Val> => T, "tuple";
+}
+
+/// Compares two values for the comparison instructions, which only order
+/// numbers, booleans, and strings against values of the same type.
+///
+/// Comparing with `Null` fails instead of being unordered, so a missing value
+/// can't quietly compare false.
+fn compare(b: &Val, c: &Val) -> Result<Option<Ordering>, EvalError> {
+    use self::Val::*;
+    Ok(match (b, c) {
+        (&Null, _) | (_, &Null) => return Err(EvalErrorKind::TypeMismatch.into()),
+        (&I(b), &I(c)) => b.partial_cmp(&c),
+        (&F(b), &F(c)) => b.partial_cmp(&c),
+        (&B(b), &B(c)) => b.partial_cmp(&c),
+        (S(b), S(c)) => b.partial_cmp(c),
+        _ => None,
+    })
+}
+
+/// Whether `b` and `c` are the same value, failing like `compare` if either
+/// is `Null`.
+fn equal(b: &Val, c: &Val) -> Result<bool, EvalError> {
+    match (b, c) {
+        (&Val::Null, _) | (_, &Val::Null) => Err(EvalErrorKind::TypeMismatch.into()),
+        (b, c) => Ok(b == c),
     }
 }
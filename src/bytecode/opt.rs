@@ -0,0 +1,721 @@
+//! Optimization passes over compiled code.
+
+use super::flow::ControlFlowGraph;
+use super::liveness::{self, BitSet};
+use std::cmp::{self, Ordering};
+
+use super::{
+    abs, bool_to_int, compare, equal, float_fn, float_to_int, int_to_bool, int_to_float, min_max,
+    pow, reg_range, sqrt, wrapping, Addr, AddrSize, Defn, FnId, Instr, Program, Val,
+};
+
+/// A pattern over a fixed number of consecutive instructions, along with
+/// what to replace them with.
+pub trait PeepholeRule {
+    /// How many instructions the rule looks at.
+    fn window_size(&self) -> usize;
+
+    /// Returns true if the rule applies to `window`. `live_after` holds the
+    /// registers that are read after the window, which are the only ones a
+    /// replacement needs to preserve.
+    fn matches(&self, window: &[Instr], live_after: &BitSet) -> bool;
+
+    /// The instructions to use instead of a matching `window`.
+    fn replace(&self, window: &[Instr]) -> Vec<Instr>;
+}
+
+/// `Const(a, k); Copy(b, a)` becomes `Const(b, k)` when `a` isn't needed later.
+pub struct ConstCopy;
+
+impl PeepholeRule for ConstCopy {
+    fn window_size(&self) -> usize {
+        2
+    }
+
+    fn matches(&self, window: &[Instr], live_after: &BitSet) -> bool {
+        match (&window[0], &window[1]) {
+            (&Instr::Const(a, _), &Instr::Copy(b, c)) => {
+                a == c && (a == b || !live_after.contains(a as usize))
+            }
+            _ => false,
+        }
+    }
+
+    fn replace(&self, window: &[Instr]) -> Vec<Instr> {
+        match (&window[0], &window[1]) {
+            (&Instr::Const(_, k), &Instr::Copy(b, _)) => vec![Instr::Const(b, k)],
+            _ => unreachable!("replace called on a window that doesn't match"),
+        }
+    }
+}
+
+/// `Copy(a, b); Copy(c, a)` becomes `Copy(c, b)` when `a` isn't needed later.
+pub struct CopyCopy;
+
+impl PeepholeRule for CopyCopy {
+    fn window_size(&self) -> usize {
+        2
+    }
+
+    fn matches(&self, window: &[Instr], live_after: &BitSet) -> bool {
+        match (&window[0], &window[1]) {
+            (&Instr::Copy(a, _), &Instr::Copy(c, d)) => {
+                a == d && (a == c || !live_after.contains(a as usize))
+            }
+            _ => false,
+        }
+    }
+
+    fn replace(&self, window: &[Instr]) -> Vec<Instr> {
+        match (&window[0], &window[1]) {
+            (&Instr::Copy(_, b), &Instr::Copy(c, _)) => vec![Instr::Copy(c, b)],
+            _ => unreachable!("replace called on a window that doesn't match"),
+        }
+    }
+}
+
+/// `Copy(a, b); Return(Some(a))` becomes `Return(Some(b))`.
+pub struct CopyReturn;
+
+impl PeepholeRule for CopyReturn {
+    fn window_size(&self) -> usize {
+        2
+    }
+
+    fn matches(&self, window: &[Instr], _: &BitSet) -> bool {
+        match (&window[0], &window[1]) {
+            (&Instr::Copy(a, _), &Instr::Return(Some(c))) => a == c,
+            _ => false,
+        }
+    }
+
+    fn replace(&self, window: &[Instr]) -> Vec<Instr> {
+        match window[0] {
+            Instr::Copy(_, b) => vec![Instr::Return(Some(b))],
+            _ => unreachable!("replace called on a window that doesn't match"),
+        }
+    }
+}
+
+/// Rewrites short sequences of instructions into shorter equivalent ones.
+pub struct PeepholeOptimizer {
+    pub rules: Vec<Box<dyn PeepholeRule>>,
+}
+
+impl Default for PeepholeOptimizer {
+    fn default() -> Self {
+        PeepholeOptimizer {
+            rules: vec![Box::new(ConstCopy), Box::new(CopyCopy), Box::new(CopyReturn)],
+        }
+    }
+}
+
+impl PeepholeOptimizer {
+    /// Creates an optimizer with the built in rules.
+    pub fn new() -> Self {
+        PeepholeOptimizer::default()
+    }
+
+    /// Optimizes every function in `program`.
+    pub fn optimize_program(&self, program: &mut Program) {
+        for defn in program.defns_mut() {
+            self.optimize(defn);
+        }
+    }
+
+    /// Applies rules to `defn` until none match, returning true if anything
+    /// changed. Jump offsets are adjusted to account for removed instructions.
+    ///
+    /// Functions containing a `Switch` are left alone, since its targets
+    /// aren't known until it runs.
+    pub fn optimize(&self, defn: &mut Defn) -> bool {
+        if defn.code().iter().any(|instr| matches!(instr, Instr::Switch(..))) {
+            return false;
+        }
+        let mut changed = false;
+        while self.apply_once(defn) {
+            changed = true;
+        }
+        changed
+    }
+
+    fn apply_once(&self, defn: &mut Defn) -> bool {
+        let live = liveness::compute(defn);
+        let targets = defn.jump_targets();
+        let len = defn.code().len();
+        let none_live = BitSet::new(defn.local_count() as usize);
+
+        for start in 0..len {
+            for rule in &self.rules {
+                let end = start + rule.window_size();
+                // Don't fold away an instruction that something jumps to
+                if end > len || targets.iter().any(|&t| start < t && t < end) {
+                    continue;
+                }
+                let live_after = live.get(end).unwrap_or(&none_live);
+                let window = &defn.code()[start..end];
+                if rule.matches(window, live_after) {
+                    let replacement = rule.replace(window);
+                    let fits = splice(defn, start, end, replacement);
+                    debug_assert!(fits, "peephole rules only shrink code");
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Hoists loop-invariant computations out of loops.
+///
+/// Invariant instructions anywhere in a loop move into a pre-header inserted
+/// just before the loop's header, which runs once each time the loop is
+/// entered. Instructions that can fail are only moved from the start of the
+/// header, which runs first on every iteration anyway, so hoisting them
+/// doesn't change which errors a program can hit.
+pub struct LoopInvariantCodeMotion;
+
+impl LoopInvariantCodeMotion {
+    /// Returns a copy of `defn` with invariant instructions moved in front of
+    /// their loops, where `cfg` is the control flow graph of `defn`.
+    pub fn apply(defn: &Defn, cfg: &ControlFlowGraph) -> Defn {
+        let mut result = defn.clone();
+        if defn.code().iter().any(|instr| matches!(instr, Instr::Switch(..))) {
+            return result;
+        }
+        // Hoisting moves code around, so the graph is rebuilt after each loop
+        let mut cfg = cfg.clone();
+        while let Some(hoisted) = Self::hoist_one(&result, &cfg) {
+            result = hoisted;
+            cfg = ControlFlowGraph::new(&result);
+        }
+        result
+    }
+
+    /// Hoists out of the first loop with anything to hoist, if there is one.
+    fn hoist_one(defn: &Defn, cfg: &ControlFlowGraph) -> Option<Defn> {
+        let live = liveness::compute(defn);
+        let size = defn.local_count() as usize;
+
+        for l in cfg.loops() {
+            // Falling from the loop into the pre-header would run it every iteration
+            if l.header > 0 && l.blocks.contains(&(l.header - 1)) {
+                continue;
+            }
+            let header = cfg.blocks[l.header];
+            let body = l.blocks
+                .iter()
+                .flat_map(|&b| cfg.blocks[b].start..cfg.blocks[b].end)
+                .collect::<Vec<_>>();
+            let exits = l.blocks
+                .iter()
+                .flat_map(|&b| &cfg.succs[b])
+                .filter(|s| !l.blocks.contains(s))
+                .map(|&s| cfg.blocks[s].start)
+                .collect::<Vec<_>>();
+
+            // How many times each register is written in the loop
+            let mut defs = vec![0; size];
+            for &iptr in &body {
+                for a in liveness::uses_and_defs(&defn.code()[iptr]).1 {
+                    defs[a] += 1;
+                }
+            }
+
+            // Instructions to hoist, in an order that defines operands first
+            let mut hoisted = Vec::new();
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for &iptr in &body {
+                    if hoisted.contains(&iptr) {
+                        continue;
+                    }
+                    let instr = &defn.code()[iptr];
+                    let (uses, defs_here) = liveness::uses_and_defs(instr);
+                    // Nothing else runs between entering the header and this
+                    let leading = header.start <= iptr
+                        && iptr < header.end
+                        && (header.start..iptr).all(|i| hoisted.contains(&i));
+                    let invariant = instr.is_pure()
+                        && (leading || cannot_fail(instr))
+                        && uses.iter().all(|&a| defs[a] == 0)
+                        && defs_here.len() == 1
+                        && defs[defs_here[0]] == 1
+                        && !live[header.start].contains(defs_here[0])
+                        && exits.iter().all(|&e| !live[e].contains(defs_here[0]));
+                    if invariant {
+                        // Once hoisted, later instructions can treat it as defined outside
+                        defs[defs_here[0]] = 0;
+                        hoisted.push(iptr);
+                        changed = true;
+                    }
+                }
+            }
+            if hoisted.is_empty() {
+                continue;
+            }
+            if let Some(result) = insert_pre_header(defn, header.start, &body, &hoisted) {
+                return Some(result);
+            }
+        }
+        None
+    }
+}
+
+/// Whether running an instruction always succeeds, whatever its operands hold.
+fn cannot_fail(instr: &Instr) -> bool {
+    use self::Instr::*;
+    matches!(*instr, Const(..) | LoadConst(..) | Copy(..) | MkTup(..) | Inspect(..))
+}
+
+/// Moves the instructions at `hoisted` out of the loop made of the
+/// instructions at `body`, into a pre-header before the header at `start`.
+///
+/// Jumps into the header from outside the loop go to the pre-header instead.
+/// Returns `None` if some jump became too far for its offset to fit.
+fn insert_pre_header(
+    defn: &Defn,
+    start: usize,
+    body: &[usize],
+    hoisted: &[usize],
+) -> Option<Defn> {
+    let len = defn.code().len();
+    // Where each instruction ends up, with hoisted ones mapping to whatever
+    // followed them
+    let mut new_pos = vec![0; len + 1];
+    let mut next = 0;
+    for (i, pos) in new_pos.iter_mut().enumerate() {
+        if i == start {
+            next += hoisted.len();
+        }
+        *pos = next;
+        if !hoisted.contains(&i) {
+            next += 1;
+        }
+    }
+
+    let mut code = Vec::with_capacity(len);
+    for (iptr, instr) in defn.code().iter().enumerate() {
+        if iptr == start {
+            code.extend(hoisted.iter().map(|&i| defn.code()[i].clone()));
+        }
+        if hoisted.contains(&iptr) {
+            continue;
+        }
+        let outside = !body.contains(&iptr);
+        let fix = |off| {
+            let target = iptr as isize + off;
+            if target == start as isize && outside {
+                new_pos[start] as isize - hoisted.len() as isize - new_pos[iptr] as isize
+            } else if 0 <= target && target <= len as isize {
+                new_pos[target as usize] as isize - new_pos[iptr] as isize
+            } else {
+                off
+            }
+        };
+        let fixed = instr.map_jump_offsets(fix);
+        let intended = instr.jump_offsets().into_iter().map(fix).collect::<Vec<_>>();
+        if fixed.jump_offsets() != intended {
+            return None;
+        }
+        code.push(fixed);
+    }
+    let mut result = defn.clone();
+    result.code = code;
+    Some(result)
+}
+
+/// Replaces computations on values known before running with their results.
+///
+/// Values are tracked within each basic block, except for registers that are
+/// never written, whose values are known everywhere.
+pub struct ConstantFolding;
+
+impl ConstantFolding {
+    /// Returns a copy of `defn` with constants folded, assuming its argument
+    /// in register 0 is `known_arg` if given.
+    ///
+    /// Instructions with one known integer operand become `AddImm` or `MulImm`
+    /// where the operand fits.
+    pub fn apply(defn: &Defn, known_arg: Option<Val>) -> Defn {
+        let mut result = defn.clone();
+        let size = defn.local_count() as usize;
+        let arg = if size > 0 { known_arg } else { None };
+
+        let mut written = vec![false; size];
+        for instr in defn.code() {
+            for a in liveness::uses_and_defs(instr).1 {
+                written[a] = true;
+            }
+        }
+        // Values that hold at the start of every block
+        let mut entry = vec![None; size];
+        if size > 0 && !written[0] {
+            entry[0] = arg.clone();
+        }
+        let leaders = defn.basic_blocks()
+            .iter()
+            .map(|block| block.start)
+            .collect::<Vec<_>>();
+
+        let mut known = entry.clone();
+        if size > 0 && !defn.jump_targets().contains(&0) {
+            known[0] = arg.clone();
+        }
+        for (iptr, instr) in defn.code().iter().enumerate() {
+            if iptr != 0 && leaders.contains(&iptr) {
+                known = entry.clone();
+            }
+            let value = fold_value(instr, &known, defn.consts());
+            let replacement = match (value.clone(), instr) {
+                (_, &Instr::Const(..)) | (_, &Instr::LoadConst(..)) => None,
+                (Some((a, val)), _) => Some(const_instr(a, add_const(&mut result.consts, val))),
+                (None, _) => to_immediate(instr, &known),
+            };
+            for a in liveness::uses_and_defs(instr).1 {
+                known[a] = None;
+            }
+            if let Some((a, val)) = value {
+                known[a as usize] = Some(val);
+            }
+            if let Some(replacement) = replacement {
+                result.code[iptr] = replacement;
+            }
+        }
+
+        // The argument itself is still needed if anything reads it
+        if let Some(arg) = arg {
+            let reads_arg = result
+                .code
+                .iter()
+                .any(|instr| liveness::uses_and_defs(instr).0.contains(&0));
+            if reads_arg {
+                let k = add_const(&mut result.consts, arg);
+                result.code.insert(0, const_instr(0, k));
+            }
+        }
+        result
+    }
+}
+
+/// The value an instruction stores and where, if all its operands are known
+/// and computing it can't fail. Loads from `consts` are always known.
+fn fold_value(instr: &Instr, known: &[Option<Val>], consts: &[Val]) -> Option<(Addr, Val)> {
+    use self::Instr::*;
+    use self::Val::*;
+
+    let get = |a: Addr| known.get(a as usize).and_then(|v| v.clone());
+    let (a, val) = match *instr {
+        Const(a, k) => (a, consts.get(k as usize)?.clone()),
+        LoadConst(a, k) => (a, consts.get(k as usize)?.clone()),
+        Copy(a, b) => (a, get(b)?),
+        Add(a, b, c) => (a, (&get(b)? + &get(c)?).ok()?),
+        AddImm(a, b, c) => (a, (&get(b)? + &I(c as i64)).ok()?),
+        Sub(a, b, c) => (a, (&get(b)? - &get(c)?).ok()?),
+        Mul(a, b, c) => (a, (&get(b)? * &get(c)?).ok()?),
+        MulImm(a, b, c) => (a, (&get(b)? * &I(c as i64)).ok()?),
+        WAdd(a, b, c) => (a, wrapping(&get(b)?, &get(c)?, i64::wrapping_add).ok()?),
+        WSub(a, b, c) => (a, wrapping(&get(b)?, &get(c)?, i64::wrapping_sub).ok()?),
+        WMul(a, b, c) => (a, wrapping(&get(b)?, &get(c)?, i64::wrapping_mul).ok()?),
+        Div(a, b, c) => (a, (&get(b)? / &get(c)?).ok()?),
+        Rem(a, b, c) => (a, (&get(b)? % &get(c)?).ok()?),
+        Pow(a, b, c) => (a, pow(&get(b)?, &get(c)?).ok()?),
+        Min(a, b, c) => (a, min_max(&get(b)?, &get(c)?, false).ok()?),
+        Max(a, b, c) => (a, min_max(&get(b)?, &get(c)?, true).ok()?),
+        And(a, b, c) => (a, (&get(b)? & &get(c)?).ok()?),
+        Orr(a, b, c) => (a, (&get(b)? | &get(c)?).ok()?),
+        Xor(a, b, c) => (a, (&get(b)? ^ &get(c)?).ok()?),
+        Shl(a, b, c) => (a, (&get(b)? << &get(c)?).ok()?),
+        Shr(a, b, c) => (a, (&get(b)? >> &get(c)?).ok()?),
+        Eq(a, b, c) => (a, B(equal(&get(b)?, &get(c)?).ok()?)),
+        Neq(a, b, c) => (a, B(!equal(&get(b)?, &get(c)?).ok()?)),
+        Lt(a, b, c) => (a, B(compare(&get(b)?, &get(c)?).ok()?.is_some_and(Ordering::is_lt))),
+        Gt(a, b, c) => (a, B(compare(&get(b)?, &get(c)?).ok()?.is_some_and(Ordering::is_gt))),
+        Leq(a, b, c) => (a, B(compare(&get(b)?, &get(c)?).ok()?.is_some_and(Ordering::is_le))),
+        Geq(a, b, c) => (a, B(compare(&get(b)?, &get(c)?).ok()?.is_some_and(Ordering::is_ge))),
+        Neg(a, b) => (a, (-&get(b)?).ok()?),
+        Not(a, b) => (a, (!&get(b)?).ok()?),
+        Abs(a, b) => (a, abs(&get(b)?).ok()?),
+        Sqrt(a, b) => (a, sqrt(&get(b)?).ok()?),
+        Floor(a, b) => (a, float_fn(&get(b)?, f64::floor).ok()?),
+        Ceil(a, b) => (a, float_fn(&get(b)?, f64::ceil).ok()?),
+        Round(a, b) => (a, float_fn(&get(b)?, f64::round).ok()?),
+        Select(a, b, c, d) => match get(b)? {
+            B(true) => (a, get(c)?),
+            B(false) => (a, get(d)?),
+            _ => return None,
+        },
+        CastItoF(a, b) => (a, int_to_float(&get(b)?).ok()?),
+        CastFtoI(a, b) => (a, float_to_int(&get(b)?).ok()?),
+        BoolToInt(a, b) => (a, bool_to_int(&get(b)?).ok()?),
+        IntToBool(a, b) => (a, int_to_bool(&get(b)?).ok()?),
+        Inspect(a, b) => (a, I(get(b)?.type_tag())),
+        #[cfg(feature = "transcendentals")]
+        Sin(a, b) => (a, float_fn(&get(b)?, f64::sin).ok()?),
+        #[cfg(feature = "transcendentals")]
+        Cos(a, b) => (a, float_fn(&get(b)?, f64::cos).ok()?),
+        #[cfg(feature = "transcendentals")]
+        Tan(a, b) => (a, float_fn(&get(b)?, f64::tan).ok()?),
+        MkTup(a, b, c) => {
+            let range = reg_range(known.len(), b, c).ok()?;
+            (a, T(known[range].iter().cloned().collect::<Option<Vec<_>>>()?))
+        }
+        _ => return None,
+    };
+    // Heap cells can't be shared through the constant pool
+    match val {
+        Ref(_) => None,
+        val => Some((a, val)),
+    }
+}
+
+/// Rewrites an instruction with one known small integer operand to take it
+/// as an immediate instead.
+fn to_immediate(instr: &Instr, known: &[Option<Val>]) -> Option<Instr> {
+    use self::Instr::*;
+
+    let int = |a: Addr| match known.get(a as usize) {
+        Some(&Some(Val::I(i))) => Some(i),
+        _ => None,
+    };
+    let add_imm = |a, b, i: i64| {
+        if i8::MIN as i64 <= i && i <= i8::MAX as i64 {
+            Some(AddImm(a, b, i as i8))
+        } else {
+            None
+        }
+    };
+    let mul_imm = |a, b, i: i64| {
+        if 0 <= i && i <= u8::MAX as i64 {
+            Some(MulImm(a, b, i as u8))
+        } else {
+            None
+        }
+    };
+    match *instr {
+        Add(a, b, c) => match (int(b), int(c)) {
+            (_, Some(i)) => add_imm(a, b, i),
+            (Some(i), _) => add_imm(a, c, i),
+            _ => None,
+        },
+        Sub(a, b, c) => int(c)
+            .and_then(|i| i.checked_neg())
+            .and_then(|i| add_imm(a, b, i)),
+        Mul(a, b, c) => match (int(b), int(c)) {
+            (_, Some(i)) => mul_imm(a, b, i),
+            (Some(i), _) => mul_imm(a, c, i),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Adds `val` to the constant pool unless it's already there, returning its index.
+fn add_const(consts: &mut Vec<Val>, val: Val) -> usize {
+    match consts.iter().position(|k| *k == val) {
+        Some(k) => k,
+        None => {
+            consts.push(val);
+            consts.len() - 1
+        }
+    }
+}
+
+/// Loads constant `k` into `a`, using `LoadConst` if `Const` can't address it.
+fn const_instr(a: Addr, k: usize) -> Instr {
+    if k <= Addr::MAX as usize {
+        Instr::Const(a, k as Addr)
+    } else {
+        Instr::LoadConst(a, k as u16)
+    }
+}
+
+/// Why a call couldn't be inlined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InlineError {
+    /// There is no function with the given id.
+    NoSuchFunction(FnId),
+    /// The instruction to inline isn't a `Call`.
+    NotACall,
+    /// The register being called isn't known to hold a function constant.
+    UnknownCallee,
+    /// The callee can end up calling itself, so inlining could go on forever.
+    Recursive(FnId),
+    /// The callee checks its arity, uses upvalues, or contains a `Switch`.
+    Unsupported,
+    /// The caller would need more locals than a function can have.
+    TooManyLocals,
+    /// The caller would need more constants than `LoadConst` can address.
+    TooManyConsts,
+    /// A jump would become too far for its offset to fit.
+    JumpTooFar,
+}
+
+/// The function called by the `Call` at `iptr`.
+pub(super) fn call_target(defn: &Defn, iptr: usize) -> Result<FnId, InlineError> {
+    let f = match defn.code().get(iptr) {
+        Some(&Instr::Call(_, f, _)) => f,
+        _ => return Err(InlineError::NotACall),
+    };
+    let writes_f = |instr: &Instr| liveness::uses_and_defs(instr).1.contains(&(f as usize));
+    let cfg = ControlFlowGraph::new(defn);
+    let block = cfg.block_of(iptr).expect("iptr is in bounds");
+
+    // The last write to the register before the call decides what it holds
+    let mut last_def = defn.code()[cfg.blocks[block].start..iptr]
+        .iter()
+        .rev()
+        .find(|instr| writes_f(instr));
+    if last_def.is_none() && f != 0 {
+        // Otherwise, the register must be written once in a block that every
+        // path to the call passes through
+        let defs = (0..defn.code().len())
+            .filter(|&i| writes_f(&defn.code()[i]))
+            .collect::<Vec<_>>();
+        if defs.len() == 1 {
+            let def_block = cfg.block_of(defs[0]).expect("defs are in bounds");
+            if def_block != block && cfg.dominators()[block].contains(def_block) {
+                last_def = Some(&defn.code()[defs[0]]);
+            }
+        }
+    }
+    let k = match last_def {
+        Some(&Instr::Const(_, k)) => k as usize,
+        Some(&Instr::LoadConst(_, k)) => k as usize,
+        _ => return Err(InlineError::UnknownCallee),
+    };
+    match defn.consts().get(k) {
+        Some(&Val::C(id, None)) => Ok(id),
+        Some(&Val::C(_, Some(_))) => Err(InlineError::Unsupported),
+        _ => Err(InlineError::UnknownCallee),
+    }
+}
+
+/// Replaces the `Call` at `call_iptr` in `caller` with the body of `callee`.
+///
+/// The callee's registers are moved past the caller's, and its constants
+/// are appended to the caller's. Each `Return` stores into the call's
+/// destination and jumps past the inlined body.
+pub(super) fn inline_call(
+    caller: &Defn,
+    call_iptr: usize,
+    callee: &Defn,
+) -> Result<Defn, InlineError> {
+    use self::Instr::*;
+
+    let (a, c) = match caller.code().get(call_iptr) {
+        Some(&Call(a, _, c)) => (a, c),
+        _ => return Err(InlineError::NotACall),
+    };
+    let unsupported = callee
+        .code()
+        .iter()
+        .any(|instr| matches!(*instr, GetUpval(..) | SetUpval(..) | Switch(..)));
+    if unsupported {
+        return Err(InlineError::Unsupported);
+    }
+
+    // A call always writes the argument to x0, even if the callee has no locals
+    let base = caller.local_count() as usize;
+    let local_count = base + cmp::max(callee.local_count() as usize, 1);
+    if local_count > AddrSize::MAX as usize {
+        return Err(InlineError::TooManyLocals);
+    }
+    let k_base = caller.consts().len();
+    if k_base + callee.consts().len() > u16::MAX as usize + 1 {
+        return Err(InlineError::TooManyConsts);
+    }
+    let shift = |r: Addr| r + base as Addr;
+
+    let len = callee.code().len();
+    let mut body = vec![Copy(shift(0), c)];
+    let mut starts = Vec::with_capacity(len + 1);
+    let mut exits = Vec::new();
+    for (iptr, instr) in callee.code().iter().enumerate() {
+        starts.push(body.len());
+        match *instr {
+            Return(Some(r)) => body.push(Copy(a, shift(r))),
+            Return(None) => body.push(MkTup(a, shift(0), 0)),
+            Const(d, k) => body.push(const_instr(shift(d), k_base + k as usize)),
+            LoadConst(d, k) => body.push(LoadConst(shift(d), (k_base + k as usize) as u16)),
+            ref instr => body.push(instr.map_registers(shift)),
+        }
+        // The last instruction can fall through to the end instead
+        if let Return(_) = *instr {
+            if iptr + 1 < len {
+                exits.push(body.len());
+                body.push(Jump(0));
+            }
+        }
+    }
+    starts.push(body.len());
+
+    let end = body.len() as isize;
+    for (iptr, instr) in callee.code().iter().enumerate() {
+        let pos = starts[iptr] as isize;
+        let fix = |off| {
+            let target = iptr as isize + off;
+            if 0 <= target && target <= len as isize {
+                starts[target as usize] as isize - pos
+            } else {
+                off
+            }
+        };
+        let fixed = body[pos as usize].map_jump_offsets(fix);
+        let intended = instr.jump_offsets().into_iter().map(fix).collect::<Vec<_>>();
+        if fixed.jump_offsets() != intended {
+            return Err(InlineError::JumpTooFar);
+        }
+        body[pos as usize] = fixed;
+    }
+    for exit in exits {
+        let off = end - exit as isize;
+        if off > i16::MAX as isize {
+            return Err(InlineError::JumpTooFar);
+        }
+        body[exit] = Jump(off as i16);
+    }
+
+    let mut result = caller.clone();
+    result.consts.extend(callee.consts().iter().cloned());
+    result.local_count = local_count as AddrSize;
+    if !splice(&mut result, call_iptr, call_iptr + 1, body) {
+        return Err(InlineError::JumpTooFar);
+    }
+    Ok(result)
+}
+
+/// Replaces `code[start..end]` with `replacement`, fixing up jumps across it.
+///
+/// Returns false if some jump became too far for its offset to fit.
+fn splice(defn: &mut Defn, start: usize, end: usize, replacement: Vec<Instr>) -> bool {
+    let removed = (end - start) as isize - replacement.len() as isize;
+    let len = defn.code().len() as isize;
+    let new_pos = |i: isize| {
+        if i <= start as isize {
+            i
+        } else {
+            i - removed
+        }
+    };
+
+    let mut fits = true;
+    for (iptr, instr) in defn.code_mut().iter_mut().enumerate() {
+        let iptr = iptr as isize;
+        let fix = |off| {
+            let target = iptr + off;
+            if 0 <= target && target <= len {
+                new_pos(target) - new_pos(iptr)
+            } else {
+                off
+            }
+        };
+        let fixed = instr.map_jump_offsets(fix);
+        let intended = instr.jump_offsets().into_iter().map(fix).collect::<Vec<_>>();
+        fits &= fixed.jump_offsets() == intended;
+        *instr = fixed;
+    }
+    defn.code_mut().splice(start..end, replacement);
+    fits
+}
@@ -1,4 +1,4 @@
-use super::{Addr, Defn, Program, Val};
+use super::{Addr, AddrSize, Defn, FnId, Instr, Program, SourceMap, Val};
 use parse_util::{Buffer, ParseError, ParseResult};
 
 /// Parses a constant for function declarations.
@@ -6,36 +6,90 @@ use parse_util::{Buffer, ParseError, ParseResult};
 /// Accepted constants:
 ///
 /// - booleans `true` and `false`
+/// - `null`
 /// - integers
-/// - floats (`.` mandatory)
-/// - function numbers (such as `f0`)
+/// - floats (`.` mandatory), or one of `nan`, `inf`, and `-inf`
+/// - function numbers (such as `f0`), optionally with an arity (such as `f0/2`)
+///
+/// Strings can contain whitespace, so they're handled by the callers.
 fn parse_const(text: &str) -> Result<Val, ()> {
     if text == "true" {
         Ok(Val::B(true))
     } else if text == "false" {
         Ok(Val::B(false))
+    } else if text == "null" {
+        Ok(Val::Null)
+    } else if text == "nan" {
+        Ok(Val::F(f64::NAN))
+    } else if text == "inf" {
+        Ok(Val::F(f64::INFINITY))
+    } else if text == "-inf" {
+        Ok(Val::F(f64::NEG_INFINITY))
     } else if text.contains('.') {
         Ok(Val::F(text.parse().map_err(|_| ())?))
-    } else if text.starts_with('f') {
-        Ok(Val::C(text[1..].parse().map_err(|_| ())?))
+    } else if let Some(rest) = text.strip_prefix('f') {
+        let mut parts = rest.splitn(2, '/');
+        let fn_id = parts.next().unwrap_or("").parse().map_err(|_| ())?;
+        let arity = match parts.next() {
+            Some(arity) => Some(arity.parse().map_err(|_| ())?),
+            None => None,
+        };
+        Ok(Val::C(fn_id, arity))
     } else {
         Ok(Val::I(text.parse().map_err(|_| ())?))
     }
 }
 
+/// Parses a single constant, as accepted in function declarations.
+///
+/// Values written with `Display` parse back to themselves.
+pub fn parse_val(text: &str) -> Result<Val, ParseError> {
+    if text.starts_with('"') {
+        let buf = Buffer { text, row: 1, col: 0 };
+        let (buf, s) = buf.quoted_string()?;
+        buf.end()?;
+        return Ok(Val::S(s));
+    }
+    parse_const(text).map_err(|()| ParseError::expected("a constant", 1, 0))
+}
+
 fn parse_constants<'a>(mut buf: Buffer<'a>) -> ParseResult<'a, Vec<Val>> {
     let mut consts = Vec::new();
     while !buf.text.is_empty() {
-        let (new_buf, text) = buf.trim_left().til(char::is_whitespace)?;
+        if buf.trim_left_inline().starts_with("\"") {
+            let (new_buf, s) = buf.trim_left_inline().quoted_string()?;
+            buf = new_buf;
+            consts.push(Val::S(s));
+            continue;
+        }
+        let start = buf.trim_left_inline();
+        let (new_buf, text) = start.til(char::is_whitespace)?;
         buf = new_buf;
         match parse_const(text) {
             Ok(c) => consts.push(c),
-            Err(()) => return Err(buf.expected("a constant")),
+            Err(()) => return Err(start.expected(format!("a constant, got '{}'", text))),
         }
     }
     Ok((buf, consts))
 }
 
+/// Builds `a := op b` for `op` one of `sin`, `cos`, or `tan`.
+#[cfg(feature = "transcendentals")]
+fn transcendental(op: &str, a: Addr, b: Addr) -> Option<Instr> {
+    match op {
+        "sin" => Some(Instr::Sin(a, b)),
+        "cos" => Some(Instr::Cos(a, b)),
+        "tan" => Some(Instr::Tan(a, b)),
+        _ => None,
+    }
+}
+
+/// Always `None`, since these instructions need the `transcendentals` feature.
+#[cfg(not(feature = "transcendentals"))]
+fn transcendental(_op: &str, _a: Addr, _b: Addr) -> Option<Instr> {
+    None
+}
+
 trait ParseExt<'a> {
     fn addr(self, prefix: &str) -> ParseResult<'a, Addr>;
 }
@@ -44,13 +98,19 @@ impl<'a> ParseExt<'a> for Buffer<'a> {
     fn addr(self, prefix: &str) -> ParseResult<'a, Addr> {
         self.trim_left()
             .token(prefix)?
-            .parse_til(|c| !c.is_digit(10))
+            .parse_til(|c| !c.is_ascii_digit())
     }
 }
 
 pub fn parse(text: &str) -> Result<Program, ParseError> {
+    parse_with_source_map(text, "").map(|(program, _)| program)
+}
+
+/// Parses a program, recording which line of `file` each instruction came from.
+pub fn parse_with_source_map(text: &str, file: &str) -> Result<(Program, SourceMap), ParseError> {
     use bytecode::Instr::*;
 
+    let mut source_map = SourceMap::new();
     let mut defns = Vec::new();
     for (row, line) in text.lines().enumerate() {
         let buf = Buffer {
@@ -68,17 +128,32 @@ pub fn parse(text: &str) -> Result<Program, ParseError> {
         // function number, `N` is the number of locals, and each `k` is a
         // constant.
         if buf.starts_with("defn") {
-            let buf = buf.token("defn")?.space()?;
-            let (buf, fn_number): (_, usize) = buf.token("f")?.parse_til(char::is_whitespace)?;
+            let buf = buf.token("defn")?.space_inline()?;
+            let (rest, id) = buf.til_exact(char::is_whitespace)?;
+            let fn_number = id.strip_prefix('f').and_then(|n| n.parse::<usize>().ok());
+            let fn_number = match fn_number {
+                Some(n) => n,
+                None => {
+                    return Err(buf.expected(format!("function id like 'f0', got '{}'", id)))
+                }
+            };
             if fn_number != defns.len() {
                 return Err(buf.expected(format!(
-                    "function id f{}, got f{}",
+                    "function ids must be sequential: expected f{}, got f{}",
                     defns.len(),
                     fn_number
                 )));
             }
-            let (buf, local_count) = buf.trim_left().parse_til(char::is_whitespace)?;
-            let buf = buf.trim_left().token(":")?.trim_left();
+            let buf = rest.trim_left_inline();
+            let (rest, count) = buf.til_exact(char::is_whitespace)?;
+            let local_count = count.parse::<AddrSize>().map_err(|_| {
+                buf.expected(format!(
+                    "local count from 0 to {}, got '{}'",
+                    AddrSize::MAX,
+                    count
+                ))
+            })?;
+            let buf = rest.trim_left_inline().token(":")?.trim_left_inline();
             let (buf, consts) = parse_constants(buf)?;
             buf.end()?;
             defns.push(Defn {
@@ -87,7 +162,10 @@ pub fn parse(text: &str) -> Result<Program, ParseError> {
                 local_count,
             });
         } else {
+            let fn_id = defns.len().saturating_sub(1) as FnId;
             if let Some(ref mut defn) = defns.last_mut() {
+                // Every line in a definition is exactly one instruction
+                source_map.record(fn_id, defn.code.len(), file, buf.row, buf.col + 1);
                 if buf.starts_with("return") {
                     // return OR return x0
                     let buf = buf.token("return")?.space_or_end()?;
@@ -99,31 +177,117 @@ pub fn parse(text: &str) -> Result<Program, ParseError> {
                         buf.end()?;
                         defn.code.push(Return(Some(addr)));
                     }
+                } else if buf.starts_with("writestr") {
+                    // writestr x0
+                    let (buf, addr) = buf.token("writestr")?.space()?.addr("x")?;
+                    buf.end()?;
+                    defn.code.push(WriteStr(addr));
+                } else if buf.starts_with("writen") || buf.starts_with("write_all") {
+                    // writen x0 OR write_all x0
+                    let (buf, _) = buf.first_token_of(&["writen", "write_all"])?;
+                    let (buf, addr) = buf.space()?.addr("x")?;
+                    buf.end()?;
+                    defn.code.push(WriteN(addr));
                 } else if buf.starts_with("write") {
                     // write x0
                     let (buf, addr) = buf.token("write")?.space()?.addr("x")?;
                     buf.end()?;
                     defn.code.push(Write(addr));
+                } else if buf.starts_with("nop") {
+                    // nop
+                    buf.token("nop")?.end()?;
+                    defn.code.push(Nop);
+                } else if buf.starts_with("assert") {
+                    // assert x0
+                    let (buf, addr) = buf.token("assert")?.space()?.addr("x")?;
+                    buf.end()?;
+                    defn.code.push(Assert(addr));
+                } else if buf.starts_with("swap") {
+                    // swap x0 x1
+                    let (buf, a) = buf.token("swap")?.space()?.addr("x")?;
+                    let (buf, b) = buf.space()?.addr("x")?;
+                    buf.end()?;
+                    defn.code.push(Swap(a, b));
+                } else if buf.starts_with("push") {
+                    // push x0 x1
+                    let (buf, a) = buf.token("push")?.space()?.addr("x")?;
+                    let (buf, b) = buf.space()?.addr("x")?;
+                    buf.end()?;
+                    defn.code.push(TupPush(a, b));
+                } else if buf.starts_with("debug") {
+                    // debug x0
+                    let (buf, addr) = buf.token("debug")?.space()?.addr("x")?;
+                    buf.end()?;
+                    defn.code.push(DebugPrint(addr));
+                } else if buf.starts_with("alloc") {
+                    // alloc x0
+                    let (buf, addr) = buf.token("alloc")?.space()?.addr("x")?;
+                    buf.end()?;
+                    defn.code.push(Alloc(addr));
+                } else if buf.starts_with("*") {
+                    // *x0 := x1
+                    let (buf, dest) = buf.token("*")?.addr("x")?;
+                    let (buf, src) = buf.trim_left().token(":=")?.trim_left().addr("x")?;
+                    buf.end()?;
+                    defn.code.push(Store(dest, src));
+                } else if buf.starts_with("upval") {
+                    // upval 0 := x1
+                    let (buf, idx) = buf.token("upval")?
+                        .space()?
+                        .parse_til(|c| !c.is_ascii_digit())?;
+                    let (buf, src) = buf.trim_left().token(":=")?.trim_left().addr("x")?;
+                    buf.end()?;
+                    defn.code.push(SetUpval(idx, src));
+                } else if buf.starts_with("jumpl") {
+                    // jumpl 1000
+                    let (buf, br) = buf.token("jumpl")?
+                        .space()?
+                        .parse_til(|c| !(c.is_ascii_digit() || c == '-'))?;
+                    buf.end()?;
+                    defn.code.push(JumpLong(br));
                 } else if buf.starts_with("jump") {
                     // jump 10
                     let (buf, br) = buf.token("jump")?
                         .space()?
-                        .parse_til(|c| !(c.is_digit(10) || c == '-'))?;
+                        .parse_til(|c| !(c.is_ascii_digit() || c == '-'))?;
                     buf.end()?;
                     defn.code.push(Jump(br));
+                } else if buf.starts_with("condl") {
+                    // condl x0 1000 2000
+                    let (buf, addr) = buf.token("condl")?.space()?.addr("x")?;
+                    let (buf, br1) = buf.space()?.parse_til(|c| !(c.is_ascii_digit() || c == '-'))?;
+                    let (buf, br2) = buf.space()?.parse_til(|c| !(c.is_ascii_digit() || c == '-'))?;
+                    buf.end()?;
+                    defn.code.push(CondJumpLong(addr, br1, br2));
                 } else if buf.starts_with("cond") {
                     // cond x0 10 20
                     let (buf, addr) = buf.token("cond")?.space()?.addr("x")?;
-                    let (buf, br1) = buf.space()?.parse_til(|c| !(c.is_digit(10) || c == '-'))?;
-                    let (buf, br2) = buf.space()?.parse_til(|c| !(c.is_digit(10) || c == '-'))?;
+                    let (buf, br1) = buf.space()?.parse_til(|c| !(c.is_ascii_digit() || c == '-'))?;
+                    let (buf, br2) = buf.space()?.parse_til(|c| !(c.is_ascii_digit() || c == '-'))?;
                     buf.end()?;
                     defn.code.push(CondJump(addr, br1, br2));
+                } else if buf.starts_with("cmp3") {
+                    // cmp3 x0 x1 -5 1 2
+                    let (buf, a) = buf.token("cmp3")?.space()?.addr("x")?;
+                    let (buf, b) = buf.space()?.addr("x")?;
+                    let (buf, lt) = buf.space()?.parse_til(|c| !(c.is_ascii_digit() || c == '-'))?;
+                    let (buf, eq) = buf.space()?.parse_til(|c| !(c.is_ascii_digit() || c == '-'))?;
+                    let (buf, gt) = buf.space()?.parse_til(|c| !(c.is_ascii_digit() || c == '-'))?;
+                    buf.end()?;
+                    defn.code.push(CmpJump(a, b, lt, eq, gt));
+                } else if buf.starts_with("switch") {
+                    // switch x0 x1 4
+                    let (buf, addr) = buf.token("switch")?.space()?.addr("x")?;
+                    let (buf, base) = buf.space()?.addr("x")?;
+                    let (buf, count) = buf.space()?.parse_til(|c| !c.is_ascii_digit())?;
+                    buf.end()?;
+                    defn.code.push(Switch(addr, base, count));
                 } else if buf.starts_with("(") {
                     let (buf, dest) = buf.token("(")?.trim_left().addr("x")?;
                     let (buf, len) = buf.trim_left()
                         .token(";")?
                         .trim_left()
-                        .parse_til(|c| !c.is_digit(10))?;
+                        .parse_til(|c| !c.is_ascii_digit())?;
                     let (buf, src) = buf.trim_left()
                         .token(")")?
                         .trim_left()
@@ -135,33 +299,178 @@ pub fn parse(text: &str) -> Result<Program, ParseError> {
                 } else {
                     // x0 := ...
                     let (buf, dest) = buf.addr("x")?;
+                    if buf.starts_with("[") {
+                        // x0[x1] := x2
+                        let (buf, idx) = buf.token("[")?.addr("x")?;
+                        let (buf, src) = buf.token("]")?
+                            .trim_left()
+                            .token(":=")?
+                            .trim_left()
+                            .addr("x")?;
+                        buf.end()?;
+                        defn.code.push(TupSet(dest, idx, src));
+                        continue;
+                    }
                     let buf = buf.trim_left().token(":=")?.trim_left();
                     if buf.starts_with("k") {
                         // x0 := k1
-                        let (buf, k) = buf.addr("k")?;
+                        // Indices too large for `Const` are loaded with `LoadConst` instead.
+                        let (buf, k): (_, u16) =
+                            buf.token("k")?.parse_til(|c| !c.is_ascii_digit())?;
+                        buf.end()?;
+                        if k as usize <= Addr::MAX as usize {
+                            defn.code.push(Const(dest, k as Addr));
+                        } else {
+                            defn.code.push(LoadConst(dest, k));
+                        }
+                    } else if buf.starts_with("K") {
+                        // x0 := K300
+                        let (buf, k) = buf.token("K")?.parse_til(|c| !c.is_ascii_digit())?;
                         buf.end()?;
-                        defn.code.push(Const(dest, k));
+                        defn.code.push(LoadConst(dest, k));
                     } else if buf.starts_with("(") {
                         // x0 := (x1; #)
                         let (buf, b) = buf.trim_left().token("(")?.addr("x")?;
                         let (buf, c) = buf.trim_left()
                             .token(";")?
                             .trim_left()
-                            .parse_til(|c| !c.is_digit(10))?;
+                            .parse_til(|c| !c.is_ascii_digit())?;
                         buf.trim_left().token(")")?.end()?;
                         defn.code.push(MkTup(dest, b, c));
+                    } else if buf.starts_with("native") {
+                        // x0 := native 1(x1)
+                        let (buf, id) = buf.token("native")?
+                            .space()?
+                            .parse_til(|c| !c.is_ascii_digit())?;
+                        let (buf, c) = buf.trim_left().token("(")?.addr("x")?;
+                        buf.trim_left().token(")")?.end()?;
+                        defn.code.push(CallNative(dest, id, c));
+                    } else if buf.starts_with("closure") {
+                        // x0 := closure x1 x2
+                        let (buf, f) = buf.token("closure")?.space()?.addr("x")?;
+                        let (buf, c) = buf.space()?.addr("x")?;
+                        buf.end()?;
+                        defn.code.push(MkClosure(dest, f, c));
+                    } else if buf.starts_with("upval") {
+                        // x0 := upval 1
+                        let (buf, idx) = buf.token("upval")?
+                            .space()?
+                            .parse_til(|c| !c.is_ascii_digit())?;
+                        buf.end()?;
+                        defn.code.push(GetUpval(dest, idx));
+                    } else if buf.starts_with("len") {
+                        // x0 := len x1
+                        let (buf, b) = buf.token("len")?.space()?.addr("x")?;
+                        buf.end()?;
+                        defn.code.push(TupLen(dest, b));
+                    } else if buf.starts_with("sqrt") || buf.starts_with("floor")
+                        || buf.starts_with("ceil") || buf.starts_with("round")
+                    {
+                        // x0 := sqrt x1, and likewise for floor, ceil, and round
+                        let (buf, op) = buf.first_token_of(&["sqrt", "floor", "ceil", "round"])?;
+                        let (buf, b) = buf.space()?.addr("x")?;
+                        buf.end()?;
+                        defn.code.push(match op {
+                            "sqrt" => Sqrt(dest, b),
+                            "floor" => Floor(dest, b),
+                            "ceil" => Ceil(dest, b),
+                            "round" => Round(dest, b),
+                            _ => unreachable!("invalid float op"),
+                        });
+                    } else if buf.starts_with("pop") {
+                        // x0 := pop x1
+                        let (buf, b) = buf.token("pop")?.space()?.addr("x")?;
+                        buf.end()?;
+                        defn.code.push(TupPop(dest, b));
+                    } else if buf.starts_with("abs") {
+                        // x0 := abs x1
+                        let (buf, b) = buf.token("abs")?.space()?.addr("x")?;
+                        buf.end()?;
+                        defn.code.push(Abs(dest, b));
+                    } else if buf.starts_with("select") {
+                        // x0 := select x1 x2 x3
+                        let (buf, b) = buf.token("select")?.space()?.addr("x")?;
+                        let (buf, c) = buf.space()?.addr("x")?;
+                        let (buf, d) = buf.space()?.addr("x")?;
+                        buf.end()?;
+                        defn.code.push(Select(dest, b, c, d));
+                    } else if buf.starts_with("concat") {
+                        // x0 := concat x1 x2
+                        let (buf, b) = buf.token("concat")?.space()?.addr("x")?;
+                        let (buf, c) = buf.space()?.addr("x")?;
+                        buf.end()?;
+                        defn.code.push(Concat(dest, b, c));
+                    } else if buf.starts_with("min") || buf.starts_with("max") {
+                        // x0 := min x1 x2 OR x0 := max x1 x2
+                        let (buf, op) = buf.first_token_of(&["min", "max"])?;
+                        let (buf, b) = buf.space()?.addr("x")?;
+                        let (buf, c) = buf.space()?.addr("x")?;
+                        buf.end()?;
+                        defn.code.push(if op == "min" { Min(dest, b, c) } else { Max(dest, b, c) });
+                    } else if buf.starts_with("strlen") {
+                        // x0 := strlen x1
+                        let (buf, b) = buf.token("strlen")?.space()?.addr("x")?;
+                        buf.end()?;
+                        defn.code.push(StrLen(dest, b));
+                    } else if buf.starts_with("i2f") || buf.starts_with("f2i")
+                        || buf.starts_with("b2i") || buf.starts_with("i2b")
+                    {
+                        // x0 := i2f x1, and likewise for f2i, b2i, and i2b
+                        let (buf, op) = buf.first_token_of(&["i2f", "f2i", "b2i", "i2b"])?;
+                        let (buf, b) = buf.space()?.addr("x")?;
+                        buf.end()?;
+                        defn.code.push(match op {
+                            "i2f" => CastItoF(dest, b),
+                            "f2i" => CastFtoI(dest, b),
+                            "b2i" => BoolToInt(dest, b),
+                            "i2b" => IntToBool(dest, b),
+                            _ => unreachable!("invalid cast"),
+                        });
+                    } else if buf.starts_with("inspect") || buf.starts_with("typeof") {
+                        // x0 := inspect x1 OR x0 := typeof x1
+                        let (buf, _) = buf.first_token_of(&["inspect", "typeof"])?;
+                        let (buf, b) = buf.space()?.addr("x")?;
+                        buf.end()?;
+                        defn.code.push(Inspect(dest, b));
+                    } else if buf.starts_with("sin") || buf.starts_with("cos")
+                        || buf.starts_with("tan")
+                    {
+                        // x0 := sin x1
+                        let (rest, op) = buf.first_token_of(&["sin", "cos", "tan"])?;
+                        let (rest, b) = rest.space()?.addr("x")?;
+                        rest.end()?;
+                        let instr = transcendental(op, dest, b).ok_or_else(|| {
+                            buf.expected(format!("the `transcendentals` feature for '{}'", op))
+                        })?;
+                        defn.code.push(instr);
+                    } else if buf.starts_with("stridx") {
+                        // x0 := stridx x1 x2
+                        let (buf, b) = buf.token("stridx")?.space()?.addr("x")?;
+                        let (buf, c) = buf.space()?.addr("x")?;
+                        buf.end()?;
+                        defn.code.push(StrIdx(dest, b, c));
+                    } else if buf.starts_with("readstr") {
+                        // x0 := readstr
+                        buf.token("readstr")?.end()?;
+                        defn.code.push(ReadStr(dest));
+                    } else if buf.starts_with("readn") {
+                        // x0 := readn x1
+                        let (buf, b) = buf.token("readn")?.space()?.addr("x")?;
+                        buf.end()?;
+                        defn.code.push(ReadN(dest, b));
                     } else if buf.starts_with("read") {
                         // x0 := read
                         buf.token("read")?.end()?;
                         defn.code.push(Read(dest));
-                    } else if buf.starts_with("!") || buf.starts_with("-") {
+                    } else if buf.starts_with("!") || buf.starts_with("-") || buf.starts_with("*") {
                         // x0 := unop x1
-                        let (buf, op) = buf.first_token_of(&["!", "-"])?;
+                        let (buf, op) = buf.first_token_of(&["!", "-", "*"])?;
                         let (buf, b) = buf.trim_left().addr("x")?;
                         buf.end()?;
                         defn.code.push(match op {
                             "!" => Not(dest, b),
                             "-" => Neg(dest, b),
+                            "*" => Load(dest, b),
                             _ => unreachable!("invalid unary op"),
                         });
                     } else {
@@ -176,13 +485,40 @@ pub fn parse(text: &str) -> Result<Program, ParseError> {
                         }
 
                         let (buf, op) = buf.first_token_of(&[
-                            "+", "-", "*", "/", "%", "&", "|", "^", "==", "!=", "<=", ">=", "<",
-                            ">", "(", "[",
+                            "+#", "*#", "~=", "++", "**", "+%", "-%", "*%", "<<", ">>", "+", "-",
+                            "*", "/", "%", "&", "|", "^", "==", "!=", "<=", ">=", "<", ">", "(",
+                            "[",
                         ])?;
                         match op {
+                            // x0 := x1 +# -5
+                            "+#" => {
+                                let (buf, c) = buf.trim_left()
+                                    .parse_til(|c| !(c.is_ascii_digit() || c == '-'))?;
+                                buf.end()?;
+                                defn.code.push(AddImm(dest, b, c));
+                            }
+                            // x0 := x1 *# 5
+                            "*#" => {
+                                let (buf, c) = buf.trim_left().parse_til(|c| !c.is_ascii_digit())?;
+                                buf.end()?;
+                                defn.code.push(MulImm(dest, b, c));
+                            }
+                            // x0 := x1 ~= x2 x3
+                            "~=" => {
+                                let (buf, c) = buf.addr("x")?;
+                                let (buf, eps) = buf.space()?.addr("x")?;
+                                buf.end()?;
+                                defn.code.push(FEq(dest, b, c, eps));
+                            }
+                            // x0 := x1 ++ x2
+                            "++" => {
+                                let (buf, c) = buf.addr("x")?;
+                                buf.end()?;
+                                defn.code.push(StrCat(dest, b, c));
+                            }
                             // x0 := x1 op x2
-                            "+" | "-" | "*" | "/" | "%" | "&" | "|" | "^" | "==" | "!=" | "<="
-                            | ">=" | "<" | ">" => {
+                            "**" | "+%" | "-%" | "*%" | "+" | "-" | "*" | "/" | "%" | "&" | "|"
+                            | "^" | "<<" | ">>" | "==" | "!=" | "<=" | ">=" | "<" | ">" => {
                                 let (buf, c) = buf.addr("x")?;
                                 buf.end()?;
                                 defn.code.push(match op {
@@ -191,9 +527,15 @@ pub fn parse(text: &str) -> Result<Program, ParseError> {
                                     "*" => Mul(dest, b, c),
                                     "/" => Div(dest, b, c),
                                     "%" => Rem(dest, b, c),
+                                    "**" => Pow(dest, b, c),
+                                    "+%" => WAdd(dest, b, c),
+                                    "-%" => WSub(dest, b, c),
+                                    "*%" => WMul(dest, b, c),
                                     "&" => And(dest, b, c),
                                     "|" => Orr(dest, b, c),
                                     "^" => Xor(dest, b, c),
+                                    "<<" => Shl(dest, b, c),
+                                    ">>" => Shr(dest, b, c),
                                     "==" => Eq(dest, b, c),
                                     "!=" => Neq(dest, b, c),
                                     "<=" => Leq(dest, b, c),
@@ -209,11 +551,18 @@ pub fn parse(text: &str) -> Result<Program, ParseError> {
                                 buf.trim_left().token(")")?.end()?;
                                 defn.code.push(Call(dest, b, c));
                             }
-                            // x0 := x1[x2]
+                            // x0 := x1[x2] OR x0 := x1[x2..x3]
                             "[" => {
                                 let (buf, c) = buf.addr("x")?;
-                                buf.trim_left().token("]")?.end()?;
-                                defn.code.push(IdxTup(dest, b, c));
+                                let buf = buf.trim_left();
+                                if buf.starts_with("..") {
+                                    let (buf, d) = buf.token("..")?.addr("x")?;
+                                    buf.trim_left().token("]")?.end()?;
+                                    defn.code.push(Slice(dest, b, c, d));
+                                } else {
+                                    buf.token("]")?.end()?;
+                                    defn.code.push(IdxTup(dest, b, c));
+                                }
                             }
                             _ => unreachable!("unmentioned op"),
                         }
@@ -224,8 +573,10 @@ pub fn parse(text: &str) -> Result<Program, ParseError> {
             }
         }
     }
-    Ok(Program {
+    let program = Program {
         defns,
         entry_point: 0,
-    })
+        stack_depth: Default::default(),
+    };
+    Ok((program, source_map))
 }
@@ -0,0 +1,48 @@
+use super::FnId;
+
+/// Where the instruction at `iptr` in function `fn_id` came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceEntry {
+    pub fn_id: FnId,
+    pub iptr: usize,
+    pub file: String,
+    pub line: usize,
+    pub col: usize,
+}
+
+/// Maps instructions back to the source positions that produced them.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SourceMap {
+    pub entries: Vec<SourceEntry>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// Records that the instruction at `iptr` in `fn_id` came from the given position.
+    pub fn record<S: Into<String>>(
+        &mut self,
+        fn_id: FnId,
+        iptr: usize,
+        file: S,
+        line: usize,
+        col: usize,
+    ) {
+        self.entries.push(SourceEntry {
+            fn_id,
+            iptr,
+            file: file.into(),
+            line,
+            col,
+        });
+    }
+
+    /// Finds the source position of an instruction, if one was recorded.
+    pub fn lookup(&self, fn_id: FnId, iptr: usize) -> Option<&SourceEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.fn_id == fn_id && entry.iptr == iptr)
+    }
+}
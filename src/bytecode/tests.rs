@@ -17,7 +17,7 @@ macro_rules! test_program {
             use super::*;
 
             #[allow(unused)]
-            fn program() -> Program {
+            pub fn program() -> Program {
                 use self::Val::*;
                 use self::Instr::*;
                 Program {
@@ -28,6 +28,7 @@ macro_rules! test_program {
                             local_count: $count,
                         },)*],
                     entry_point: 0,
+                    stack_depth: Default::default(),
                 }
             }
 
@@ -48,6 +49,11 @@ macro_rules! test_program {
             fn test_parse() {
                 assert_eq!(parse::parse($text), Ok(program()));
             }
+
+            #[test]
+            fn test_display_round_trip() {
+                assert_eq!(parse::parse(&program().to_string()), Ok(program()));
+            }
         }
     }
 }
@@ -133,7 +139,7 @@ return x2
     }
     input: b"";
     output: b"";
-    result: Err(EvalError {});
+    result: Err(EvalErrorKind::TypeMismatch.into());
 }
 
 test_program! {
@@ -155,6 +161,10 @@ x3 := x1 ^ x4
 x4 := k4
 x1 := x3 == x4
 x0 := x0 & x1
+# true ^ true, then false ^ true
+x1 := x0 ^ x0
+x1 := x1 ^ x0
+x0 := x0 & x1
 return x0
 "#;
     defn {
@@ -171,6 +181,9 @@ return x0
             Const(4, 4),
             Eq(1, 3, 4),
             And(0, 0, 1),
+            Xor(1, 0, 0),
+            Xor(1, 1, 0),
+            And(0, 0, 1),
             Return(Some(0)),
         ],
         consts: [I(1), I(2), I(4), I(-1), I(-8)],
@@ -233,6 +246,23 @@ return x0
     result: Ok(I(3));
 }
 
+#[test]
+fn test_jump_targets() {
+    let defn = &test_backwards_jump::program().defns[0];
+    assert_eq!(defn.jump_targets(), vec![1, 3, 5]);
+    assert_eq!(
+        defn.annotate_jumps(),
+        vec![
+            None,
+            Some("L0:".to_string()),
+            None,
+            Some("L1:".to_string()),
+            None,
+            Some("L2:".to_string()),
+        ]
+    );
+}
+
 test_program! {
     name: call_return;
     text: r#"
@@ -261,7 +291,7 @@ return x0
             Call(0, 1, 0),
             Return(Some(0)),
         ],
-        consts: [I(42), I(69), C(1)],
+        consts: [I(42), I(69), C(1, None)],
         local_count: 2,
     }
     defn {
@@ -315,6 +345,295 @@ return x0
     result: Ok(I(15 / (((1 + 2) * (1 + 2)) % 7)));
 }
 
+// The called function is in x2, so calling defns[2] instead of f1 would be wrong
+test_program! {
+    name: call_through_register;
+    text: r#"
+defn f0 3 : f1 5
+x2 := k0
+x0 := k1
+x0 := x2(x0)
+return x0
+
+defn f1 1 :
+x0 := x0 * x0
+return x0
+
+defn f2 1 :
+return x0
+"#;
+    defn {
+        code: [
+            Const(2, 0),
+            Const(0, 1),
+            Call(0, 2, 0),
+            Return(Some(0)),
+        ],
+        consts: [C(1, None), I(5)],
+        local_count: 3,
+    }
+    defn {
+        code: [
+            Mul(0, 0, 0),
+            Return(Some(0)),
+        ],
+        consts: [],
+        local_count: 1,
+    }
+    defn {
+        code: [Return(Some(0))],
+        consts: [],
+        local_count: 1,
+    }
+    input: b"";
+    output: b"";
+    result: Ok(I(25));
+}
+
+test_program! {
+    name: shift;
+    text: r#"
+defn f0 3 : 5 2 -8
+x0 := k0
+x1 := k1
+x0 := x0 << x1
+x2 := k2
+x2 := x2 >> x1
+x0 := (x0; 3)
+return x0
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            Const(1, 1),
+            Shl(0, 0, 1),
+            Const(2, 2),
+            Shr(2, 2, 1),
+            MkTup(0, 0, 3),
+            Return(Some(0)),
+        ],
+        consts: [I(5), I(2), I(-8)],
+        local_count: 3,
+    }
+    input: b"";
+    output: b"";
+    result: Ok(T(vec![I(20), I(2), I((-8i64 as u64 >> 2) as i64)]));
+}
+
+test_program! {
+    name: swap;
+    text: r#"
+defn f0 3 : 1 2
+x0 := k0
+x1 := k1
+swap x0 x1
+swap x1 x1
+x2 := x0 - x1
+return x2
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            Const(1, 1),
+            Swap(0, 1),
+            Swap(1, 1),
+            Sub(2, 0, 1),
+            Return(Some(2)),
+        ],
+        consts: [I(1), I(2)],
+        local_count: 3,
+    }
+    input: b"";
+    output: b"";
+    result: Ok(I(1));
+}
+
+test_program! {
+    name: select;
+    text: r#"
+defn f0 4 : true false 1 2
+x0 := k0
+x2 := k2
+x3 := k3
+x0 := select x0 x2 x3
+x1 := k1
+x1 := select x1 x2 x3
+x0 := (x0; 2)
+return x0
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            Const(2, 2),
+            Const(3, 3),
+            Select(0, 0, 2, 3),
+            Const(1, 1),
+            Select(1, 1, 2, 3),
+            MkTup(0, 0, 2),
+            Return(Some(0)),
+        ],
+        consts: [B(true), B(false), I(1), I(2)],
+        local_count: 4,
+    }
+    input: b"";
+    output: b"";
+    result: Ok(T(vec![I(1), I(2)]));
+}
+
+test_program! {
+    name: select_non_bool;
+    text: r#"
+defn f0 1 : 1
+x0 := k0
+x0 := select x0 x0 x0
+return x0
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            Select(0, 0, 0, 0),
+            Return(Some(0)),
+        ],
+        consts: [I(1)],
+        local_count: 1,
+    }
+    input: b"";
+    output: b"";
+    result: Err(EvalErrorKind::TypeMismatch.into());
+}
+
+test_program! {
+    name: assert;
+    text: r#"
+defn f0 2 : 1 2
+x0 := k0
+x1 := k1
+x1 := x0 < x1
+assert x1
+write x0
+x1 := x0 == x0
+x1 := !x1
+assert x1
+write x0
+return x0
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            Const(1, 1),
+            Lt(1, 0, 1),
+            Assert(1),
+            Write(0),
+            Eq(1, 0, 0),
+            Not(1, 1),
+            Assert(1),
+            Write(0),
+            Return(Some(0)),
+        ],
+        consts: [I(1), I(2)],
+        local_count: 2,
+    }
+    input: b"";
+    output: &[1];
+    result: Err(EvalErrorKind::AssertionFailed.into());
+}
+
+test_program! {
+    name: assert_non_bool;
+    text: r#"
+defn f0 1 : 1
+x0 := k0
+assert x0
+return x0
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            Assert(0),
+            Return(Some(0)),
+        ],
+        consts: [I(1)],
+        local_count: 1,
+    }
+    input: b"";
+    output: b"";
+    result: Err(EvalErrorKind::TypeMismatch.into());
+}
+
+test_program! {
+    name: float_rem;
+    text: r#"
+defn f0 2 : 5.0 2.0
+x0 := k0
+x1 := k1
+x0 := x0 % x1
+return x0
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            Const(1, 1),
+            Rem(0, 0, 1),
+            Return(Some(0)),
+        ],
+        consts: [F(5.0), F(2.0)],
+        local_count: 2,
+    }
+    input: b"";
+    output: b"";
+    result: Ok(F(1.0));
+}
+
+test_program! {
+    name: imm_arith;
+    text: r#"
+defn f0 1 : 0
+x0 := k0
+x0 := x0 +# 1
+x0 := x0 +# 1
+x0 := x0 *# 10
+x0 := x0 +# -3
+return x0
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            AddImm(0, 0, 1),
+            AddImm(0, 0, 1),
+            MulImm(0, 0, 10),
+            AddImm(0, 0, -3),
+            Return(Some(0)),
+        ],
+        consts: [I(0)],
+        local_count: 1,
+    }
+    input: b"";
+    output: b"";
+    result: Ok(I(17));
+}
+
+test_program! {
+    name: imm_overflow;
+    text: r#"
+defn f0 1 : 9223372036854775807
+x0 := k0
+x0 := x0 +# 1
+return x0
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            AddImm(0, 0, 1),
+            Return(Some(0)),
+        ],
+        consts: [I(i64::MAX)],
+        local_count: 1,
+    }
+    input: b"";
+    output: b"";
+    result: Err(EvalErrorKind::Arithmetic.into());
+}
+
 test_program! {
     name: unop;
     text: r#"
@@ -355,6 +674,7 @@ defn f0 2 : f1
   (x0; 2) := x0
   write x0
   write x1
+  return
 
 defn f1 3 :
   (x0; 2) := x0
@@ -372,8 +692,9 @@ defn f1 3 :
             UnTup(0, 2, 0),
             Write(0),
             Write(1),
+            Return(None),
         ],
-        consts: [C(1)],
+        consts: [C(1, None)],
         local_count: 2,
     }
     defn {
@@ -400,6 +721,7 @@ defn f0 2 :
     x1 := x1 + x1
     write x1
     write x0
+    return
 "#;
     defn {
         code: [
@@ -408,6 +730,7 @@ defn f0 2 :
             Add(1, 1, 1),
             Write(1),
             Write(0),
+            Return(None),
         ],
         consts: [],
         local_count: 2,
@@ -435,7 +758,7 @@ fn test_format() {
                             Call(0, 1, 0),
                             Return(Some(0)),
                         ],
-                        consts: vec![I(42), I(69), C(1)],
+                        consts: vec![I(42), I(69), C(1, None)],
                         local_count: 2,
                     },
                     Defn {
@@ -452,6 +775,7 @@ fn test_format() {
                     },
                 ],
                 entry_point: 0,
+                stack_depth: Default::default(),
             }
         ),
         r#"defn f0 2 : 42 69 f1
@@ -471,3 +795,3148 @@ defn f1 3 : 0 1
     return x0"#
     );
 }
+
+#[test]
+fn test_iter_defns() {
+    let program = parse::parse(
+        r#"
+defn f0 1 : f1
+x0 := k0
+return x0
+
+defn f1 1 : f2
+x0 := k0
+return x0
+
+defn f2 1 :
+return x0
+"#,
+    ).unwrap();
+
+    let mut ids = Vec::new();
+    for (id, defn) in &program {
+        assert_eq!(defn, &program.defns()[id as usize]);
+        ids.push(id);
+    }
+    assert_eq!(ids, vec![0, 1, 2]);
+    assert!(ids.contains(&program.entry_point()));
+}
+
+#[test]
+fn test_recursion() {
+    let direct = parse::parse(
+        r#"
+defn f0 2 : f0
+x1 := k0
+x0 := x1(x0)
+return x0
+"#,
+    ).unwrap();
+    assert!(direct.is_recursive(0));
+
+    let mutual = parse::parse(
+        r#"
+defn f0 2 : f1
+x1 := k0
+x0 := x1(x0)
+return x0
+
+defn f1 2 : f2
+x1 := k0
+x0 := x1(x0)
+return x0
+
+defn f2 2 : f1
+x1 := k0
+x0 := x1(x0)
+return x0
+"#,
+    ).unwrap();
+    assert!(!mutual.is_recursive(0));
+    assert!(mutual.is_recursive(1));
+    assert!(mutual.is_recursive(2));
+
+    let linear = parse::parse(
+        r#"
+defn f0 2 : f1
+x1 := k0
+x0 := x1(x0)
+return x0
+
+defn f1 2 : f2
+x1 := k0
+x0 := x1(x0)
+return x0
+
+defn f2 1 :
+return x0
+"#,
+    ).unwrap();
+    let graph = linear.call_graph();
+    assert_eq!(graph[&0], vec![1]);
+    assert_eq!(graph[&1], vec![2]);
+    assert_eq!(graph[&2], vec![]);
+    assert!(!linear.is_recursive(0));
+    assert!(!linear.is_recursive(1));
+    assert!(!linear.is_recursive(2));
+}
+
+#[test]
+fn test_load_const() {
+    use self::Val::*;
+    use self::Instr::*;
+
+    let consts = (0..300).map(|i| format!(" {}", i)).collect::<String>();
+    let text = format!(
+        "defn f0 2 :{}\nx0 := k255\nx1 := k299\nx0 := x0 + x1\nx1 := K1\nx0 := x0 + x1\nreturn x0",
+        consts
+    );
+    let program = parse::parse(&text).unwrap();
+
+    // With wide registers `Const` can address the whole pool by itself.
+    #[cfg(not(feature = "wide_regs"))]
+    let (wide_load, issues) = (
+        LoadConst(1, 299),
+        vec![ValidationIssue::TooManyConsts {
+            fn_id: 0,
+            count: 300,
+        }],
+    );
+    #[cfg(feature = "wide_regs")]
+    let (wide_load, issues) = (Const(1, 299), vec![]);
+
+    assert_eq!(
+        program.defns()[0].code(),
+        &[
+            Const(0, 255),
+            wide_load,
+            Add(0, 0, 1),
+            LoadConst(1, 1),
+            Add(0, 0, 1),
+            Return(Some(0)),
+        ]
+    );
+    assert_eq!(
+        program.eval(&mut ::std::io::empty(), &mut Vec::new()),
+        Ok(I(555))
+    );
+    assert_eq!(program.validate(), issues);
+    assert_eq!(call_return::program().validate(), vec![]);
+}
+
+#[test]
+#[cfg(feature = "wide_regs")]
+fn test_wide_regs() {
+    use self::Val::*;
+
+    let text = "defn f0 300 : 1 2\nx0 := k0\nx299 := k1\nx299 := x299 + x0\nreturn x299";
+    let program = parse::parse(text).unwrap();
+    assert_eq!(program.defns()[0].local_count(), 300);
+    assert_eq!(
+        program.eval(&mut ::std::io::empty(), &mut Vec::new()),
+        Ok(I(3))
+    );
+}
+
+test_program! {
+    name: closure_counter;
+    text: r#"
+defn f0 4 : f1 0
+x1 := k1
+x1 := (x1; 1)
+x0 := k0
+x0 := closure x0 x1
+x1 := x0(x1)
+x2 := x0(x1)
+x3 := x0(x1)
+x1 := (x1; 3)
+return x1
+
+defn f1 2 :
+x1 := upval 0
+x1 := x1 +# 1
+upval 0 := x1
+return x1
+"#;
+    defn {
+        code: [
+            Const(1, 1),
+            MkTup(1, 1, 1),
+            Const(0, 0),
+            MkClosure(0, 0, 1),
+            Call(1, 0, 1),
+            Call(2, 0, 1),
+            Call(3, 0, 1),
+            MkTup(1, 1, 3),
+            Return(Some(1)),
+        ],
+        consts: [C(1, None), I(0)],
+        local_count: 4,
+    }
+    defn {
+        code: [
+            GetUpval(1, 0),
+            AddImm(1, 1, 1),
+            SetUpval(0, 1),
+            Return(Some(1)),
+        ],
+        consts: [],
+        local_count: 2,
+    }
+    input: b"";
+    output: b"";
+    // The closure keeps its count between calls
+    result: Ok(T(vec![I(1), I(2), I(3)]));
+}
+
+test_program! {
+    name: closure_bad_upval;
+    text: r#"
+defn f0 2 : f1
+x1 := k0
+x0 := x1(x0)
+return x0
+
+defn f1 1 :
+x0 := upval 0
+return x0
+"#;
+    defn {
+        code: [
+            Const(1, 0),
+            Call(0, 1, 0),
+            Return(Some(0)),
+        ],
+        consts: [C(1, None)],
+        local_count: 2,
+    }
+    defn {
+        code: [
+            GetUpval(0, 0),
+            Return(Some(0)),
+        ],
+        consts: [],
+        local_count: 1,
+    }
+    input: b"";
+    output: b"";
+    result: Err(EvalErrorKind::OutOfBounds { index: 0, len: 0 }.into());
+}
+
+test_program! {
+    name: heap_counter;
+    text: r#"
+defn f0 3 : f1 0
+x0 := k1
+alloc x0
+x2 := k0
+x1 := x2(x0)
+x1 := x2(x0)
+x1 := *x0
+x2 := x1
+alloc x2
+x0 := x0 == x2
+x0 := (x0; 2)
+return x0
+
+defn f1 2 :
+x1 := *x0
+x1 := x1 +# 1
+*x0 := x1
+return
+"#;
+    defn {
+        code: [
+            Const(0, 1),
+            Alloc(0),
+            Const(2, 0),
+            Call(1, 2, 0),
+            Call(1, 2, 0),
+            Load(1, 0),
+            Copy(2, 1),
+            Alloc(2),
+            Eq(0, 0, 2),
+            MkTup(0, 0, 2),
+            Return(Some(0)),
+        ],
+        consts: [C(1, None), I(0)],
+        local_count: 3,
+    }
+    defn {
+        code: [
+            Load(1, 0),
+            AddImm(1, 1, 1),
+            Store(0, 1),
+            Return(None),
+        ],
+        consts: [],
+        local_count: 2,
+    }
+    input: b"";
+    output: b"";
+    // Cells with the same contents are still distinct
+    result: Ok(T(vec![B(false), I(2)]));
+}
+
+#[test]
+fn test_ref_identity() {
+    use self::Val::*;
+    use std::sync::{Arc, Mutex};
+
+    let a = Ref(Arc::new(Mutex::new(I(1))));
+    let b = Ref(Arc::new(Mutex::new(I(1))));
+    assert_eq!(a, a.clone());
+    assert_ne!(a, b);
+}
+
+#[test]
+fn test_max_call_depth() {
+    use self::Val::*;
+
+    let program = parse::parse(
+        r#"
+defn f0 2 : f0
+x1 := k0
+x0 := x1(x0)
+return x0
+"#,
+    ).unwrap();
+    let mut input = ::std::io::empty();
+    let mut output = Vec::new();
+    let mut ctx = EvalContext::new(&mut input, &mut output);
+    ctx.options.max_call_depth = Some(100);
+    assert_eq!(program.eval_with_context(&mut ctx), Err(EvalErrorKind::CallDepthExceeded.into()));
+
+    let mut ctx = EvalContext::new(&mut input, &mut output);
+    ctx.options.max_call_depth = Some(1);
+    assert_eq!(
+        call_return::program().eval_with_context(&mut ctx),
+        Ok(I(111))
+    );
+}
+
+struct Reverse;
+
+impl NativeFn for Reverse {
+    fn call(&self, args: &Val) -> Result<Val, EvalError> {
+        match *args {
+            Val::T(ref t) => Ok(Val::T(t.iter().rev().cloned().collect())),
+            _ => Err(EvalErrorKind::TypeMismatch.into()),
+        }
+    }
+}
+
+#[test]
+fn test_parse_malformed_consts() {
+    use parse_util::ParseError;
+
+    let error = |text| parse::parse(text).unwrap_err();
+    assert_eq!(
+        error("defn f0 2 : 1 42xyz 3\n  return"),
+        ParseError::expected("a constant, got '42xyz'", 1, 14)
+    );
+    assert_eq!(
+        error("defn f0 2 : 1 2 ,\n  return"),
+        ParseError::expected("a constant, got ','", 1, 16)
+    );
+    assert_eq!(
+        error("defn f0 2 : 1.5.2\n  return"),
+        ParseError::expected("a constant, got '1.5.2'", 1, 12)
+    );
+
+    let program = parse::parse("defn f0 2 : 1 2   \n  return").unwrap();
+    assert_eq!(program.defns()[0].consts(), &[Val::I(1), Val::I(2)]);
+}
+
+#[test]
+fn test_call_native() {
+    use self::Val::*;
+    use self::Instr::*;
+
+    let program = parse::parse(
+        r#"
+defn f0 3 : 1 2 3
+x0 := k0
+x1 := k1
+x2 := k2
+x0 := (x0; 3)
+x0 := native 7(x0)
+x1 := native 8(x0)
+return x1
+"#,
+    ).unwrap();
+    assert_eq!(program.defns()[0].code()[4], CallNative(0, 7, 0));
+
+    let mut input = ::std::io::empty();
+    let mut output = Vec::new();
+    let mut ctx = EvalContext::new(&mut input, &mut output);
+    ctx.register_native(7, Reverse);
+    ctx.register_native(8, |args: &Val| match *args {
+        T(ref t) => Ok(t[0].clone()),
+        _ => Err(EvalErrorKind::TypeMismatch.into()),
+    });
+    assert_eq!(program.eval_with_context(&mut ctx), Ok(I(3)));
+
+    // Unregistered natives are an error
+    assert_eq!(
+        program.eval(&mut ::std::io::empty(), &mut Vec::new()),
+        Err(EvalErrorKind::UnknownNative(7).into())
+    );
+}
+
+assert_impl_all!(Program: Send, Sync);
+assert_impl_all!(Val: Send, Sync);
+
+#[test]
+fn test_eval_parallel() {
+    let program = parse::parse(
+        r#"
+defn f0 2 :
+x0 := read
+x1 := read
+x0 := x0 * x1
+return x0
+"#,
+    ).unwrap();
+    let inputs = (0..100u8).map(|i| vec![i, i / 2]).collect::<Vec<_>>();
+    let sequential = inputs
+        .iter()
+        .map(|input| program.eval(&mut ::std::io::Cursor::new(input), &mut Vec::new()))
+        .collect::<Vec<_>>();
+    assert_eq!(program.eval_parallel(inputs), sequential);
+}
+
+#[test]
+fn test_val_bytes() {
+    use self::Val::*;
+
+    let val = T(vec![
+        B(true),
+        I(-42),
+        F(1.5),
+        T(vec![]),
+        T(vec![C(3, None), C(4, Some(2)), T(vec![B(false), I(i64::MAX)])]),
+        S("h\u{e9}llo".to_string()),
+        Null,
+        Closure {
+            fn_id: 2,
+            upvals: vec![I(1), F(-0.0)].into_boxed_slice(),
+        },
+    ]);
+    assert_eq!(Val::from_bytes(&val.to_bytes()), Ok(val));
+
+    assert_eq!(I(1).to_bytes(), vec![0x02, 1, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(T(vec![C(1, None)]).to_bytes(), vec![0x04, 1, 0, 0, 0, 0x05, 1, 0]);
+
+    // Truncated, trailing, and unknown bytes are all errors
+    assert_eq!(Val::from_bytes(&[]), Err(DecodeError {}));
+    assert_eq!(Val::from_bytes(&[0x02, 1, 0]), Err(DecodeError {}));
+    assert_eq!(Val::from_bytes(&[0x04, 2, 0, 0, 0, 0x01]), Err(DecodeError {}));
+    assert_eq!(Val::from_bytes(&[0x01, 0x01]), Err(DecodeError {}));
+    assert_eq!(Val::from_bytes(&[0xff]), Err(DecodeError {}));
+
+    // Nesting too deep to decode without risking the stack is an error
+    assert_eq!(Val::from_bytes(&[0x07; 1 << 20]), Err(DecodeError {}));
+    let mut bytes = [0x04, 1, 0, 0, 0].repeat(100_000);
+    bytes.push(0x01);
+    assert_eq!(Val::from_bytes(&bytes), Err(DecodeError {}));
+    let nested = (0..100).fold(B(true), |val, _| T(vec![val]));
+    assert_eq!(Val::from_bytes(&nested.to_bytes()), Ok(nested));
+}
+
+#[test]
+fn test_checksum() {
+    use self::Instr::*;
+
+    let program = call_return::program();
+    let checksum = program.checksum();
+    assert_eq!(checksum, call_return::program().checksum());
+
+    for fn_id in 0..program.defns().len() {
+        for iptr in 0..program.defns()[fn_id].code().len() {
+            let mut changed = call_return::program();
+            let instr = &mut changed.defns_mut()[fn_id].code_mut()[iptr];
+            *instr = match *instr {
+                Return(Some(_)) => Return(None),
+                _ => Return(Some(0)),
+            };
+            assert_ne!(changed.checksum(), checksum);
+        }
+    }
+
+    let mut swapped = call_return::program();
+    swapped.defns_mut()[0].code_mut().swap(0, 1);
+    assert_ne!(swapped.checksum(), checksum);
+}
+
+#[test]
+fn test_instr_encoding() {
+    use self::Instr::*;
+
+    let instrs = [
+        Const(1, 2),
+        LoadConst(1, 300),
+        Copy(1, 2),
+        Add(1, 2, 3),
+        AddImm(1, 2, -3),
+        Sub(1, 2, 3),
+        Mul(1, 2, 3),
+        MulImm(1, 2, 3),
+        Div(1, 2, 3),
+        Rem(1, 2, 3),
+        And(1, 2, 3),
+        Orr(1, 2, 3),
+        Xor(1, 2, 3),
+        Eq(1, 2, 3),
+        Neq(1, 2, 3),
+        Lt(1, 2, 3),
+        Gt(1, 2, 3),
+        Leq(1, 2, 3),
+        Geq(1, 2, 3),
+        Neg(1, 2),
+        Not(1, 2),
+        Jump(-1000),
+        CondJump(1, -2, 3),
+        MkTup(1, 2, 3),
+        UnTup(1, 2, 3),
+        IdxTup(1, 2, 3),
+        Call(1, 2, 3),
+        MkClosure(1, 2, 3),
+        GetUpval(1, 2),
+        SetUpval(1, 2),
+        Alloc(1),
+        Load(1, 2),
+        Store(1, 2),
+        CallNative(1, 70000, 3),
+        Return(None),
+        Return(Some(1)),
+        Read(1),
+        Write(1),
+        Switch(1, 2, 3),
+        ReadN(1, 2),
+        WriteN(1),
+        DebugPrint(1),
+        FEq(1, 2, 3, 4),
+        Nop,
+        CmpJump(1, 2, -3, 4, 500),
+        ReadStr(1),
+        WriteStr(1),
+        StrLen(1, 2),
+        StrCat(1, 2, 3),
+        StrIdx(1, 2, 3),
+        Pow(1, 2, 3),
+        Inspect(1, 2),
+        Shl(1, 2, 3),
+        Shr(1, 2, 3),
+        CastItoF(1, 2),
+        CastFtoI(1, 2),
+        TupLen(1, 2),
+        Concat(1, 2, 3),
+        Slice(1, 2, 3, 4),
+        Swap(1, 2),
+        Select(1, 2, 3, 4),
+        Assert(1),
+        BoolToInt(1, 2),
+        IntToBool(1, 2),
+        JumpLong(-100_000),
+        CondJumpLong(1, 70_000, -3),
+        Abs(1, 2),
+        Sqrt(1, 2),
+        Floor(1, 2),
+        Ceil(1, 2),
+        Round(1, 2),
+        Min(1, 2, 3),
+        Max(1, 2, 3),
+        WAdd(1, 2, 3),
+        WSub(1, 2, 3),
+        WMul(1, 2, 3),
+        TupSet(1, 2, 3),
+        TupPush(1, 2),
+        TupPop(1, 2),
+        #[cfg(feature = "transcendentals")]
+        Sin(1, 2),
+        #[cfg(feature = "transcendentals")]
+        Cos(1, 2),
+        #[cfg(feature = "transcendentals")]
+        Tan(1, 2),
+    ];
+
+    let mut opcodes = Vec::new();
+    for instr in instrs.iter() {
+        let mut bytes = instr.encode().to_vec();
+        opcodes.push(bytes[0]);
+        assert_eq!(Instr::decode(&bytes), Some((instr.clone(), bytes.len())));
+
+        // Trailing bytes aren't consumed, and missing bytes are an error
+        let len = bytes.len();
+        bytes.push(0xff);
+        assert_eq!(Instr::decode(&bytes).map(|(_, n)| n), Some(len));
+        assert_eq!(Instr::decode(&bytes[..len - 1]).map(|(_, n)| n), None);
+    }
+    opcodes.sort();
+    opcodes.dedup();
+    assert_eq!(opcodes.len(), instrs.len());
+    assert_eq!(Instr::decode(&[]), None);
+    assert_eq!(Instr::decode(&[0xff]), None);
+}
+
+#[test]
+fn test_program_bytes() {
+    let program = untup::program();
+    assert_eq!(Program::from_bytes(&program.to_bytes()), Ok(untup::program()));
+
+    let mut bytes = program.to_bytes();
+    bytes.push(0);
+    assert_eq!(Program::from_bytes(&bytes), Err(DecodeError {}));
+    bytes.truncate(bytes.len() - 2);
+    assert_eq!(Program::from_bytes(&bytes), Err(DecodeError {}));
+}
+
+test_program! {
+    name: switch;
+    text: r#"
+defn f0 2 : 2 10 11 12 13 99
+x0 := read
+x1 := k0
+switch x0 x1 4
+jump 13
+jump 4
+jump 5
+jump 6
+jump 7
+x0 := k1
+return x0
+x0 := k2
+return x0
+x0 := k3
+return x0
+x0 := k4
+return x0
+x0 := k5
+return x0
+"#;
+    defn {
+        code: [
+            Read(0),
+            Const(1, 0),
+            Switch(0, 1, 4),
+            Jump(13),
+            Jump(4),
+            Jump(5),
+            Jump(6),
+            Jump(7),
+            Const(0, 1),
+            Return(Some(0)),
+            Const(0, 2),
+            Return(Some(0)),
+            Const(0, 3),
+            Return(Some(0)),
+            Const(0, 4),
+            Return(Some(0)),
+            Const(0, 5),
+            Return(Some(0)),
+        ],
+        consts: [I(2), I(10), I(11), I(12), I(13), I(99)],
+        local_count: 2,
+    }
+    input: &[2];
+    output: b"";
+    result: Ok(I(12));
+}
+
+#[test]
+fn test_switch_cases() {
+    use self::Val::*;
+
+    let program = switch::program();
+    let cases = [(0, 10), (1, 11), (2, 12), (3, 13), (4, 99), (200, 99)];
+    for &(input, expected) in cases.iter() {
+        assert_eq!(
+            program.eval(&mut ::std::io::Cursor::new(vec![input]), &mut Vec::new()),
+            Ok(I(expected))
+        );
+    }
+}
+
+test_program! {
+    name: read_write_n;
+    text: r#"
+defn f0 2 : 4
+x1 := k0
+x0 := readn x1
+writen x0
+return x0
+"#;
+    defn {
+        code: [
+            Const(1, 0),
+            ReadN(0, 1),
+            WriteN(0),
+            Return(Some(0)),
+        ],
+        consts: [I(4)],
+        local_count: 2,
+    }
+    input: &[1, 2, 254, 255];
+    output: &[1, 2, 254, 255];
+    result: Ok(T(vec![I(1), I(2), I(254), I(255)]));
+}
+
+test_program! {
+    name: read_n_eof;
+    text: r#"
+defn f0 2 : 4
+x1 := k0
+x0 := readn x1
+return x0
+"#;
+    defn {
+        code: [
+            Const(1, 0),
+            ReadN(0, 1),
+            Return(Some(0)),
+        ],
+        consts: [I(4)],
+        local_count: 2,
+    }
+    input: &[1, 2, 3];
+    output: b"";
+    result: Err(EvalErrorKind::UnexpectedEof.into());
+}
+
+test_program! {
+    name: write_n_out_of_range;
+    text: r#"
+defn f0 2 : 5 256
+x0 := k0
+x1 := k1
+x0 := (x0; 2)
+writen x0
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            Const(1, 1),
+            MkTup(0, 0, 2),
+            WriteN(0),
+        ],
+        consts: [I(5), I(256)],
+        local_count: 2,
+    }
+    input: b"";
+    output: b"";
+    result: Err(EvalErrorKind::ByteOutOfRange(256).into());
+}
+
+test_program! {
+    name: write_all;
+    text: r#"
+defn f0 4 : 0 104 105 255
+x0 := k0
+x1 := k1
+x2 := k2
+x3 := k3
+x0 := (x0; 4)
+write_all x0
+return
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            Const(1, 1),
+            Const(2, 2),
+            Const(3, 3),
+            MkTup(0, 0, 4),
+            WriteN(0),
+            Return(None),
+        ],
+        consts: [I(0), I(104), I(105), I(255)],
+        local_count: 4,
+    }
+    input: b"";
+    output: &[0, b'h', b'i', 255];
+    result: Ok(T(vec![]));
+}
+
+#[test]
+fn test_falls_off_end() {
+    let program = parse::parse(
+        r#"
+defn f0 2 : f1
+  x0 := k0
+  x0 := x0(x1)
+  return x0
+
+defn f1 1 :
+  x0 := read
+"#,
+    ).unwrap();
+    assert_eq!(
+        program.validate(),
+        vec![ValidationIssue::FallsOffEnd { fn_id: 1 }]
+    );
+    assert_eq!(
+        program.eval(&mut &b"a"[..], &mut vec![]).unwrap_err().kind,
+        EvalErrorKind::InstructionPointerOutOfBounds { fn_id: 1, iptr: 1 }
+    );
+}
+
+#[test]
+fn test_jump_out_of_bounds() {
+    let program = parse::parse(
+        r#"
+defn f0 1 : true
+  x0 := k0
+  cond x0 5 1
+  return
+"#,
+    ).unwrap();
+    assert_eq!(
+        program.validate(),
+        vec![ValidationIssue::JumpOutOfBounds { fn_id: 0, iptr: 1 }]
+    );
+    assert_eq!(
+        program.eval(&mut &b""[..], &mut vec![]).unwrap_err().kind,
+        EvalErrorKind::InstructionPointerOutOfBounds { fn_id: 0, iptr: 6 }
+    );
+
+    // Jumping back past the start is an error rather than wrapping around
+    let program = parse::parse(
+        r#"
+defn f0 1 : true
+  x0 := k0
+  cond x0 -3 1
+  return
+"#,
+    ).unwrap();
+    assert_eq!(
+        program.validate(),
+        vec![ValidationIssue::JumpOutOfBounds { fn_id: 0, iptr: 1 }]
+    );
+    assert_eq!(
+        program.eval(&mut &b""[..], &mut vec![]).unwrap_err().kind,
+        EvalErrorKind::JumpOutOfBounds { fn_id: 0, iptr: 1 }
+    );
+}
+
+#[test]
+fn test_estimate_stack_depth() {
+    let linear = parse::parse(
+        r#"
+defn f0 2 : f1 f2
+x1 := k1
+x0 := x1(x0)
+x1 := k0
+x0 := x1(x0)
+return x0
+
+defn f1 2 : f2
+x1 := k0
+x0 := x1(x0)
+return x0
+
+defn f2 1 :
+return x0
+"#,
+    ).unwrap();
+    assert_eq!(linear.estimate_stack_depth(), 2);
+
+    // The estimate is exactly the depth evaluation needs.
+    let eval_with_max = |max| {
+        let (mut input, mut output) = (&b""[..], vec![]);
+        let mut ctx = EvalContext::new(&mut input, &mut output);
+        ctx.options.max_call_depth = Some(max);
+        linear.eval_with_context(&mut ctx).map_err(|e| e.kind)
+    };
+    assert_eq!(eval_with_max(2), Ok(Val::I(0)));
+    assert_eq!(eval_with_max(1), Err(EvalErrorKind::CallDepthExceeded));
+
+    // Evaluation estimates the depth once, and again only after the code changes
+    let mut linear = linear;
+    assert_eq!(linear.stack_depth.0.get(), Some(&2));
+    linear.defns_mut()[0].code_mut().swap(0, 2);
+    assert_eq!(linear.stack_depth.0.get(), None);
+    assert_eq!(linear.eval(&mut &b""[..], &mut vec![]), Ok(Val::I(0)));
+    assert_eq!(linear.stack_depth.0.get(), Some(&2));
+    assert_eq!(linear, parse::parse(&format!("{}", linear)).unwrap());
+
+    let recursive = parse::parse(
+        r#"
+defn f0 2 : f0
+x1 := k0
+x0 := x1(x0)
+return x0
+"#,
+    ).unwrap();
+    assert_eq!(recursive.estimate_stack_depth(), usize::MAX);
+}
+
+#[test]
+fn test_call_arity() {
+    use self::Val::*;
+
+    let text = r#"
+defn f0 3 : f1/2 1 2
+  x0 := k1
+  x1 := k2
+  x0 := (x0; 2)
+  x2 := k0
+  x0 := x2(x0)
+  x1 := (x1; 1)
+  x0 := x2(x1)
+  return x0
+
+defn f1 2 :
+  (x0; 2) := x0
+  x0 := x0 + x1
+  return x0
+"#;
+    let program = parse::parse(text).unwrap();
+    assert_eq!(program.defns()[0].consts()[0], C(1, Some(2)));
+    assert_eq!(format!("{}", C(1, Some(2))), "f1/2");
+    assert_eq!(
+        program.eval(&mut &b""[..], &mut vec![]).unwrap_err().kind,
+        EvalErrorKind::WrongArity {
+            expected: 2,
+            got: 1,
+        }
+    );
+
+    // The first call has the right number of arguments
+    let ok = text.replace("  x1 := (x1; 1)\n  x0 := x2(x1)\n", "");
+    assert_eq!(parse::parse(&ok).unwrap().eval(&mut &b""[..], &mut vec![]), Ok(I(3)));
+}
+
+#[test]
+fn test_call_unknown_function() {
+    use self::Instr::*;
+    use self::Val::*;
+
+    let program = parse::parse("defn f0 2 : f7\n  x1 := k0\n  x0 := x1(x0)\n  return x0").unwrap();
+    assert_eq!(
+        program.eval(&mut &b""[..], &mut vec![]).unwrap_err().kind,
+        EvalErrorKind::UnknownFunction(7)
+    );
+
+    // Closures and decoded programs can name any function too
+    let closure = Closure {
+        fn_id: 3,
+        upvals: Box::new([]),
+    };
+    let program = Program {
+        defns: vec![Defn {
+            code: vec![Const(1, 0), Call(0, 1, 0), Return(Some(0))],
+            consts: vec![closure],
+            local_count: 2,
+        }],
+        entry_point: 0,
+        stack_depth: Default::default(),
+    };
+    let program = Program::from_bytes(&program.to_bytes()).unwrap();
+    assert_eq!(
+        program.eval(&mut &b""[..], &mut vec![]).unwrap_err().kind,
+        EvalErrorKind::UnknownFunction(3)
+    );
+
+    let program = Program {
+        defns: vec![],
+        entry_point: 0,
+        stack_depth: Default::default(),
+    };
+    assert_eq!(
+        program.eval(&mut &b""[..], &mut vec![]).unwrap_err().kind,
+        EvalErrorKind::UnknownFunction(0)
+    );
+}
+
+test_program! {
+    name: debug_print;
+    text: r#"
+defn f0 1 : 5
+  x0 := k0
+  debug x0
+  return x0
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            DebugPrint(0),
+            Return(Some(0)),
+        ],
+        consts: [I(5)],
+        local_count: 1,
+    }
+    input: b"";
+    output: b"";
+    result: Ok(I(5));
+}
+
+#[cfg(feature = "debug_instrs")]
+#[test]
+fn test_debug_print_stderr() {
+    use std::env;
+    use std::process::Command;
+
+    // The test harness captures stderr, so the program runs again in a child
+    // process whose stderr can be checked
+    if env::var_os("TIC_TAC_DEBUG_PRINT_CHILD").is_some() {
+        let res = debug_print::program().eval(&mut io::empty(), &mut io::sink());
+        assert_eq!(res, Ok(Val::I(5)));
+        return;
+    }
+    let name = "bytecode::tests::test_debug_print_stderr";
+    let output = Command::new(env::current_exe().unwrap())
+        .args(["--exact", name, "--nocapture", "--test-threads=1"])
+        .env("TIC_TAC_DEBUG_PRINT_CHILD", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("DEBUG[0]: int(5)\n"), "stderr was {:?}", stderr);
+}
+
+#[test]
+fn test_liveness() {
+    let program = call_return::program();
+    let live = |fn_id: usize| {
+        liveness::compute(&program.defns()[fn_id])
+            .iter()
+            .map(|set| set.iter().collect())
+            .collect::<Vec<Vec<_>>>()
+    };
+    assert_eq!(
+        live(0),
+        vec![vec![], vec![0], vec![0, 1], vec![0], vec![0, 1], vec![0]]
+    );
+    assert_eq!(
+        live(1),
+        vec![vec![0], vec![0, 1], vec![0, 1], vec![0, 1, 2], vec![1, 2], vec![0]]
+    );
+
+    // Registers read later in a loop stay live around the back edge
+    let looping = parse::parse(
+        r#"
+defn f0 3 : 0 1 true
+x0 := k0
+x1 := k1
+x2 := k2
+x0 := x0 + x1
+cond x2 -1 1
+return x0
+"#,
+    ).unwrap();
+    let live = liveness::compute(&looping.defns()[0]);
+    assert_eq!(live[4].iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+}
+
+#[test]
+fn test_source_map() {
+    let text = r#"
+defn f0 2 : f1
+  x0 := k0
+  x0 := x0(x1)
+  return x0
+
+# Falls off the end
+defn f1 1 :
+    x0 := read
+"#;
+    let (program, source_map) = parse::parse_with_source_map(text, "main.tac").unwrap();
+    assert_eq!(program, parse::parse(text).unwrap());
+    assert_eq!(source_map.entries.len(), 4);
+    assert_eq!(
+        source_map.lookup(0, 1),
+        Some(&SourceEntry {
+            fn_id: 0,
+            iptr: 1,
+            file: "main.tac".to_string(),
+            line: 4,
+            col: 3,
+        })
+    );
+    assert_eq!(source_map.lookup(0, 3), None);
+
+    let err = program.eval(&mut &b"a"[..], &mut vec![]).unwrap_err();
+    match err.kind {
+        EvalErrorKind::InstructionPointerOutOfBounds { fn_id, iptr } => {
+            // The last instruction run is the one before the bad pointer
+            let entry = source_map.lookup(fn_id, iptr - 1).unwrap();
+            assert_eq!((entry.line, entry.col), (9, 5));
+        }
+        kind => panic!("unexpected error {:?}", kind),
+    }
+}
+
+#[test]
+fn test_peephole() {
+    use self::Instr::*;
+
+    let text = r#"
+defn f0 4 : 0 1 10
+  x0 := k0
+  x3 := x0
+  x1 := k1
+  x2 := k2
+  x0 := x3 + x1
+  x3 := x0
+  x0 := x3 < x2
+  cond x0 -3 1
+  x2 := x3
+  x0 := x2
+  return x0
+"#;
+    let mut program = parse::parse(text).unwrap();
+    let unoptimized = parse::parse(text).unwrap();
+    opt::PeepholeOptimizer::new().optimize_program(&mut program);
+
+    assert_eq!(
+        program.defns()[0].code(),
+        &[
+            Const(3, 0),
+            Const(1, 1),
+            Const(2, 2),
+            Add(0, 3, 1),
+            Copy(3, 0),
+            Lt(0, 3, 2),
+            CondJump(0, -3, 1),
+            Return(Some(3)),
+        ]
+    );
+    assert!(program.defns()[0].code().len() < unoptimized.defns()[0].code().len());
+    assert_eq!(program.eval(&mut &b""[..], &mut vec![]), Ok(Val::I(10)));
+    assert_eq!(unoptimized.eval(&mut &b""[..], &mut vec![]), Ok(Val::I(10)));
+
+    // Jumps over a folded sequence are shortened
+    let mut program = parse::parse(
+        r#"
+defn f0 2 : 5 true
+  x1 := k1
+  cond x1 1 4
+  x0 := k0
+  x1 := x0
+  return x1
+  return x0
+"#,
+    ).unwrap();
+    opt::PeepholeOptimizer::new().optimize_program(&mut program);
+    assert_eq!(
+        program.defns()[0].code(),
+        &[
+            Const(1, 1),
+            CondJump(1, 1, 3),
+            Const(1, 0),
+            Return(Some(1)),
+            Return(Some(0)),
+        ]
+    );
+}
+
+test_program! {
+    name: float_eq;
+    text: r#"
+defn f0 5 : 0.1 0.2 0.3 0.000001
+  x0 := k0
+  x1 := k1
+  x0 := x0 + x1
+  x1 := k2
+  x2 := k3
+  x3 := x0 == x1
+  x4 := x0 ~= x1 x2
+  x0 := (x3; 2)
+  return x0
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            Const(1, 1),
+            Add(0, 0, 1),
+            Const(1, 2),
+            Const(2, 3),
+            Eq(3, 0, 1),
+            FEq(4, 0, 1, 2),
+            MkTup(0, 3, 2),
+            Return(Some(0)),
+        ],
+        consts: [F(0.1), F(0.2), F(0.3), F(0.000001)],
+        local_count: 5,
+    }
+    input: b"";
+    output: b"";
+    result: Ok(T(vec![B(false), B(true)]));
+}
+
+#[test]
+fn test_float_eq_nan() {
+    use self::Val::*;
+
+    let program = |a: Val| Program {
+        defns: vec![Defn {
+            code: vec![
+                Instr::Const(0, 0),
+                Instr::Const(1, 1),
+                Instr::FEq(0, 0, 0, 1),
+                Instr::Return(Some(0)),
+            ],
+            consts: vec![a, F(1.0)],
+            local_count: 2,
+        }],
+        entry_point: 0,
+        stack_depth: Default::default(),
+    };
+    let eval = |a| program(a).eval(&mut &b""[..], &mut vec![]).map_err(|e| e.kind);
+    assert_eq!(eval(F(f64::NAN)), Ok(B(false)));
+    assert_eq!(eval(F(2.0)), Ok(B(true)));
+    assert_eq!(eval(I(2)), Err(EvalErrorKind::TypeMismatch));
+}
+
+#[test]
+fn test_return_out_of_bounds() {
+    let program = Program {
+        defns: vec![Defn {
+            code: vec![Instr::Return(Some(3))],
+            consts: vec![],
+            local_count: 2,
+        }],
+        entry_point: 0,
+        stack_depth: Default::default(),
+    };
+    assert_eq!(
+        program.validate(),
+        vec![ValidationIssue::RegisterOutOfBounds { fn_id: 0, iptr: 0 }]
+    );
+    assert_eq!(
+        program.eval(&mut &b""[..], &mut vec![]).unwrap_err().kind,
+        EvalErrorKind::OutOfBounds { index: 3, len: 2 }
+    );
+}
+
+#[test]
+fn test_compact_nops() {
+    let with_nops = r#"
+defn f0 3 : 0 1 5
+  nop
+  x0 := k0
+  x1 := k1
+  x2 := k2
+  nop
+  x0 := x0 + x1
+  nop
+  x1 := x0 < x2
+  cond x1 -4 1
+  nop
+  return x0
+"#;
+    let without_nops = r#"
+defn f0 3 : 0 1 5
+  x0 := k0
+  x1 := k1
+  x2 := k2
+  x0 := x0 + x1
+  x1 := x0 < x2
+  cond x1 -2 1
+  return x0
+"#;
+    let mut program = parse::parse(with_nops).unwrap();
+    let expected = parse::parse(without_nops).unwrap();
+    assert_eq!(
+        program.eval(&mut &b""[..], &mut vec![]),
+        expected.eval(&mut &b""[..], &mut vec![])
+    );
+
+    program.defns_mut()[0].compact_nops();
+    assert_eq!(program, expected);
+}
+
+test_program! {
+    name: cmp_jump;
+    text: r#"
+defn f0 3 : 5 0 1 2
+  x0 := read
+  x1 := k0
+  cmp3 x0 x1 1 3 5
+  x2 := k1
+  return x2
+  x2 := k2
+  return x2
+  x2 := k3
+  return x2
+"#;
+    defn {
+        code: [
+            Read(0),
+            Const(1, 0),
+            CmpJump(0, 1, 1, 3, 5),
+            Const(2, 1),
+            Return(Some(2)),
+            Const(2, 2),
+            Return(Some(2)),
+            Const(2, 3),
+            Return(Some(2)),
+        ],
+        consts: [I(5), I(0), I(1), I(2)],
+        local_count: 3,
+    }
+    input: &[5];
+    output: b"";
+    result: Ok(I(1));
+}
+
+#[test]
+fn test_cmp_jump_branches() {
+    use self::Val::*;
+
+    let program = cmp_jump::program();
+    let eval = |input: u8| program.eval(&mut &[input][..], &mut vec![]);
+    assert_eq!(eval(4), Ok(I(0)));
+    assert_eq!(eval(5), Ok(I(1)));
+    assert_eq!(eval(6), Ok(I(2)));
+
+    let nan = Program {
+        defns: vec![Defn {
+            code: vec![
+                Instr::Const(0, 0),
+                Instr::CmpJump(0, 0, 1, 1, 1),
+                Instr::Return(None),
+            ],
+            consts: vec![F(f64::NAN)],
+            local_count: 1,
+        }],
+        entry_point: 0,
+        stack_depth: Default::default(),
+    };
+    assert_eq!(
+        nan.eval(&mut &b""[..], &mut vec![]).unwrap_err().kind,
+        EvalErrorKind::Unordered
+    );
+}
+
+test_program! {
+    name: call_chain;
+    text: r#"
+defn f0 2 : f1 1
+  x0 := k1
+  x1 := k0
+  x0 := x1(x0)
+  x0 := x0 *# 2
+  return x0
+
+defn f1 2 : f2
+  x1 := k0
+  x0 := x1(x0)
+  x0 := x0 +# 10
+  return x0
+
+defn f2 2 : f3
+  x1 := k0
+  x0 := x1(x0)
+  x0 := x0 *# 3
+  return x0
+
+defn f3 1 :
+  x0 := x0 +# 1
+  return x0
+"#;
+    defn {
+        code: [
+            Const(0, 1),
+            Const(1, 0),
+            Call(0, 1, 0),
+            MulImm(0, 0, 2),
+            Return(Some(0)),
+        ],
+        consts: [C(1, None), I(1)],
+        local_count: 2,
+    }
+    defn {
+        code: [
+            Const(1, 0),
+            Call(0, 1, 0),
+            AddImm(0, 0, 10),
+            Return(Some(0)),
+        ],
+        consts: [C(2, None)],
+        local_count: 2,
+    }
+    defn {
+        code: [
+            Const(1, 0),
+            Call(0, 1, 0),
+            MulImm(0, 0, 3),
+            Return(Some(0)),
+        ],
+        consts: [C(3, None)],
+        local_count: 2,
+    }
+    defn {
+        code: [
+            AddImm(0, 0, 1),
+            Return(Some(0)),
+        ],
+        consts: [],
+        local_count: 1,
+    }
+    input: b"";
+    output: b"";
+    // ((1 + 1) * 3 + 10) * 2
+    result: Ok(I(32));
+}
+
+test_program! {
+    name: read_write_str;
+    text: r#"
+defn f0 2 :
+  x0 := readstr
+  x1 := readstr
+  writestr x1
+  writestr x0
+  return x0
+"#;
+    defn {
+        code: [
+            ReadStr(0),
+            ReadStr(1),
+            WriteStr(1),
+            WriteStr(0),
+            Return(Some(0)),
+        ],
+        consts: [],
+        local_count: 2,
+    }
+    input: "h\u{e9}llo\0world\0".as_bytes();
+    output: "world\0h\u{e9}llo\0".as_bytes();
+    result: Ok(S("h\u{e9}llo".to_string()));
+}
+
+#[test]
+fn test_read_str_errors() {
+    let program = read_write_str::program();
+    let eval = |input: &[u8]| {
+        program
+            .eval(&mut &input[..], &mut vec![])
+            .map_err(|e| e.kind)
+    };
+    assert_eq!(eval(b"abc\0def"), Err(EvalErrorKind::UnexpectedEof));
+    assert_eq!(eval(b"\xff\0abc\0"), Err(EvalErrorKind::Utf8Error));
+}
+
+test_program! {
+    name: str_ops;
+    text: r#"
+defn f0 6 : 2
+  x0 := readstr
+  x1 := readstr
+  x0 := x0 ++ x1
+  x1 := strlen x0
+  x2 := k0
+  x3 := stridx x0 x2
+  x4 := x0[x2]
+  x5 := x0
+  x0 := (x1; 5)
+  return x0
+"#;
+    defn {
+        code: [
+            ReadStr(0),
+            ReadStr(1),
+            StrCat(0, 0, 1),
+            StrLen(1, 0),
+            Const(2, 0),
+            StrIdx(3, 0, 2),
+            IdxTup(4, 0, 2),
+            Copy(5, 0),
+            MkTup(0, 1, 5),
+            Return(Some(0)),
+        ],
+        consts: [I(2)],
+        local_count: 6,
+    }
+    input: b"ab\0cd\0";
+    output: b"";
+    result: Ok(T(vec![I(4), I(2), I(99), I(99), S("abcd".to_string())]));
+}
+
+#[test]
+fn test_str_ops_multibyte() {
+    use self::Val::*;
+
+    let program = str_ops::program();
+    let eval = |input: &str| {
+        program
+            .eval(&mut input.as_bytes(), &mut vec![])
+            .map_err(|e| e.kind)
+    };
+    // "é" is two bytes, 0xc3 0xa9
+    assert_eq!(
+        eval("\u{e9}\0x\0"),
+        Ok(T(vec![I(3), I(2), I(0x78), I(0x78), S("\u{e9}x".to_string())]))
+    );
+    assert_eq!(
+        eval("h\0\u{e9}\0"),
+        Ok(T(vec![I(3), I(2), I(0xa9), I(0xa9), S("h\u{e9}".to_string())]))
+    );
+    assert_eq!(
+        eval("a\0\0"),
+        Err(EvalErrorKind::OutOfBounds { index: 2, len: 1 })
+    );
+}
+
+#[test]
+fn test_debug_repr() {
+    use self::Val::*;
+
+    assert_eq!(I(42).fmt_debug_repr(), "int(42)");
+    assert_eq!(B(true).fmt_debug_repr(), "bool(true)");
+    assert_eq!(F(2.5).fmt_debug_repr(), "float(2.5)");
+    assert_eq!(F(2.0).fmt_debug_repr(), "float(2.0)");
+    assert_eq!(
+        T(vec![I(1), B(false), C(2, None)]).fmt_debug_repr(),
+        "tuple[3](int(1), bool(false), fn(2))"
+    );
+    assert_eq!(T(vec![]).fmt_debug_repr(), "tuple[0]()");
+    assert_eq!(C(1, Some(2)).fmt_debug_repr(), "fn(1/2)");
+    let closure = |upvals: Vec<Val>| Closure {
+        fn_id: 3,
+        upvals: upvals.into_boxed_slice(),
+    };
+    assert_eq!(closure(vec![]).fmt_debug_repr(), "closure[0](fn(3))");
+    assert_eq!(
+        closure(vec![I(1), T(vec![F(-0.5)])]).fmt_debug_repr(),
+        "closure[2](fn(3); int(1), tuple[1](float(-0.5)))"
+    );
+    assert_eq!(
+        Ref(Arc::new(Mutex::new(S("h\u{e9}".to_string())))).fmt_debug_repr(),
+        "ref(str[3](\"h\u{e9}\"))"
+    );
+}
+
+#[test]
+fn test_poisoned_ref_display() {
+    use self::Val::*;
+
+    let cell = Arc::new(Mutex::new(I(1)));
+    let poisoner = cell.clone();
+    let _ = ::std::thread::spawn(move || {
+        let _guard = poisoner.lock().unwrap();
+        panic!("poisoning the cell");
+    }).join();
+    assert!(cell.is_poisoned());
+    assert_eq!(Ref(cell.clone()).to_string(), "ref 1");
+    assert_eq!(Ref(cell).fmt_debug_repr(), "ref(int(1))");
+}
+
+#[test]
+fn test_display_repr() {
+    use self::Val::*;
+
+    let consts = vec![
+        B(true),
+        I(-42),
+        F(3.0),
+        F(-0.5),
+        F(1e300),
+        F(f64::INFINITY),
+        F(f64::NEG_INFINITY),
+        C(3, None),
+        C(1, Some(2)),
+    ];
+    let text = format!(
+        "defn f0 1 : {}\nreturn",
+        consts
+            .iter()
+            .map(Val::fmt_display_repr)
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    let program = parse::parse(&text).unwrap();
+    assert_eq!(program.defns()[0].consts(), &consts[..]);
+    for val in &consts {
+        assert_eq!(val.fmt_display_repr(), val.to_string());
+    }
+
+    // NaN never equals itself, so check it separately
+    assert_eq!(F(f64::NAN).fmt_display_repr(), "nan");
+    let program = parse::parse("defn f0 1 : nan\nreturn").unwrap();
+    match program.defns()[0].consts()[0] {
+        F(f) => assert!(f.is_nan()),
+        ref val => panic!("expected NaN, got {}", val),
+    }
+
+    assert_eq!(
+        T(vec![F(f64::INFINITY), I(1)]).fmt_display_repr(),
+        "(inf, 1)"
+    );
+}
+
+#[test]
+fn test_float_display_round_trip() {
+    use self::parse::parse_val;
+    use self::Val::F;
+
+    let mut floats = vec![
+        0.0,
+        -0.0,
+        1.0,
+        -2.5,
+        1e300,
+        1e-300,
+        5e-324,
+        f64::MAX,
+        f64::MIN,
+        f64::MIN_POSITIVE,
+        f64::EPSILON,
+        f64::NAN,
+        f64::INFINITY,
+        f64::NEG_INFINITY,
+    ];
+    // Arbitrary bit patterns from a xorshift generator
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    for _ in 0..10_000 {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        floats.push(f64::from_bits(state));
+    }
+
+    for f in floats {
+        let text = format!("{}", F(f));
+        match parse_val(&text) {
+            Ok(F(g)) if f.is_nan() => assert!(g.is_nan(), "{} parsed as {}", text, g),
+            Ok(F(g)) => assert_eq!(f.to_bits(), g.to_bits(), "{} parsed as {}", text, g),
+            res => panic!("{} parsed as {:?}", text, res),
+        }
+    }
+    assert_eq!(format!("{}", F(f64::NEG_INFINITY)), "-inf");
+}
+
+#[test]
+fn test_instr_arity_and_purity() {
+    use self::Instr::*;
+
+    assert_eq!(Add(0, 1, 2).arity(), 3);
+    assert_eq!(FEq(0, 1, 2, 3).arity(), 4);
+    assert_eq!(Const(0, 5).arity(), 1);
+    assert_eq!(MkTup(0, 1, 3).arity(), 2);
+    assert_eq!(CallNative(0, 7, 1).arity(), 2);
+    assert_eq!(Return(Some(1)).arity(), 1);
+    assert_eq!(Return(None).arity(), 0);
+    assert_eq!(Jump(3).arity(), 0);
+
+    for instr in &[Const(0, 0), Add(0, 1, 2), Div(0, 1, 2), MkTup(0, 1, 2), StrCat(0, 1, 2)] {
+        assert!(instr.is_pure(), "{} should be pure", instr);
+    }
+    let impure = [
+        Read(0),
+        Write(0),
+        Call(0, 1, 2),
+        CallNative(0, 1, 2),
+        DebugPrint(0),
+        Load(0, 1),
+        Store(0, 1),
+        GetUpval(0, 1),
+        Jump(1),
+        Return(None),
+    ];
+    for instr in &impure {
+        assert!(!instr.is_pure(), "{} shouldn't be pure", instr);
+    }
+}
+
+#[test]
+fn test_rename_registers() {
+    use self::Instr::*;
+
+    let program = parse::parse(
+        r#"
+defn f0 3 : 1 2
+  x0 := k0
+  x1 := k1
+  x2 := x0 + x1
+  x0 := (x1; 2)
+  (x1; 2) := x0
+  cond x2 1 1
+  return x2
+"#,
+    ).unwrap();
+    let mapping = [(0, 2), (1, 0), (2, 1)].iter().cloned().collect();
+    let renamed = program.defns()[0].rename_registers(&mapping).unwrap();
+
+    assert_eq!(renamed.local_count(), 3);
+    assert_eq!(renamed.consts(), program.defns()[0].consts());
+    assert_eq!(
+        renamed.code(),
+        &[
+            Const(2, 0),
+            Const(0, 1),
+            Add(1, 2, 0),
+            MkTup(2, 0, 2),
+            UnTup(0, 2, 2),
+            CondJump(1, 1, 1),
+            Return(Some(1)),
+        ]
+    );
+
+    // Unmapped registers are left alone
+    let mapping = [(0, 4)].iter().cloned().collect();
+    let renamed = program.defns()[0].rename_registers(&mapping).unwrap();
+    assert_eq!(renamed.local_count(), 5);
+    assert_eq!(renamed.code()[2], Add(2, 4, 1));
+
+    // Swapping two registers of a tuple range would reorder its elements
+    let mapping = [(1, 2), (2, 1)].iter().cloned().collect();
+    assert_eq!(program.defns()[0].rename_registers(&mapping), None);
+    let program = parse::parse("defn f0 3 :\n  (x1; 2) := x0\n  return x1").unwrap();
+    assert_eq!(program.defns()[0].rename_registers(&mapping), None);
+
+    // Swapping registers outside any range is fine
+    let program = parse::parse(
+        r#"
+defn f0 3 : 1 2
+  x0 := k0
+  x1 := k1
+  x2 := x0 - x1
+  return x2
+"#,
+    ).unwrap();
+    let mapping = [(0, 1), (1, 0)].iter().cloned().collect();
+    let renamed = program.defns()[0].rename_registers(&mapping).unwrap();
+    assert_eq!(renamed.code(), &[Const(1, 0), Const(0, 1), Sub(2, 1, 0), Return(Some(2))]);
+    let run = |program: &Program| program.eval(&mut io::empty(), &mut io::sink());
+    let original = run(&program);
+    let mut program = program;
+    program.defns_mut()[0] = renamed;
+    assert_eq!(run(&program), original);
+}
+
+#[test]
+fn test_control_flow_graph() {
+    use self::flow::{BasicBlock, ControlFlowGraph};
+
+    let program = parse::parse(
+        r#"
+defn f0 4 : 0 1 10
+  x0 := k0
+  x1 := k1
+  x2 := k2
+  x3 := x0 < x2
+  cond x3 1 3
+  x0 := x0 + x1
+  jump -4
+  return x0
+"#,
+    ).unwrap();
+    let cfg = ControlFlowGraph::new(&program.defns()[0]);
+    assert_eq!(
+        cfg.blocks,
+        vec![
+            BasicBlock { start: 0, end: 2 },
+            BasicBlock { start: 2, end: 5 },
+            BasicBlock { start: 5, end: 7 },
+            BasicBlock { start: 7, end: 8 },
+        ]
+    );
+    assert_eq!(cfg.succs, vec![vec![1], vec![2, 3], vec![1], vec![]]);
+    assert_eq!(cfg.block_of(6), Some(2));
+
+    let loops = cfg.loops();
+    assert_eq!(loops.len(), 1);
+    assert_eq!(loops[0].header, 1);
+    assert_eq!(loops[0].blocks, vec![1, 2]);
+
+    // A jump out of dead code isn't a back edge
+    let program = parse::parse("defn f0 1 :\n  return\n  jump -1").unwrap();
+    let cfg = ControlFlowGraph::new(&program.defns()[0]);
+    assert_eq!(cfg.succs, vec![vec![], vec![0]]);
+    assert!(cfg.dominators()[1].is_empty());
+    assert_eq!(cfg.loops(), vec![]);
+}
+
+#[test]
+fn test_loop_invariant_code_motion() {
+    use self::flow::ControlFlowGraph;
+    use self::Instr::*;
+
+    let program = parse::parse(
+        r#"
+defn f0 4 : 0 1 10
+  x0 := k0
+  x2 := k2
+  x3 := x0 < x2
+  cond x3 1 4
+  x1 := k1
+  x0 := x0 + x1
+  jump -5
+  return x0
+"#,
+    ).unwrap();
+    let defn = &program.defns()[0];
+    let optimized = opt::LoopInvariantCodeMotion::apply(defn, &ControlFlowGraph::new(defn));
+    // Both loads move into a pre-header, but the comparison depends on x0
+    assert_eq!(
+        optimized.code(),
+        &[
+            Const(0, 0),
+            Const(2, 2),
+            Const(1, 1),
+            Lt(3, 0, 2),
+            CondJump(3, 1, 3),
+            Add(0, 0, 1),
+            Jump(-3),
+            Return(Some(0)),
+        ]
+    );
+    assert_eq!(optimized.code().len(), defn.code().len());
+
+    let optimized = Program {
+        defns: vec![optimized],
+        entry_point: 0,
+        stack_depth: Default::default(),
+    };
+    let run = |program: &Program, max| program.eval_steps(&mut io::empty(), &mut io::sink(), max);
+    // Six instructions per iteration before, four after
+    assert_eq!(run(&program, 65), Ok(Ok(Val::I(10))));
+    assert_eq!(run(&program, 64), Err(StepLimitExceeded { steps_taken: 64 }));
+    assert_eq!(run(&optimized, 46), Ok(Ok(Val::I(10))));
+    assert_eq!(run(&optimized, 45), Err(StepLimitExceeded { steps_taken: 45 }));
+}
+
+#[test]
+fn test_loop_invariant_code_motion_nested() {
+    use self::flow::ControlFlowGraph;
+    use self::Instr::*;
+
+    let program = parse::parse(
+        r#"
+defn f0 6 : 0 3 1
+  x0 := k0
+  x1 := k0
+  x5 := k1
+  x3 := x1 < x5
+  cond x3 1 10
+  x2 := k0
+  x3 := x2 < x5
+  cond x3 1 5
+  x4 := k2
+  x0 := x0 + x4
+  x2 := x2 + x4
+  jump -5
+  x1 := x1 +# 1
+  jump -10
+  return x0
+"#,
+    ).unwrap();
+    let defn = &program.defns()[0];
+    let optimized = opt::LoopInvariantCodeMotion::apply(defn, &ControlFlowGraph::new(defn));
+    // The load in the inner loop moves out of both loops
+    assert_eq!(
+        optimized.code(),
+        &[
+            Const(0, 0),
+            Const(1, 0),
+            Const(5, 1),
+            Const(4, 2),
+            Lt(3, 1, 5),
+            CondJump(3, 1, 9),
+            Const(2, 0),
+            Lt(3, 2, 5),
+            CondJump(3, 1, 4),
+            Add(0, 0, 4),
+            Add(2, 2, 4),
+            Jump(-4),
+            AddImm(1, 1, 1),
+            Jump(-9),
+            Return(Some(0)),
+        ]
+    );
+
+    let optimized = Program {
+        defns: vec![optimized],
+        entry_point: 0,
+        stack_depth: Default::default(),
+    };
+    let run = |program: &Program, max| program.eval_steps(&mut io::empty(), &mut io::sink(), max);
+    // Nine loads in the inner loop become one before both loops
+    assert_eq!(run(&program, 81), Ok(Ok(Val::I(9))));
+    assert_eq!(run(&optimized, 73), Ok(Ok(Val::I(9))));
+    assert_eq!(run(&optimized, 72), Err(StepLimitExceeded { steps_taken: 72 }));
+}
+
+#[test]
+fn test_pow() {
+    use self::Val::*;
+
+    let program = parse::parse(
+        r#"
+defn f0 3 :
+  (x1; 2) := x0
+  x0 := x1 ** x2
+  return x0
+"#,
+    ).unwrap();
+    assert_eq!(program.defns()[0].code()[1], Instr::Pow(0, 1, 2));
+
+    // Calls the function above with a tuple of constants
+    let pow = |b: Val, e: Val| {
+        let caller = Defn {
+            code: vec![
+                Instr::Const(0, 0),
+                Instr::Const(1, 1),
+                Instr::MkTup(0, 0, 2),
+                Instr::Const(1, 2),
+                Instr::Call(0, 1, 0),
+                Instr::Return(Some(0)),
+            ],
+            consts: vec![b, e, C(1, None)],
+            local_count: 2,
+        };
+        let program = Program {
+            defns: vec![caller, program.defns()[0].clone()],
+            entry_point: 0,
+            stack_depth: Default::default(),
+        };
+        program.eval(&mut &b""[..], &mut vec![]).map_err(|e| e.kind)
+    };
+    assert_eq!(pow(I(2), I(10)), Ok(I(1024)));
+    assert_eq!(pow(I(3), I(0)), Ok(I(1)));
+    assert_eq!(pow(I(-3), I(3)), Ok(I(-27)));
+    assert_eq!(pow(I(2), I(-1)), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(pow(I(2), I(63)), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(pow(I(2), I(62)), Ok(I(1 << 62)));
+    assert_eq!(pow(F(2.0), I(-1)), Ok(F(0.5)));
+    match pow(F(2.0), F(0.5)) {
+        Ok(F(f)) => assert!((f - 1.414).abs() < 0.001),
+        res => panic!("expected a float, got {:?}", res),
+    }
+    assert_eq!(pow(F(7.5), F(0.0)), Ok(F(1.0)));
+    assert_eq!(pow(F(0.0), I(0)), Ok(F(1.0)));
+    assert_eq!(pow(I(2), F(0.5)), Err(EvalErrorKind::TypeMismatch));
+    assert_eq!(pow(B(true), I(1)), Err(EvalErrorKind::TypeMismatch));
+}
+
+#[test]
+fn test_val_neg() {
+    use self::Val::*;
+
+    assert_eq!(-&I(5), Ok(I(-5)));
+    assert_eq!(-&I(i64::MAX), Ok(I(i64::MIN + 1)));
+    assert_eq!((-&I(i64::MIN)).map_err(|e| e.kind), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(-&F(2.5), Ok(F(-2.5)));
+    assert_eq!((-&B(true)).map_err(|e| e.kind), Err(EvalErrorKind::TypeMismatch));
+    assert_eq!((-&T(vec![])).map_err(|e| e.kind), Err(EvalErrorKind::TypeMismatch));
+}
+
+#[test]
+fn test_val_not() {
+    use self::Val::*;
+
+    assert_eq!(!&B(true), Ok(B(false)));
+    assert_eq!(!&B(false), Ok(B(true)));
+    assert_eq!(!&I(0xFF00), Ok(I(!0xFF00i64)));
+    assert_eq!((!&F(1.0)).map_err(|e| e.kind), Err(EvalErrorKind::TypeMismatch));
+}
+
+#[test]
+fn test_val_shift() {
+    use self::Val::*;
+
+    let err = |res: Result<Val, EvalError>| res.map_err(|e| e.kind);
+    assert_eq!(&I(3) << &I(4), Ok(I(48)));
+    assert_eq!(&I(1) << &I(63), Ok(I(i64::MIN)));
+    assert_eq!(&I(48) >> &I(4), Ok(I(3)));
+    // Right shifts are logical, so the sign bit isn't copied
+    assert_eq!(&I(-1) >> &I(60), Ok(I(0xf)));
+    assert_eq!(&I(-1) >> &I(0), Ok(I(-1)));
+    assert_eq!(err(&I(1) << &I(64)), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(err(&I(1) >> &I(64)), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(err(&I(1) << &I(-1)), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(err(&I(1) >> &I(-1)), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(err(&F(1.0) << &I(1)), Err(EvalErrorKind::TypeMismatch));
+    assert_eq!(err(&I(1) >> &B(true)), Err(EvalErrorKind::TypeMismatch));
+}
+
+#[test]
+fn test_casts() {
+    use self::Val::*;
+
+    let eval = |op: &str, arg: Val| {
+        let text = format!("defn f0 2 : {}\nx0 := k0\nx1 := {} x0\nreturn x1", arg, op);
+        let program = parse::parse(&text).unwrap();
+        program.eval(&mut &b""[..], &mut vec![]).map_err(|e| e.kind)
+    };
+    assert_eq!(eval("i2f", I(-3)), Ok(F(-3.0)));
+    assert_eq!(eval("f2i", F(2.9)), Ok(I(2)));
+    assert_eq!(eval("f2i", F(-2.9)), Ok(I(-2)));
+    assert_eq!(eval("f2i", F(i64::MIN as f64)), Ok(I(i64::MIN)));
+    for &i in &[0, 1, -1, 1 << 53, -(1 << 53)] {
+        assert_eq!(int_to_float(&I(i)).and_then(|f| float_to_int(&f)), Ok(I(i)));
+    }
+
+    assert_eq!(eval("f2i", F(f64::NAN)), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(eval("f2i", F(f64::INFINITY)), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(eval("f2i", F(i64::MAX as f64)), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(eval("f2i", I(1)), Err(EvalErrorKind::TypeMismatch));
+    assert_eq!(eval("i2f", F(1.0)), Err(EvalErrorKind::TypeMismatch));
+
+    assert_eq!(eval("b2i", B(true)), Ok(I(1)));
+    assert_eq!(eval("b2i", B(false)), Ok(I(0)));
+    assert_eq!(eval("i2b", I(0)), Ok(B(false)));
+    assert_eq!(eval("i2b", I(-7)), Ok(B(true)));
+    assert_eq!(eval("b2i", I(1)), Err(EvalErrorKind::TypeMismatch));
+    assert_eq!(eval("i2b", B(true)), Err(EvalErrorKind::TypeMismatch));
+    assert_eq!(eval("i2b", F(1.0)), Err(EvalErrorKind::TypeMismatch));
+}
+
+#[test]
+fn test_tup_len() {
+    use self::Instr::*;
+    use self::Val::*;
+
+    let eval = |arg| {
+        let program = Program {
+            defns: vec![Defn {
+                code: vec![Const(0, 0), TupLen(0, 0), Return(Some(0))],
+                consts: vec![arg],
+                local_count: 1,
+            }],
+            entry_point: 0,
+            stack_depth: Default::default(),
+        };
+        program.eval(&mut &b""[..], &mut vec![]).map_err(|e| e.kind)
+    };
+    assert_eq!(eval(T(vec![])), Ok(I(0)));
+    assert_eq!(eval(T(vec![B(true)])), Ok(I(1)));
+    assert_eq!(eval(T(vec![I(1), T(vec![I(2), I(3)]), F(4.0)])), Ok(I(3)));
+    assert_eq!(eval(S("abc".to_string())), Err(EvalErrorKind::TypeMismatch));
+
+    assert_eq!(
+        parse::parse("defn f0 2 :\n  x0 := len x1\n  return x0")
+            .unwrap()
+            .defns()[0]
+            .code()[0],
+        TupLen(0, 1)
+    );
+}
+
+#[test]
+fn test_concat() {
+    use self::Instr::*;
+    use self::Val::*;
+
+    let eval = |b, c| {
+        let program = Program {
+            defns: vec![Defn {
+                code: vec![Const(0, 0), Const(1, 1), Concat(0, 0, 1), Return(Some(0))],
+                consts: vec![b, c],
+                local_count: 2,
+            }],
+            entry_point: 0,
+            stack_depth: Default::default(),
+        };
+        program.eval(&mut &b""[..], &mut vec![]).map_err(|e| e.kind)
+    };
+    assert_eq!(eval(T(vec![]), T(vec![I(1)])), Ok(T(vec![I(1)])));
+    assert_eq!(eval(T(vec![I(1)]), T(vec![])), Ok(T(vec![I(1)])));
+    assert_eq!(
+        eval(T(vec![I(1), I(2)]), T(vec![B(true)])),
+        Ok(T(vec![I(1), I(2), B(true)]))
+    );
+    assert_eq!(eval(T(vec![]), I(1)), Err(EvalErrorKind::TypeMismatch));
+    assert_eq!(
+        eval(S("a".to_string()), S("b".to_string())),
+        Err(EvalErrorKind::TypeMismatch)
+    );
+
+    let program = parse::parse("defn f0 3 :\n  x0 := concat x1 x2\n  return x0").unwrap();
+    assert_eq!(program.defns()[0].code()[0], Concat(0, 1, 2));
+}
+
+#[test]
+fn test_slice() {
+    use self::Instr::*;
+    use self::Val::*;
+
+    let eval = |t, lo, hi| {
+        let program = Program {
+            defns: vec![Defn {
+                code: vec![
+                    Const(0, 0),
+                    Const(1, 1),
+                    Const(2, 2),
+                    Slice(0, 0, 1, 2),
+                    Return(Some(0)),
+                ],
+                consts: vec![t, lo, hi],
+                local_count: 3,
+            }],
+            entry_point: 0,
+            stack_depth: Default::default(),
+        };
+        program.eval(&mut &b""[..], &mut vec![]).map_err(|e| e.kind)
+    };
+    let tup = || T(vec![I(10), I(11), I(12), I(13)]);
+    assert_eq!(eval(tup(), I(1), I(3)), Ok(T(vec![I(11), I(12)])));
+    assert_eq!(eval(tup(), I(0), I(4)), Ok(tup()));
+    assert_eq!(eval(tup(), I(2), I(2)), Ok(T(vec![])));
+    assert_eq!(
+        eval(tup(), I(3), I(2)),
+        Err(EvalErrorKind::OutOfBounds { index: 3, len: 4 })
+    );
+    assert_eq!(
+        eval(tup(), I(1), I(5)),
+        Err(EvalErrorKind::OutOfBounds { index: 5, len: 4 })
+    );
+    assert_eq!(
+        eval(tup(), I(-1), I(2)),
+        Err(EvalErrorKind::OutOfBounds { index: -1, len: 4 })
+    );
+    assert_eq!(
+        eval(S("abc".to_string()), I(0), I(1)),
+        Err(EvalErrorKind::TypeMismatch)
+    );
+    assert_eq!(eval(tup(), F(0.0), I(1)), Err(EvalErrorKind::TypeMismatch));
+
+    let program = parse::parse("defn f0 4 :\n  x0 := x1[x2..x3]\n  return x0").unwrap();
+    assert_eq!(program.defns()[0].code()[0], Slice(0, 1, 2, 3));
+    assert_eq!(program.defns()[0].code()[0].to_string(), "x0 := x1[x2..x3]");
+}
+
+#[test]
+fn test_abs() {
+    use self::Val::*;
+
+    let eval = |arg| {
+        let program = parse::parse("defn f0 1 :\n  x0 := abs x0\n  return x0").unwrap();
+        program.eval_with_args(arg, &mut io::empty(), &mut io::sink()).map_err(|e| e.kind)
+    };
+    assert_eq!(eval(I(5)), Ok(I(5)));
+    assert_eq!(eval(I(-5)), Ok(I(5)));
+    assert_eq!(eval(I(0)), Ok(I(0)));
+    assert_eq!(eval(I(i64::MAX)), Ok(I(i64::MAX)));
+    assert_eq!(eval(I(i64::MIN)), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(eval(F(-2.5)), Ok(F(2.5)));
+    assert_eq!(eval(F(-0.0)), Ok(F(0.0)));
+    assert_eq!(eval(B(true)), Err(EvalErrorKind::TypeMismatch));
+    assert_eq!(eval(T(vec![I(-1)])), Err(EvalErrorKind::TypeMismatch));
+}
+
+#[test]
+fn test_float_math() {
+    use self::Val::*;
+
+    let eval = |op: &str, arg| {
+        let text = format!("defn f0 1 :\n  x0 := {} x0\n  return x0", op);
+        let program = parse::parse(&text).unwrap();
+        program.eval_with_args(arg, &mut io::empty(), &mut io::sink()).map_err(|e| e.kind)
+    };
+    assert_eq!(eval("sqrt", F(6.25)), Ok(F(2.5)));
+    assert_eq!(eval("sqrt", F(0.0)), Ok(F(0.0)));
+    assert_eq!(eval("sqrt", F(-1.0)), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(eval("floor", F(-1.5)), Ok(F(-2.0)));
+    assert_eq!(eval("floor", F(1.5)), Ok(F(1.0)));
+    assert_eq!(eval("ceil", F(-1.5)), Ok(F(-1.0)));
+    assert_eq!(eval("ceil", F(1.5)), Ok(F(2.0)));
+    assert_eq!(eval("round", F(2.5)), Ok(F(3.0)));
+    assert_eq!(eval("round", F(-2.5)), Ok(F(-3.0)));
+    assert_eq!(eval("round", F(2.4)), Ok(F(2.0)));
+    for op in &["sqrt", "floor", "ceil", "round"] {
+        assert_eq!(eval(op, I(4)), Err(EvalErrorKind::TypeMismatch));
+    }
+}
+
+#[test]
+fn test_min_max() {
+    use self::Instr::*;
+    use self::Val::*;
+
+    let eval = |instr, b, c| {
+        let program = Program {
+            defns: vec![Defn {
+                code: vec![Const(0, 0), Const(1, 1), instr, Return(Some(0))],
+                consts: vec![b, c],
+                local_count: 2,
+            }],
+            entry_point: 0,
+            stack_depth: Default::default(),
+        };
+        program.eval(&mut &b""[..], &mut vec![]).map_err(|e| e.kind)
+    };
+    assert_eq!(eval(Min(0, 0, 1), I(3), I(-4)), Ok(I(-4)));
+    assert_eq!(eval(Max(0, 0, 1), I(3), I(-4)), Ok(I(3)));
+    assert_eq!(eval(Min(0, 0, 1), I(7), I(7)), Ok(I(7)));
+    assert_eq!(eval(Max(0, 0, 1), I(7), I(7)), Ok(I(7)));
+    assert_eq!(eval(Min(0, 0, 1), F(1.5), F(2.5)), Ok(F(1.5)));
+    assert_eq!(eval(Max(0, 0, 1), F(1.5), F(2.5)), Ok(F(2.5)));
+    assert_eq!(eval(Max(0, 0, 1), F(-0.5), F(-0.5)), Ok(F(-0.5)));
+    assert_eq!(eval(Min(0, 0, 1), F(f64::NAN), F(1.0)), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(eval(Max(0, 0, 1), F(1.0), F(f64::NAN)), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(eval(Min(0, 0, 1), I(1), F(1.0)), Err(EvalErrorKind::TypeMismatch));
+    assert_eq!(eval(Max(0, 0, 1), B(true), B(false)), Err(EvalErrorKind::TypeMismatch));
+
+    let program = parse::parse("defn f0 3 :\n  x0 := min x1 x2\n  x0 := max x0 x2\n  return x0");
+    let code = program.unwrap().defns()[0].code().to_vec();
+    assert_eq!(code[..2], [Min(0, 1, 2), Max(0, 0, 2)]);
+    assert_eq!(code[0].to_string(), "x0 := min x1 x2");
+    assert_eq!(code[1].to_string(), "x0 := max x0 x2");
+}
+
+#[test]
+fn test_wrapping_arith() {
+    use self::Val::*;
+
+    let eval = |op: &str, b, c| {
+        let text = format!("defn f0 2 : {}\n  x1 := k0\n  x0 := x0 {} x1\n  return x0", c, op);
+        let program = parse::parse(&text).unwrap();
+        program.eval_with_args(b, &mut io::empty(), &mut io::sink()).map_err(|e| e.kind)
+    };
+    assert_eq!(eval("+%", I(i64::MAX), 1), Ok(I(i64::MIN)));
+    assert_eq!(eval("+", I(i64::MAX), 1), Err(EvalErrorKind::Arithmetic));
+    assert_eq!(eval("-%", I(i64::MIN), 1), Ok(I(i64::MAX)));
+    assert_eq!(eval("*%", I(i64::MAX), 2), Ok(I(-2)));
+    assert_eq!(eval("+%", I(2), 3), Ok(I(5)));
+    assert_eq!(eval("-%", I(2), 3), Ok(I(-1)));
+    assert_eq!(eval("*%", I(2), 3), Ok(I(6)));
+    for op in &["+%", "-%", "*%"] {
+        assert_eq!(eval(op, F(1.0), 1), Err(EvalErrorKind::TypeMismatch));
+        assert_eq!(eval(op, B(true), 1), Err(EvalErrorKind::TypeMismatch));
+    }
+
+    let program = parse::parse("defn f0 3 :\n  x0 := x1 -% x2\n  return x0").unwrap();
+    assert_eq!(program.defns()[0].code()[0], Instr::WSub(0, 1, 2));
+    assert_eq!(program.defns()[0].code()[0].to_string(), "x0 := x1 -% x2");
+}
+
+#[test]
+fn test_tup_set() {
+    use self::Instr::*;
+    use self::Val::*;
+
+    let eval = |tup, idx, val| {
+        let program = Program {
+            defns: vec![Defn {
+                code: vec![Const(0, 0), Const(1, 1), Const(2, 2), TupSet(0, 1, 2), Return(Some(0))],
+                consts: vec![tup, idx, val],
+                local_count: 3,
+            }],
+            entry_point: 0,
+            stack_depth: Default::default(),
+        };
+        program.eval(&mut &b""[..], &mut vec![]).map_err(|e| e.kind)
+    };
+    let tup = || T(vec![I(1), I(2), I(3)]);
+    assert_eq!(eval(tup(), I(0), B(true)), Ok(T(vec![B(true), I(2), I(3)])));
+    assert_eq!(eval(tup(), I(2), tup()), Ok(T(vec![I(1), I(2), tup()])));
+    assert_eq!(
+        eval(tup(), I(3), I(0)),
+        Err(EvalErrorKind::OutOfBounds { index: 3, len: 3 })
+    );
+    assert_eq!(
+        eval(tup(), I(-1), I(0)),
+        Err(EvalErrorKind::OutOfBounds { index: -1, len: 3 })
+    );
+    assert_eq!(eval(I(5), I(0), I(0)), Err(EvalErrorKind::TypeMismatch));
+    assert_eq!(eval(tup(), F(0.0), I(0)), Err(EvalErrorKind::TypeMismatch));
+
+    let program = parse::parse("defn f0 3 :\n  x0[x1] := x2\n  return x0").unwrap();
+    assert_eq!(program.defns()[0].code()[0], TupSet(0, 1, 2));
+    assert_eq!(program.defns()[0].code()[0].to_string(), "x0[x1] := x2");
+}
+
+#[test]
+fn test_tup_push_pop() {
+    use self::Val::*;
+
+    // Pushes 0, 1, and 2 onto an empty tuple, saves a copy, then pops them back off
+    let text = r#"
+defn f0 5 : 0 3
+  x0 := (x0; 0)
+  x1 := k0
+  x2 := k1
+  push x0 x1
+  x1 := x1 +# 1
+  x3 := x1 < x2
+  cond x3 -3 1
+  x4 := x0
+  x3 := pop x0
+  x2 := pop x0
+  x1 := pop x0
+  x0 := (x0; 5)
+  return x0
+"#;
+    let program = parse::parse(text).unwrap();
+    assert_eq!(
+        program.eval(&mut &b""[..], &mut vec![]),
+        Ok(T(vec![T(vec![]), I(0), I(1), I(2), T(vec![I(0), I(1), I(2)])]))
+    );
+
+    let eval = |text: &str, arg| {
+        let program = parse::parse(text).unwrap();
+        program.eval_with_args(arg, &mut io::empty(), &mut io::sink()).map_err(|e| e.kind)
+    };
+    let pop = "defn f0 1 :\n  x0 := pop x0\n  return x0";
+    assert_eq!(eval(pop, T(vec![I(1), I(2)])), Ok(I(2)));
+    assert_eq!(
+        eval(pop, T(vec![])),
+        Err(EvalErrorKind::OutOfBounds { index: -1, len: 0 })
+    );
+    assert_eq!(eval(pop, I(1)), Err(EvalErrorKind::TypeMismatch));
+    let push = "defn f0 1 :\n  push x0 x0\n  return x0";
+    assert_eq!(eval(push, T(vec![I(1)])), Ok(T(vec![I(1), T(vec![I(1)])])));
+    assert_eq!(eval(push, I(1)), Err(EvalErrorKind::TypeMismatch));
+}
+
+#[test]
+fn test_null() {
+    use self::parse::parse_val;
+    use self::Val::*;
+
+    let unit = parse::parse("defn f0 1 :\n  return").unwrap();
+    let null = parse::parse("defn f0 1 : null\n  x0 := k0\n  return x0").unwrap();
+    assert_eq!(unit.eval(&mut &b""[..], &mut vec![]), Ok(T(vec![])));
+    assert_eq!(null.eval(&mut &b""[..], &mut vec![]), Ok(Null));
+    assert_ne!(Null, T(vec![]));
+    assert_eq!(format!("{}", Null), "null");
+    assert_eq!(parse_val("null"), Ok(Null));
+    assert_eq!(Null.fmt_debug_repr(), "null");
+
+    let eval = |op: &str, arg| {
+        let text = format!("defn f0 2 : null\n  x1 := k0\n  x0 := x0 {} x1\n  return x0", op);
+        let program = parse::parse(&text).unwrap();
+        program.eval_with_args(arg, &mut io::empty(), &mut io::sink()).map_err(|e| e.kind)
+    };
+    let ops = [
+        "+", "-", "*", "/", "%", "**", "&", "|", "^", "<<", "<", ">", "<=", ">=", "==", "!=",
+    ];
+    for op in &ops {
+        assert_eq!(eval(op, I(1)), Err(EvalErrorKind::TypeMismatch), "{}", op);
+        assert_eq!(eval(op, Null), Err(EvalErrorKind::TypeMismatch), "{}", op);
+    }
+
+    // Folding leaves the comparison for eval to reject
+    let text = "defn f0 2 : null\n  x0 := k0\n  x1 := x0 == x0\n  return x1";
+    let program = parse::parse(text).unwrap();
+    let folded = opt::ConstantFolding::apply(&program.defns()[0], None);
+    assert_eq!(folded.code(), program.defns()[0].code());
+}
+
+#[test]
+fn test_val_conversions() {
+    use std::convert::{TryFrom, TryInto};
+    use self::Val::*;
+
+    assert_eq!(Val::from(42i64), I(42));
+    assert_eq!(Val::from(-1.5), F(-1.5));
+    assert_eq!(Val::from(true), B(true));
+    assert_eq!(Val::from(vec![I(1), B(false)]), T(vec![I(1), B(false)]));
+    let val: Val = 7i64.into();
+    assert_eq!(val, I(7));
+
+    assert_eq!(i64::try_from(I(42)), Ok(42));
+    assert_eq!(f64::try_from(F(-1.5)), Ok(-1.5));
+    assert_eq!(bool::try_from(B(true)), Ok(true));
+    assert_eq!(Vec::<Val>::try_from(T(vec![I(1)])), Ok(vec![I(1)]));
+    let x: Result<i64, _> = Val::from(3i64).try_into();
+    assert_eq!(x, Ok(3));
+
+    let err = |expected, found| ValTypeError { expected, found };
+    assert_eq!(i64::try_from(F(1.0)).unwrap_err(), err("int", F(1.0)));
+    assert_eq!(f64::try_from(I(1)).unwrap_err(), err("float", I(1)));
+    assert_eq!(bool::try_from(Null).unwrap_err(), err("bool", Null));
+    assert_eq!(
+        Vec::<Val>::try_from(S("ab".to_string())).unwrap_err(),
+        err("tuple", S("ab".to_string()))
+    );
+}
+
+#[test]
+fn test_val_rem() {
+    use self::Val::*;
+
+    assert_eq!(&F(7.0) % &F(3.0), Ok(F(1.0)));
+    assert_eq!(&F(-7.5) % &F(2.0), Ok(F(-1.5)));
+    match &F(7.0) % &F(0.0) {
+        Ok(F(f)) => assert!(f.is_nan()),
+        res => panic!("expected NaN, got {:?}", res),
+    }
+    assert_eq!(&I(7) % &I(3), Ok(I(1)));
+    assert_eq!(
+        (&I(7) % &I(0)).map_err(|e| e.kind),
+        Err(EvalErrorKind::Arithmetic)
+    );
+}
+
+#[test]
+fn test_specialize() {
+    use self::Instr::*;
+
+    let text = r#"
+defn f0 2 : f1 5
+  x0 := k1
+  x1 := k0
+  x0 := x1(x0)
+  return x0
+
+defn f1 3 :
+  x1 := read
+  x1 := x1 + x0
+  x1 := x1 + x0
+  x1 := x1 + x0
+  x2 := x0 * x0
+  x1 := x1 * x2
+  return x1
+"#;
+    let program = parse::parse(text).unwrap();
+    let specialized = program.specialize(1, Val::I(5)).unwrap();
+    assert_eq!(
+        specialized.defns()[1].code(),
+        &[
+            Read(1),
+            AddImm(1, 1, 5),
+            AddImm(1, 1, 5),
+            AddImm(1, 1, 5),
+            Const(2, 0),
+            MulImm(1, 1, 25),
+            Return(Some(1)),
+        ]
+    );
+    assert_eq!(specialized.defns()[1].consts(), &[Val::I(25)]);
+    assert_eq!(specialized.defns()[0], program.defns()[0]);
+
+    let run = |program: &Program| program.eval(&mut io::Cursor::new(vec![2]), &mut io::sink());
+    assert_eq!(run(&specialized), run(&program));
+    assert_eq!(run(&specialized), Ok(Val::I(425)));
+
+    assert!(program.specialize(2, Val::I(5)).is_none());
+}
+
+#[test]
+fn test_constant_folding_pool_consts() {
+    use self::Instr::*;
+
+    let text = r#"
+defn f0 4 : 2 3
+  x1 := k0
+  x2 := k1
+  x3 := x1 + x2
+  x3 := x3 * x1
+  return x3
+"#;
+    let mut program = parse::parse(text).unwrap();
+    let folded = opt::ConstantFolding::apply(&program.defns()[0], None);
+    assert_eq!(
+        folded.code(),
+        &[Const(1, 0), Const(2, 1), Const(3, 2), Const(3, 3), Return(Some(3))]
+    );
+    assert_eq!(folded.consts(), &[Val::I(2), Val::I(3), Val::I(5), Val::I(10)]);
+
+    program.defns_mut()[0] = folded;
+    assert_eq!(program.eval(&mut io::empty(), &mut io::sink()), Ok(Val::I(10)));
+
+    // A tuple running past the last register is left for eval to reject
+    let program = parse::parse("defn f0 255 :\n  x0 := (x200; 100)\n  return x0").unwrap();
+    let folded = opt::ConstantFolding::apply(&program.defns()[0], None);
+    assert_eq!(folded.code(), program.defns()[0].code());
+}
+
+#[test]
+fn test_untup_syntax() {
+    use self::Instr::*;
+
+    let program = parse::parse("defn f0 3 :\n  (x0; 2) := x1\n  (x1;3):=x0\n  return").unwrap();
+    let code = program.defns()[0].code();
+    assert_eq!(code, &[UnTup(0, 2, 1), UnTup(1, 3, 0), Return(None)]);
+    assert_eq!(format!("{}", code[0]), "(x0; 2) := x1");
+
+    // Display writes the same syntax the parser reads
+    let text = format!("defn f0 3 :\n  {}\n  return", code[1]);
+    assert_eq!(parse::parse(&text).unwrap().defns()[0].code()[0], code[1]);
+
+    assert!(parse::parse("defn f0 3 :\n  (x0; 2) := k1\n  return").is_err());
+}
+
+#[test]
+fn test_parse_string_consts() {
+    use self::Val::*;
+
+    let program = parse::parse("defn f0 1 : 1 \"two words\" \"tab\\there\" 3\n  return").unwrap();
+    assert_eq!(
+        program.defns()[0].consts(),
+        &[
+            I(1),
+            S("two words".to_string()),
+            S("tab\there".to_string()),
+            I(3),
+        ]
+    );
+    assert!(parse::parse("defn f0 1 : \"open\n  return").is_err());
+}
+
+#[test]
+fn test_string_display_round_trip() {
+    use self::parse::parse_val;
+    use self::Val::S;
+
+    let cases = [
+        ("", r#""""#),
+        ("two words", r#""two words""#),
+        ("quote \" and \\", r#""quote \" and \\""#),
+        ("a\tb\nc\r\0", r#""a\tb\nc\r\0""#),
+        ("bell \u{7} del \u{7f}", r#""bell \x07 del \x7f""#),
+        ("h\u{e9}llo", "\"h\u{e9}llo\""),
+    ];
+    for &(s, text) in &cases {
+        let val = S(s.to_string());
+        assert_eq!(format!("{}", val), text);
+        assert_eq!(parse_val(text), Ok(val));
+    }
+    assert!(parse_val(r#""unclosed"#).is_err());
+    assert!(parse_val(r#""a" "b""#).is_err());
+}
+
+#[test]
+fn test_string_compare() {
+    use self::Val::*;
+
+    let eval = |op: &str, b: &str, c: &str| {
+        let text = format!(
+            "defn f0 2 : {:?} {:?}\n  x0 := k0\n  x1 := k1\n  x0 := x0 {} x1\n  return x0",
+            b, c, op
+        );
+        let program = parse::parse(&text).unwrap();
+        program.eval(&mut &b""[..], &mut vec![]).map_err(|e| e.kind)
+    };
+    assert_eq!(eval("<", "abc", "abd"), Ok(B(true)));
+    assert_eq!(eval("<", "ab", "abc"), Ok(B(true)));
+    assert_eq!(eval(">", "b", "abc"), Ok(B(true)));
+    assert_eq!(eval("<=", "abc", "abc"), Ok(B(true)));
+    assert_eq!(eval(">=", "abc", "abd"), Ok(B(false)));
+    assert_eq!(eval("==", "abc", "abc"), Ok(B(true)));
+    assert_eq!(eval("!=", "abc", "ABC"), Ok(B(true)));
+    assert!(S("a".to_string()) < S("b".to_string()));
+    assert!(I(1) < S(String::new()));
+
+    for op in &["+", "-", "*", "/", "%", "**", "&", "|", "^", "<<", "+%"] {
+        assert_eq!(eval(op, "a", "b"), Err(EvalErrorKind::TypeMismatch), "{}", op);
+    }
+}
+
+#[test]
+fn test_eval_steps() {
+    let program = parse::parse(
+        r#"
+defn f0 3 : 0 1000
+  x0 := k0
+  x1 := k1
+  x2 := x0 < x1
+  cond x2 1 3
+  x0 := x0 +# 1
+  jump -3
+  return x0
+"#,
+    ).unwrap();
+    let run = |max| program.eval_steps(&mut io::empty(), &mut io::sink(), max);
+
+    assert_eq!(run(500), Err(StepLimitExceeded { steps_taken: 500 }));
+    // Two loads, four instructions per iteration, then the final check and return
+    assert_eq!(run(4005), Ok(Ok(Val::I(1000))));
+    assert_eq!(run(4004), Err(StepLimitExceeded { steps_taken: 4004 }));
+
+    let program = parse::parse("defn f0 1 :\n  x0 := x0 / x0\n  return x0").unwrap();
+    assert_eq!(
+        program.eval_steps(&mut io::empty(), &mut io::sink(), 10),
+        Ok(Err(EvalErrorKind::Arithmetic.into()))
+    );
+}
+
+test_program! {
+    name: inspect;
+    text: r#"
+defn f0 16 : true 1 2.5 f0 "s"
+  x0 := k0
+  x1 := k1
+  x2 := k2
+  x3 := (x0; 0)
+  x4 := k3
+  x5 := closure x4 x3
+  x6 := k4
+  alloc x6
+  x7 := k4
+  x8 := inspect x0
+  x9 := inspect x1
+  x10 := inspect x2
+  x11 := inspect x3
+  x12 := inspect x4
+  x13 := inspect x5
+  x14 := inspect x6
+  x15 := inspect x7
+  x0 := (x8; 8)
+  return x0
+"#;
+    defn {
+        code: [
+            Const(0, 0),
+            Const(1, 1),
+            Const(2, 2),
+            MkTup(3, 0, 0),
+            Const(4, 3),
+            MkClosure(5, 4, 3),
+            Const(6, 4),
+            Alloc(6),
+            Const(7, 4),
+            Inspect(8, 0),
+            Inspect(9, 1),
+            Inspect(10, 2),
+            Inspect(11, 3),
+            Inspect(12, 4),
+            Inspect(13, 5),
+            Inspect(14, 6),
+            Inspect(15, 7),
+            MkTup(0, 8, 8),
+            Return(Some(0)),
+        ],
+        consts: [B(true), I(1), F(2.5), C(0, None), S("s".to_string())],
+        local_count: 16,
+    }
+    input: b"";
+    output: b"";
+    result: Ok(T((0..8).map(I).collect()));
+}
+
+#[test]
+fn test_defn_header_errors() {
+    use parse_util::ParseError;
+
+    let error = |text| parse::parse(text).unwrap_err();
+    assert_eq!(
+        error("defn f0"),
+        ParseError::expected("token separator", 1, 5)
+    );
+    assert_eq!(
+        error("defn f0 2"),
+        ParseError::expected("token separator", 1, 8)
+    );
+    assert_eq!(
+        error("defn foo 2 :"),
+        ParseError::expected("function id like 'f0', got 'foo'", 1, 5)
+    );
+    assert_eq!(
+        error("defn 0 2 :"),
+        ParseError::expected("function id like 'f0', got '0'", 1, 5)
+    );
+    assert_eq!(
+        error("defn f0 1 :\n  return\ndefn f5 1 :"),
+        ParseError::expected("function ids must be sequential: expected f1, got f5", 3, 5)
+    );
+    assert_eq!(
+        error("defn f0 -1 :"),
+        ParseError::expected(format!("local count from 0 to {}, got '-1'", AddrSize::MAX), 1, 8)
+    );
+    assert_eq!(
+        error("defn f0 2 1 2"),
+        ParseError::expected("\":\"", 1, 10)
+    );
+    assert_eq!(
+        error("defn f0 2 : 1 2x 3"),
+        ParseError::expected("a constant, got '2x'", 1, 14)
+    );
+}
+
+#[test]
+fn test_bool_xor() {
+    use self::Val::*;
+
+    assert_eq!(&B(true) ^ &B(false), Ok(B(true)));
+    assert_eq!(&B(false) ^ &B(true), Ok(B(true)));
+    assert_eq!(&B(true) ^ &B(true), Ok(B(false)));
+    assert_eq!(&B(false) ^ &B(false), Ok(B(false)));
+    assert_eq!(
+        (&B(true) ^ &I(1)).map_err(|e| e.kind),
+        Err(EvalErrorKind::TypeMismatch)
+    );
+}
+
+#[test]
+fn test_split_at() {
+    use self::Instr::*;
+
+    let program = arith::program();
+    let defn = &program.defns()[0];
+    assert_eq!(defn.split_at(0), None);
+    assert_eq!(defn.split_at(defn.code().len()), None);
+
+    let (first, second) = defn.split_at(4).unwrap();
+    assert_eq!(
+        first.code(),
+        &[Const(0, 0), Const(1, 1), Add(0, 0, 1), Mul(0, 0, 0), Return(None)]
+    );
+    assert_eq!(second.code(), &defn.code()[4..]);
+    assert_eq!(first.local_count(), 3);
+    assert_eq!(second.local_count(), 3);
+
+    let eval = |program: Program| program.eval(&mut io::empty(), &mut io::sink());
+    assert_eq!(
+        eval(Program {
+            defns: vec![first],
+            entry_point: 0,
+            stack_depth: Default::default(),
+        }),
+        Ok(Val::T(vec![]))
+    );
+
+    // The second half picks up with (1 + 2) * (1 + 2) passed in as x0
+    let caller = parse::parse(
+        r#"
+defn f0 2 : f1 9
+  x0 := k1
+  x1 := k0
+  x0 := x1(x0)
+  return x0
+"#,
+    ).unwrap()
+        .defns()[0]
+        .clone();
+    assert_eq!(
+        eval(Program {
+            defns: vec![caller, second],
+            entry_point: 0,
+            stack_depth: Default::default(),
+        }),
+        Ok(Val::I(15 / (9 % 7)))
+    );
+}
+
+#[test]
+fn test_inline_function() {
+    use self::opt::InlineError;
+    use self::Instr::*;
+    use self::Val::*;
+
+    let program = parse::parse(
+        r#"
+defn f0 3 : f1 5 -5
+  x1 := k0
+  x2 := k1
+  x2 := x1(x2)
+  x0 := k2
+  x0 := x1(x0)
+  x0 := x0 + x2
+  return x0
+
+defn f1 2 : 0
+  x1 := k0
+  x1 := x0 < x1
+  cond x1 2 1
+  return x0
+  x0 := -x0
+  return x0
+"#,
+    ).unwrap();
+    let eval = |program: &Program| program.eval(&mut io::empty(), &mut io::sink());
+
+    let inlined = program.inline_function(0, 2).unwrap();
+    let defn = &inlined.defns()[0];
+    assert_eq!(
+        defn.code(),
+        &[
+            Const(1, 0),
+            Const(2, 1),
+            // The body of f1, with x0 and x1 moved to x3 and x4
+            Copy(3, 2),
+            Const(4, 3),
+            Lt(4, 3, 4),
+            CondJump(4, 3, 1),
+            Copy(2, 3),
+            Jump(3),
+            Neg(3, 3),
+            Copy(2, 3),
+            Const(0, 2),
+            Call(0, 1, 0),
+            Add(0, 0, 2),
+            Return(Some(0)),
+        ]
+    );
+    assert_eq!(defn.consts(), &[C(1, None), I(5), I(-5), I(0)]);
+    assert_eq!(defn.local_count(), 5);
+    assert_eq!(eval(&inlined), Ok(I(10)));
+
+    // Inlining the second call too leaves no calls behind
+    let inlined = inlined.inline_function(0, 11).unwrap();
+    assert!(!inlined.defns()[0]
+        .code()
+        .iter()
+        .any(|instr| matches!(*instr, Call(..))));
+    assert_eq!(inlined.validate(), vec![]);
+    assert_eq!(eval(&inlined), eval(&program));
+
+    assert_eq!(program.inline_function(0, 0), Err(InlineError::NotACall));
+    assert_eq!(program.inline_function(2, 0), Err(InlineError::NoSuchFunction(2)));
+
+    let error = |text, iptr| parse::parse(text).unwrap().inline_function(0, iptr);
+    assert_eq!(
+        error("defn f0 2 : f0\n  x1 := k0\n  x0 := x1(x0)\n  return x0", 1),
+        Err(InlineError::Recursive(0))
+    );
+    assert_eq!(
+        error("defn f0 2 : f1\n  x1 := k0\n  x1 := x0\n  x0 := x1(x0)\n  return x0", 2),
+        Err(InlineError::UnknownCallee)
+    );
+    assert_eq!(
+        error(
+            "defn f0 2 : f1/1\n  x1 := k0\n  x0 := x1(x0)\n  return x0\ndefn f1 1 :\n  return x0",
+            1
+        ),
+        Err(InlineError::Unsupported)
+    );
+}
+
+#[test]
+fn test_val_total_order() {
+    use self::Val::*;
+
+    let mut vals = vec![I(5), B(false), F(f64::NAN), T(vec![]), C(0, None)];
+    vals.sort();
+    assert_eq!(vals, vec![B(false), I(5), F(f64::NAN), T(vec![]), C(0, None)]);
+
+    let mut floats = vec![F(f64::NAN), F(1.0), F(f64::INFINITY), F(-2.0), F(f64::NAN)];
+    floats.sort();
+    assert_eq!(
+        floats,
+        vec![F(-2.0), F(1.0), F(f64::INFINITY), F(f64::NAN), F(f64::NAN)]
+    );
+
+    let mut vals = vec![
+        S("b".to_string()),
+        T(vec![I(1), I(2)]),
+        S("a".to_string()),
+        T(vec![I(1)]),
+        C(1, Some(2)),
+        C(1, None),
+        I(-1),
+    ];
+    vals.sort();
+    assert_eq!(
+        vals,
+        vec![
+            I(-1),
+            T(vec![I(1)]),
+            T(vec![I(1), I(2)]),
+            C(1, None),
+            C(1, Some(2)),
+            S("a".to_string()),
+            S("b".to_string()),
+        ]
+    );
+
+    // Comparison instructions still only order values of the same type
+    let program = parse::parse(
+        r#"
+defn f0 4 : 1 2.5
+  x0 := k0
+  x1 := k1
+  x2 := x0 < x1
+  x3 := x1 < x0
+  x0 := (x2; 2)
+  return x0
+"#,
+    ).unwrap();
+    assert_eq!(
+        program.eval(&mut io::empty(), &mut io::sink()),
+        Ok(T(vec![B(false), B(false)]))
+    );
+}
+
+#[test]
+fn test_eval_with_args() {
+    use self::Val::*;
+
+    let program = call_return::program();
+    let f1 = Program {
+        defns: program.defns().to_vec(),
+        entry_point: 1,
+        stack_depth: Default::default(),
+    };
+    let eval = |args| f1.eval_with_args(args, &mut io::empty(), &mut io::sink());
+    assert_eq!(eval(T(vec![I(42), I(69)])), Ok(I(42 + 69)));
+    assert_eq!(eval(T(vec![I(-1), I(1), I(5)])), Ok(I(0)));
+    assert_eq!(
+        eval(I(0)).map_err(|e| e.kind),
+        Err(EvalErrorKind::TypeMismatch)
+    );
+
+    // f0 overwrites x0 before reading it
+    assert_eq!(
+        program.eval_with_args(B(true), &mut io::empty(), &mut io::sink()),
+        program.eval(&mut io::empty(), &mut io::sink())
+    );
+}
+
+#[test]
+#[cfg(feature = "transcendentals")]
+fn test_transcendentals() {
+    use std::f64::consts::FRAC_PI_2;
+    use self::Val::*;
+
+    let eval = |op: &str, arg: Val| {
+        let text = format!("defn f0 2 : {}\nx0 := k0\nx1 := {} x0\nreturn x1", arg, op);
+        let program = parse::parse(&text).unwrap();
+        program.eval(&mut &b""[..], &mut vec![]).map_err(|e| e.kind)
+    };
+    match eval("sin", F(FRAC_PI_2)) {
+        Ok(F(x)) => assert!((x - 1.0).abs() < 1e-12),
+        other => panic!("expected a float, got {:?}", other),
+    }
+    assert_eq!(eval("cos", F(0.0)), Ok(F(1.0)));
+    assert_eq!(eval("tan", F(0.0)), Ok(F(0.0)));
+    assert_eq!(eval("sin", I(0)), Err(EvalErrorKind::TypeMismatch));
+}
+
+#[test]
+#[cfg(not(feature = "transcendentals"))]
+fn test_transcendentals_disabled() {
+    use parse_util::ParseError;
+
+    assert_eq!(
+        parse::parse("defn f0 2 :\n  x0 := sin x1"),
+        Err(ParseError::expected("the `transcendentals` feature for 'sin'", 2, 8))
+    );
+}
+
+#[test]
+fn test_link() {
+    use self::Val::*;
+
+    // f2 is the library's f1 once linked
+    let main_text = r#"
+defn f0 2 : f2
+  x0 := read
+  x1 := k0
+  x0 := x1(x0)
+  write x0
+  return
+"#;
+    // f1 squares its argument by calling f0, then adds one
+    let math_text = r#"
+defn f0 1 :
+  x0 := x0 * x0
+  return x0
+
+defn f1 2 : f0/1 f1
+  x1 := k0
+  x0 := x1(x0)
+  x0 := x0 +# 1
+  return x0
+"#;
+    let main = parse::parse(main_text).unwrap();
+    let math = || parse::parse(math_text).unwrap();
+
+    let linked = main.link(&[math()]).unwrap();
+    assert_eq!(linked.defns().len(), 3);
+    assert_eq!(linked.entry_point(), 0);
+    assert_eq!(linked.defns()[0], main.defns()[0]);
+    assert_eq!(
+        linked.defns()[2].consts(),
+        &[C(1, Some(1)), C(2, None)]
+    );
+    let mut output = Vec::new();
+    assert_eq!(linked.eval(&mut &[7][..], &mut output), Ok(T(vec![])));
+    assert_eq!(output, vec![50]);
+
+    // Without the library, f2 doesn't exist
+    assert_eq!(
+        main.link(&[]),
+        Err(LinkError::UnresolvedFunction {
+            module: 0,
+            fn_id: 0,
+            target: 2,
+        })
+    );
+    // Libraries can only refer to their own functions
+    assert_eq!(
+        main.link(&[math(), parse::parse(main_text).unwrap()]),
+        Err(LinkError::UnresolvedFunction {
+            module: 2,
+            fn_id: 0,
+            target: 2,
+        })
+    );
+}
+
+#[test]
+fn test_verify_local_count() {
+    use self::Instr::*;
+
+    let program = parse::parse("defn f0 2 :\n  x0 := x5\n  return x0").unwrap();
+    assert_eq!(program.defns()[0].verify_local_count(), Err(6));
+    assert_eq!(
+        program.validate(),
+        vec![ValidationIssue::RegisterOutOfBounds { fn_id: 0, iptr: 0 }]
+    );
+
+    // More locals than needed is allowed, but the minimum is still reported
+    let program = parse::parse("defn f0 5 :\n  x0 := x1\n  return x0").unwrap();
+    assert_eq!(program.defns()[0].verify_local_count(), Ok(2));
+    assert_eq!(program.validate(), vec![]);
+
+    // Register ranges count in full
+    let defn = Defn {
+        code: vec![MkTup(0, 1, 3), Return(Some(0))],
+        consts: vec![],
+        local_count: 3,
+    };
+    assert_eq!(defn.verify_local_count(), Err(4));
+    assert_eq!(arith::program().defns()[0].verify_local_count(), Ok(2));
+}
+
+#[test]
+fn test_idx_tup_bounds() {
+    use self::Val::*;
+
+    let program = |t, i| Program {
+        defns: vec![Defn {
+            code: vec![
+                Instr::Const(0, 0),
+                Instr::Const(1, 1),
+                Instr::IdxTup(0, 0, 1),
+                Instr::Return(Some(0)),
+            ],
+            consts: vec![T(t), I(i)],
+            local_count: 2,
+        }],
+        entry_point: 0,
+        stack_depth: Default::default(),
+    };
+    let eval = |t, i| program(t, i).eval(&mut &b""[..], &mut vec![]).map_err(|e| e.kind);
+    assert_eq!(eval(vec![I(4), I(5)], 1), Ok(I(5)));
+    assert_eq!(
+        eval(vec![I(4), I(5)], 2),
+        Err(EvalErrorKind::OutOfBounds { index: 2, len: 2 })
+    );
+    assert_eq!(
+        eval(vec![I(4), I(5)], -1),
+        Err(EvalErrorKind::OutOfBounds { index: -1, len: 2 })
+    );
+    assert_eq!(
+        eval(vec![], 0),
+        Err(EvalErrorKind::OutOfBounds { index: 0, len: 0 })
+    );
+}
+
+#[test]
+fn test_mk_tup_bounds() {
+    use self::Instr::*;
+    use self::Val::*;
+
+    let eval = |code| {
+        let program = Program {
+            defns: vec![Defn {
+                code,
+                consts: vec![I(1), I(2), I(3)],
+                local_count: 3,
+            }],
+            entry_point: 0,
+            stack_depth: Default::default(),
+        };
+        program.eval(&mut &b""[..], &mut vec![]).map_err(|e| e.kind)
+    };
+    let load = |instr| vec![Const(0, 0), Const(1, 1), Const(2, 2), instr, Return(Some(0))];
+    assert_eq!(eval(load(MkTup(0, 1, 0))), Ok(T(vec![])));
+    assert_eq!(eval(load(MkTup(0, 0, 3))), Ok(T(vec![I(1), I(2), I(3)])));
+    assert_eq!(
+        eval(load(MkTup(0, 1, 3))),
+        Err(EvalErrorKind::OutOfBounds { index: 3, len: 3 })
+    );
+    assert_eq!(
+        eval(load(MkTup(0, 5, 0))),
+        Err(EvalErrorKind::OutOfBounds { index: 5, len: 3 })
+    );
+    // The end of the range can't wrap around
+    assert_eq!(
+        eval(load(MkTup(0, 2, Addr::MAX))),
+        Err(EvalErrorKind::OutOfBounds { index: 3, len: 3 })
+    );
+
+    // Unpacking checks its range the same way
+    let mut code = load(MkTup(0, 0, 2));
+    code.insert(4, UnTup(2, 2, 0));
+    assert_eq!(
+        eval(code),
+        Err(EvalErrorKind::OutOfBounds { index: 3, len: 3 })
+    );
+}
+
+#[test]
+fn test_read_byte() {
+    use std::io::{Error, ErrorKind};
+
+    // Fails with `Interrupted` once before giving each byte
+    struct Flaky<'a> {
+        bytes: &'a [u8],
+        interrupt: bool,
+    }
+    impl<'a> Read for Flaky<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(Error::new(ErrorKind::Interrupted, "try again"));
+            }
+            self.bytes.read(buf)
+        }
+    }
+
+    let program = parse::parse("defn f0 1 :\n  x0 := read\n  return x0").unwrap();
+    let mut input = Flaky {
+        bytes: &[7],
+        interrupt: false,
+    };
+    assert_eq!(program.eval(&mut input, &mut vec![]), Ok(Val::I(7)));
+    assert_eq!(
+        program.eval(&mut input, &mut vec![]).map_err(|e| e.kind),
+        Err(EvalErrorKind::UnexpectedEof)
+    );
+    assert_eq!(
+        program.eval(&mut io::Cursor::new(vec![]), &mut vec![]).map_err(|e| e.kind),
+        Err(EvalErrorKind::UnexpectedEof)
+    );
+}
+
+#[test]
+fn test_typeof() {
+    use self::Val::*;
+
+    let program = parse::parse("defn f0 1 :\n  x0 := typeof x0\n  return x0").unwrap();
+    assert_eq!(program.defns()[0].code()[0], Instr::Inspect(0, 0));
+    let eval = |arg| program.eval_with_args(arg, &mut io::empty(), &mut io::sink());
+    assert_eq!(eval(B(false)), Ok(I(0)));
+    assert_eq!(eval(I(5)), Ok(I(1)));
+    assert_eq!(eval(F(0.5)), Ok(I(2)));
+    assert_eq!(eval(T(vec![])), Ok(I(3)));
+    assert_eq!(eval(C(0, None)), Ok(I(4)));
+    assert_eq!(
+        eval(Closure {
+            fn_id: 0,
+            upvals: Box::new([]),
+        }),
+        Ok(I(5))
+    );
+    assert_eq!(eval(Ref(Arc::new(Mutex::new(I(0))))), Ok(I(6)));
+    assert_eq!(eval(S(String::new())), Ok(I(7)));
+}
+
+#[test]
+fn test_long_jumps() {
+    use self::Val::*;
+
+    // Both jumps cross more instructions than `CondJump` can
+    let nops = "  nop\n".repeat(200);
+    let text = format!(
+        "defn f0 1 : true 1 2\n  x0 := k0\n  condl x0 203 1\n  x0 := k1\n{}  return x0\n  \
+         x0 := k2\n  jumpl -203\n",
+        nops
+    );
+    let program = parse::parse(&text).unwrap();
+    assert_eq!(program.defns()[0].code()[1], Instr::CondJumpLong(0, 203, 1));
+    assert_eq!(program.defns()[0].code()[205], Instr::JumpLong(-203));
+    assert_eq!(program.validate(), vec![]);
+    assert_eq!(program.eval(&mut &b""[..], &mut vec![]), Ok(I(1)));
+    assert_eq!(parse::parse(&program.to_string()), Ok(program));
+
+    assert!(parse::parse(&text.replace("condl", "cond")).is_err());
+}
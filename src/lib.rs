@@ -1,2 +1,9 @@
+#[cfg(test)]
+#[macro_use]
+extern crate static_assertions;
+
+extern crate rayon;
+extern crate smallvec;
+
 pub mod bytecode;
 mod parse_util;
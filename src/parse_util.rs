@@ -12,12 +12,14 @@ pub struct Buffer<'a> {
 
 // Note: These implementations aren't fully general and assume that text is one line only
 impl<'a> Buffer<'a> {
+    /// Skips `offset` bytes, which must land on a char boundary. `col` counts
+    /// chars, so it stays right past multi-byte characters.
     pub fn advance(&self, offset: usize) -> Buffer<'a> {
         let offset = ::std::cmp::min(offset, self.text.len());
         Buffer {
             text: &self.text[offset..],
             row: self.row,
-            col: self.col + offset,
+            col: self.col + self.text[..offset].chars().count(),
         }
     }
 
@@ -28,9 +30,17 @@ impl<'a> Buffer<'a> {
         }
     }
 
+    /// Like `trim_left`, but stops at a newline.
+    pub fn trim_left_inline(&self) -> Buffer<'a> {
+        match self.text.find(|x: char| !x.is_whitespace() || x == '\n') {
+            Some(offset) => self.advance(offset),
+            None => self.advance(self.text.len()),
+        }
+    }
+
     pub fn trim_right(&self) -> Buffer<'a> {
         Buffer {
-            text: self.text.trim_right(),
+            text: self.text.trim_end(),
             ..*self
         }
     }
@@ -56,6 +66,17 @@ impl<'a> Buffer<'a> {
         }
     }
 
+    /// Like `space`, but doesn't skip newlines, for things that must be on
+    /// the same line.
+    pub fn space_inline(&self) -> ParseSuccess<'a> {
+        let new_input = self.trim_left_inline();
+        if new_input == *self {
+            Err(self.expected("whitespace"))
+        } else {
+            Ok(new_input)
+        }
+    }
+
     pub fn space_or_end(&self) -> ParseSuccess<'a> {
         if self.text.is_empty() {
             Ok(*self)
@@ -116,6 +137,14 @@ impl<'a> Buffer<'a> {
         }
     }
 
+    /// Like `til`, but fails if nothing in the remaining text matches `pat`.
+    pub fn til_exact<P: Fn(char) -> bool>(&self, pat: P) -> ParseResult<'a, &'a str> {
+        match self.text.find(pat) {
+            Some(offset) => Ok((self.advance(offset), &self.text[..offset])),
+            None => Err(self.expected("token separator")),
+        }
+    }
+
     pub fn parse_til<T: FromStr, P: Fn(char) -> bool>(&self, pat: P) -> ParseResult<'a, T>
     where
         <T as FromStr>::Err: ::std::error::Error,
@@ -129,6 +158,60 @@ impl<'a> Buffer<'a> {
         ))
     }
 
+    /// Parses a string literal in double quotes.
+    ///
+    /// Supports the escapes `\\`, `\"`, `\n`, `\t`, `\r`, `\0`, and `\xNN` for
+    /// the byte with hex value `NN`. The bytes must form valid UTF-8.
+    pub fn quoted_string(self) -> ParseResult<'a, String> {
+        if !self.starts_with("\"") {
+            return Err(self.expected("opening quote"));
+        }
+        let mut buf = self.advance(1);
+        let mut bytes = Vec::new();
+        loop {
+            let c = match buf.text.chars().next() {
+                Some(c) => c,
+                None => return Err(buf.expected("closing quote")),
+            };
+            match c {
+                '"' => break,
+                '\\' => {
+                    let escape = buf.advance(1);
+                    let (len, byte) = match escape.text.chars().next() {
+                        Some('\\') => (1, b'\\'),
+                        Some('"') => (1, b'"'),
+                        Some('n') => (1, b'\n'),
+                        Some('t') => (1, b'\t'),
+                        Some('r') => (1, b'\r'),
+                        Some('0') => (1, b'\0'),
+                        Some('x') => {
+                            let hex = escape
+                                .text
+                                .get(1..3)
+                                .filter(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()));
+                            match hex {
+                                Some(hex) => (3, u8::from_str_radix(hex, 16).unwrap()),
+                                None => return Err(escape.advance(1).expected("two hex digits")),
+                            }
+                        }
+                        _ => return Err(escape.expected("an escape sequence")),
+                    };
+                    bytes.push(byte);
+                    buf = escape.advance(len);
+                }
+                c => {
+                    let mut utf8 = [0; 4];
+                    bytes.extend(c.encode_utf8(&mut utf8).as_bytes());
+                    buf = buf.advance(c.len_utf8());
+                }
+            }
+        }
+        match String::from_utf8(bytes) {
+            Ok(s) => Ok((buf.advance(1), s)),
+            Err(_) => Err(self.expected("a string of valid UTF-8")),
+        }
+    }
+
     pub fn expected<S: Into<String>>(&self, message: S) -> ParseError {
         ParseError::expected(message, self.row, self.col)
     }
@@ -210,6 +293,17 @@ mod test {
                 text: "",
             }
         );
+
+        // Columns count chars, not bytes
+        let input = Buffer {
+            row: 1,
+            col: 0,
+            text: "\u{e9}t\u{e9} x",
+        };
+        let (rest, word) = input.til(char::is_whitespace).unwrap();
+        assert_eq!(word, "\u{e9}t\u{e9}");
+        assert_eq!(rest.col, 3);
+        assert_eq!(rest.space().unwrap().col, 4);
     }
 
     #[test]
@@ -251,6 +345,32 @@ mod test {
         assert_eq!(input.trim(), input.trim().trim());
     }
 
+    #[test]
+    fn test_trim_error_col() {
+        let input = Buffer {
+            row: 3,
+            col: 1,
+            text: "   42x  12  ",
+        };
+
+        // Errors after trimming point at the token, not the start of the line
+        let trimmed = input.trim();
+        assert_eq!(
+            trimmed.parse_til::<i64, _>(char::is_whitespace).unwrap_err(),
+            ParseError::expected(
+                "error parsing token: 42x, error invalid digit found in string",
+                3,
+                4
+            )
+        );
+
+        let (rest, _) = trimmed.til(char::is_whitespace).unwrap();
+        let rest = rest.trim();
+        assert_eq!(rest.col, 9);
+        assert_eq!(rest.text, "12");
+        assert_eq!(rest.trim_right().expected("x"), ParseError::expected("x", 3, 9));
+    }
+
     #[test]
     fn test_space() {
         let good_input = Buffer {
@@ -287,6 +407,39 @@ mod test {
         assert_eq!(is_end.space_or_end(), Ok(is_end));
     }
 
+    #[test]
+    fn test_space_inline() {
+        let input = Buffer {
+            row: 0,
+            col: 0,
+            text: " \t next",
+        };
+        assert_eq!(input.space_inline(), input.space());
+
+        let newline = Buffer {
+            row: 0,
+            col: 0,
+            text: "  \n next",
+        };
+        assert_eq!(
+            newline.space_inline(),
+            Ok(Buffer {
+                row: 0,
+                col: 2,
+                text: "\n next",
+            })
+        );
+        assert_eq!(newline.trim_left().text, "next");
+
+        let only_newline = Buffer {
+            row: 0,
+            col: 0,
+            text: "\nnext",
+        };
+        assert!(only_newline.space_inline().is_err());
+        assert!(only_newline.space().is_ok());
+    }
+
     #[test]
     fn test_token() {
         let input = Buffer {
@@ -353,6 +506,59 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_til_exact() {
+        let input = Buffer {
+            row: 1,
+            col: 0,
+            text: "12 rest",
+        };
+        assert_eq!(input.til_exact(char::is_whitespace), input.til(char::is_whitespace));
+
+        // At the end of the input, `til` takes everything but `til_exact` fails
+        let input = input.advance(3);
+        assert_eq!(input.til(char::is_whitespace).unwrap().1, "rest");
+        assert_eq!(
+            input.til_exact(char::is_whitespace),
+            Err(ParseError::expected("token separator", 1, 3))
+        );
+    }
+
+    #[test]
+    fn test_quoted_string() {
+        let input = Buffer {
+            row: 2,
+            col: 4,
+            text: r#""a\\b \"c\"\n\t\r\0\x41\xc3\xa9" rest"#,
+        };
+        let (rest, s) = input.quoted_string().unwrap();
+        assert_eq!(s, "a\\b \"c\"\n\t\r\0A\u{e9}");
+        assert_eq!(rest.text, " rest");
+        assert_eq!(rest.col, 4 + 32);
+
+        let input = Buffer {
+            row: 0,
+            col: 0,
+            text: "\"\u{1F49C} \"",
+        };
+        let (rest, s) = input.quoted_string().unwrap();
+        assert_eq!(s, "\u{1F49C} ");
+        assert_eq!(rest.col, 4);
+
+        // Errors point at the problem rather than the opening quote
+        let error = |text, msg, col| {
+            let input = Buffer { row: 1, col: 0, text };
+            assert_eq!(input.quoted_string(), Err(ParseError::expected(msg, 1, col)));
+        };
+        error("no quote", "opening quote", 0);
+        error("\"abc", "closing quote", 4);
+        error("\"abc\\", "an escape sequence", 5);
+        error(r#""a\u{41}""#, "an escape sequence", 3);
+        error(r#""\x4""#, "two hex digits", 3);
+        error(r#""\x+1""#, "two hex digits", 3);
+        error(r#""\xff""#, "a string of valid UTF-8", 0);
+    }
+
     #[test]
     fn test_starts_with() {
         let input = Buffer {
@@ -370,7 +576,7 @@ mod test {
 
     #[test]
     fn test_starts_with_multibyte() {
-        const HEART: &'static str = "\u{1F49C}";
+        const HEART: &str = "\u{1F49C}";
         let heart_emoji = Buffer {
             row: 0,
             col: 0,